@@ -0,0 +1,414 @@
+//! A headless way to import and inspect models, for batch jobs without a display.
+
+#[macro_use]
+extern crate anyhow;
+#[macro_use]
+extern crate log;
+
+mod quality;
+
+use abstutil::Timer;
+use anyhow::Result;
+use geom::Duration;
+use structopt::StructOpt;
+
+use model::{DailyModel, ImportConfig, ImportReport, MultidayModel, SharedGtfs};
+
+#[derive(StructOpt)]
+#[structopt(name = "bus_spotting", about = "Import and query bus spotting models")]
+enum Command {
+    /// Import raw data from a .zip into daily and multiday models
+    Import {
+        /// The path to a .zip file with raw data, in the format described in the user guide
+        #[structopt(long)]
+        input: String,
+        /// The directory to write {date}.bin, multiday.bin, and the GTFS feeds to
+        #[structopt(long, default_value = "data/output")]
+        output: String,
+        /// The path to a JSON file with an ImportConfig. Anything missing uses the defaults.
+        #[structopt(long)]
+        config: Option<String>,
+        /// Add the imported days to an existing multiday.bin in the output directory, instead of
+        /// replacing it. Days already present are replaced. Days using a different GTFS feed
+        /// start a new feed version.
+        #[structopt(long)]
+        append: bool,
+    },
+    /// Summarize a model
+    Info {
+        /// The path to a serialized model
+        path: String,
+        /// The model is multiday, not daily
+        #[structopt(long)]
+        multiday: bool,
+    },
+    /// Export a model to another format
+    Export {
+        /// The path to a serialized model
+        path: String,
+        /// The model is multiday, not daily
+        #[structopt(long)]
+        multiday: bool,
+        /// csv, geojson, gtfs-rt, or report. gtfs-rt and report only work for daily models.
+        #[structopt(long)]
+        format: ExportFormat,
+        /// A file to write, or a directory for gtfs-rt
+        #[structopt(long)]
+        output: String,
+        /// For gtfs-rt, how often to write a snapshot
+        #[structopt(long, default_value = "30")]
+        interval_seconds: usize,
+        /// For gtfs-rt, the timezone of the input data, as hours east of UTC
+        #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+        utc_offset_hours: i32,
+    },
+    /// Describe how well AVL, GTFS, and ticketing data were matched for a daily model
+    Quality {
+        /// The path to a serialized daily model
+        path: String,
+    },
+}
+
+enum ExportFormat {
+    Csv,
+    GeoJson,
+    GtfsRt,
+    Report,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(x: &str) -> Result<Self> {
+        match x {
+            "csv" => Ok(Self::Csv),
+            "geojson" => Ok(Self::GeoJson),
+            "gtfs-rt" => Ok(Self::GtfsRt),
+            "report" => Ok(Self::Report),
+            _ => bail!("Unknown format {x}; use csv, geojson, gtfs-rt, or report"),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    abstutil::logger::setup();
+    let mut timer = Timer::new("bus spotting");
+    run(Command::from_iter(abstutil::cli_args()), &mut timer)
+}
+
+fn run(command: Command, timer: &mut Timer) -> Result<()> {
+    match command {
+        Command::Import {
+            input,
+            output,
+            config,
+            append,
+        } => import(input, output, config, append, timer),
+        Command::Info { path, multiday } => {
+            if multiday {
+                print_multiday_info(&load_multiday(&path)?);
+            } else {
+                print_daily_info(&load_daily(&path)?);
+            }
+            Ok(())
+        }
+        Command::Export {
+            path,
+            multiday,
+            format,
+            output,
+            interval_seconds,
+            utc_offset_hours,
+        } => {
+            let contents = match (format, multiday) {
+                (ExportFormat::Csv, false) => load_daily(&path)?.export_to_csv()?,
+                (ExportFormat::Csv, true) => load_multiday(&path)?.export_to_csv()?,
+                (ExportFormat::GeoJson, false) => load_daily(&path)?.export_to_geojson()?,
+                (ExportFormat::GeoJson, true) => load_multiday(&path)?.export_to_geojson()?,
+                (ExportFormat::GtfsRt, false) => {
+                    let utc_offset = chrono::FixedOffset::east_opt(utc_offset_hours * 3600)
+                        .ok_or_else(|| anyhow!("Bad UTC offset {utc_offset_hours}"))?;
+                    return load_daily(&path)?.export_gtfs_rt(
+                        &output,
+                        Duration::seconds(interval_seconds as f64),
+                        utc_offset,
+                        timer,
+                    );
+                }
+                (ExportFormat::GtfsRt, true) => {
+                    bail!("GTFS-RT export needs a daily model");
+                }
+                (ExportFormat::Report, false) => load_daily(&path)?.export_matching_report()?,
+                (ExportFormat::Report, true) => {
+                    bail!("Matching reports are only in daily models");
+                }
+            };
+            fs_err::write(&output, contents)?;
+            info!("Wrote {output}");
+            Ok(())
+        }
+        Command::Quality { path } => {
+            for line in quality::describe(&load_daily(&path)?) {
+                println!("{line}");
+            }
+            Ok(())
+        }
+    }
+}
+
+fn import(
+    input: String,
+    output_dir: String,
+    config_path: Option<String>,
+    append: bool,
+    timer: &mut Timer,
+) -> Result<()> {
+    let config = if let Some(path) = config_path {
+        serde_json::from_slice(&fs_err::read(path)?)?
+    } else {
+        ImportConfig::default()
+    };
+
+    let multiday_path = format!("{output_dir}/multiday.bin");
+    let multiday = if append && std::path::Path::new(&multiday_path).exists() {
+        load_multiday(&multiday_path)?
+    } else {
+        MultidayModel::empty()
+    };
+
+    let bytes = fs_err::read(input)?;
+    let (models, report) = DailyModel::import_zip_bytes(bytes, &config, timer)?;
+    for line in report.describe() {
+        println!("{line}");
+    }
+    write_import(&output_dir, &models, &report, multiday)
+}
+
+fn write_import(
+    output_dir: &str,
+    models: &[DailyModel],
+    report: &ImportReport,
+    mut multiday: MultidayModel,
+) -> Result<()> {
+    if models.is_empty() {
+        bail!("Nothing was imported, so {output_dir} is unchanged");
+    }
+    // Check the feed versions don't overlap before writing anything
+    for model in models {
+        multiday.add_day(model)?;
+    }
+    fs_err::create_dir_all(output_dir)?;
+
+    // Every day shares one GTFS feed, so write it once. Feeds from earlier imports stay, since
+    // older days still refer to them.
+    let shared_gtfs = SharedGtfs {
+        gtfs: models[0].gtfs.clone(),
+        hash: models[0].gtfs_hash,
+    };
+    let path = format!("{output_dir}/{}", shared_gtfs.file_name());
+    fs_err::write(&path, shared_gtfs.to_file_bytes())?;
+    info!("Wrote {path}");
+
+    for model in models {
+        let path = format!("{output_dir}/{}.bin", model.date);
+        fs_err::write(&path, model.to_file_bytes(false))?;
+        info!("Wrote {path}");
+    }
+
+    let multiday_path = format!("{output_dir}/multiday.bin");
+    fs_err::write(&multiday_path, multiday.to_file_bytes(false))?;
+    info!("Wrote {multiday_path}");
+
+    let path = format!("{output_dir}/import_report.txt");
+    fs_err::write(&path, report.describe().join("\n"))?;
+    info!("Wrote {path}");
+    Ok(())
+}
+
+fn load_daily(path: &str) -> Result<DailyModel> {
+    let bytes = fs_err::read(path)?;
+    let gtfs = SharedGtfs::load_next_to(path, &bytes)?;
+    DailyModel::from_file_bytes(&bytes, &gtfs)
+}
+
+fn load_multiday(path: &str) -> Result<MultidayModel> {
+    let bytes = fs_err::read(path)?;
+    let gtfs = SharedGtfs::load_next_to(path, &bytes)?;
+    MultidayModel::from_file_bytes(&bytes, &gtfs)
+}
+
+fn print_daily_info(model: &DailyModel) {
+    println!("Daily model for {}", model.date);
+    println!(
+        "- GTFS: {} routes, {} stops",
+        model.gtfs.routes.len(),
+        model.gtfs.stops.len()
+    );
+    println!(
+        "- {} vehicles, {} with at least one trip",
+        model.vehicles.len(),
+        model
+            .vehicles
+            .iter()
+            .filter(|v| !v.timetable.0.is_empty())
+            .count()
+    );
+    println!("- {} ticketing journeys", model.journeys.len());
+    println!("- {} boarding events", model.boardings.len());
+}
+
+fn print_multiday_info(model: &MultidayModel) {
+    println!("Multiday model with {} days", model.boardings_per_day.len());
+    for feed in &model.feeds {
+        println!(
+            "- GTFS {:016x} from {} to {}: {} routes, {} stops",
+            feed.hash,
+            feed.first_day,
+            feed.last_day,
+            feed.gtfs.routes.len(),
+            feed.gtfs.stops.len()
+        );
+    }
+    println!("- {} vehicles", model.vehicle_ids.borrow().len());
+    for (date, events) in &model.boardings_per_day {
+        println!("- {date}: {} boarding events", events.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use geom::{Bounds, GPSBounds, Pt2D};
+    use gtfs::{IDMapping, GTFS};
+    use model::{MatchingConfig, MatchingReport};
+
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        let cmd =
+            Command::from_iter_safe(["bus_spotting", "import", "--input", "raw.zip"]).unwrap();
+        assert!(matches!(
+            cmd,
+            Command::Import { output, config: None, append: false, .. } if output == "data/output"
+        ));
+
+        let cmd = Command::from_iter_safe([
+            "bus_spotting",
+            "export",
+            "day.bin",
+            "--format",
+            "gtfs-rt",
+            "--output",
+            "rt",
+            "--utc-offset-hours",
+            "-5",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cmd,
+            Command::Export {
+                multiday: false,
+                format: ExportFormat::GtfsRt,
+                interval_seconds: 30,
+                utc_offset_hours: -5,
+                ..
+            }
+        ));
+
+        assert!(Command::from_iter_safe([
+            "bus_spotting",
+            "export",
+            "day.bin",
+            "--format",
+            "shapefile",
+            "--output",
+            "out"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn empty_import() {
+        let dir = temp_dir("empty_import");
+        assert!(write_import(&dir, &[], &ImportReport::default(), MultidayModel::empty()).is_err());
+        assert!(!std::path::Path::new(&dir).exists());
+    }
+
+    #[test]
+    fn inspect_imported_model() {
+        let dir = temp_dir("inspect");
+        let date = NaiveDate::from_ymd_opt(2022, 3, 14).unwrap();
+        let report = ImportReport {
+            imported: vec![date],
+            skipped: Vec::new(),
+        };
+        write_import(&dir, &[empty_model(date)], &report, MultidayModel::empty()).unwrap();
+
+        let mut timer = Timer::throwaway();
+        let daily = format!("{dir}/{date}.bin");
+        let multiday = format!("{dir}/multiday.bin");
+        run(
+            Command::Info {
+                path: daily.clone(),
+                multiday: false,
+            },
+            &mut timer,
+        )
+        .unwrap();
+        run(
+            Command::Info {
+                path: multiday.clone(),
+                multiday: true,
+            },
+            &mut timer,
+        )
+        .unwrap();
+        run(Command::Quality { path: daily }, &mut timer).unwrap();
+
+        let csv = format!("{dir}/boardings.csv");
+        run(export(&multiday, ExportFormat::Csv, &csv), &mut timer).unwrap();
+        assert!(std::path::Path::new(&csv).exists());
+        // GTFS-RT needs a daily model
+        let rt = format!("{dir}/rt");
+        assert!(run(export(&multiday, ExportFormat::GtfsRt, &rt), &mut timer).is_err());
+
+        fs_err::remove_dir_all(&dir).unwrap();
+    }
+
+    fn export(multiday_path: &str, format: ExportFormat, output: &str) -> Command {
+        Command::Export {
+            path: multiday_path.to_string(),
+            multiday: true,
+            format,
+            output: output.to_string(),
+            interval_seconds: 30,
+            utc_offset_hours: 0,
+        }
+    }
+
+    // A GTFS-only model, like an import without AVL or ticketing data produces
+    fn empty_model(date: NaiveDate) -> DailyModel {
+        let shared = SharedGtfs::new(GTFS::empty());
+        DailyModel {
+            date,
+            bounds: Bounds::from(&[Pt2D::zero(), Pt2D::new(1.0, 1.0)]),
+            gps_bounds: GPSBounds::new(),
+            vehicles: Vec::new(),
+            vehicle_ids: IDMapping::new(),
+            gtfs: shared.gtfs,
+            gtfs_hash: shared.hash,
+            journeys: Vec::new(),
+            boardings: Vec::new(),
+            matching: MatchingConfig::default(),
+            matching_report: MatchingReport::default(),
+        }
+    }
+
+    // A fresh directory per test, since tests run in parallel
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("bus_spotting_cli_{name}"));
+        let _ = fs_err::remove_dir_all(&dir);
+        dir.to_string_lossy().to_string()
+    }
+}
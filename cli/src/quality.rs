@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+
+use abstutil::prettyprint_usize;
+use geom::Duration;
+
+use model::{DailyModel, Stage};
+
+/// Summarizes how much of each input was successfully matched
+pub fn describe(model: &DailyModel) -> Vec<String> {
+    let mut lines = vec![format!("Matching quality for {}", model.date)];
+
+    lines.extend(model.matching_report.describe());
+
+    // Every event on a trip shares the same confidence, so this weights longer trips more
+    let mut low_confidence = 0;
+    let mut unknown_confidence = 0;
+    for ev in &model.boardings {
+        match ev.confidence {
+            Some(c) if c.score() < 0.5 => {
+                low_confidence += 1;
+            }
+            Some(_) => {}
+            None => {
+                unknown_confidence += 1;
+            }
+        }
+    }
+    lines.push(format!(
+        "{} of {} boarding events have confidence under 50%. {} unknown",
+        prettyprint_usize(low_confidence),
+        prettyprint_usize(model.boardings.len()),
+        prettyprint_usize(unknown_confidence)
+    ));
+
+    let mut raw_points = 0;
+    let mut cleaned_points = 0;
+    let mut compressed_points = 0;
+    for vehicle in &model.vehicles {
+        raw_points += vehicle.cleaning.raw_points;
+        cleaned_points += vehicle.cleaning.final_points;
+        compressed_points += vehicle.compression.compressed_points;
+    }
+    lines.push(format!(
+        "AVL: {} raw points, {} after cleaning, {} after compression",
+        prettyprint_usize(raw_points),
+        prettyprint_usize(cleaned_points),
+        prettyprint_usize(compressed_points)
+    ));
+
+    let mut time_per_stage: BTreeMap<Stage, Duration> = BTreeMap::new();
+    for vehicle in &model.vehicles {
+        for (t1, t2, stage) in &vehicle.stages.0 {
+            *time_per_stage.entry(*stage).or_insert(Duration::ZERO) += *t2 - *t1;
+        }
+    }
+    for (stage, duration) in time_per_stage {
+        lines.push(format!("Total time {:?}: {}", stage, duration));
+    }
+
+    lines
+}
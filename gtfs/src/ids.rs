@@ -11,6 +11,18 @@ pub mod orig {
 
     #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
     pub struct TripID(String);
+
+    impl StopID {
+        pub fn as_str(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl TripID {
+        pub fn as_str(&self) -> &str {
+            &self.0
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -16,7 +16,7 @@ use std::collections::{BTreeMap, BTreeSet};
 
 use abstutil::Timer;
 use anyhow::Result;
-use geom::{GPSBounds, PolyLine, Polygon};
+use geom::{Distance, GPSBounds, PolyLine, Polygon};
 use serde::{Deserialize, Serialize};
 use zip::ZipArchive;
 
@@ -46,8 +46,11 @@ pub struct GTFS {
 }
 
 impl GTFS {
+    /// Route shapes are snapped to the street network if `osm_input.xml` is present and their ends
+    /// are within `snap_threshold` of a road.
     pub fn load_from_dir<R: std::io::Read + std::io::Seek>(
         archive: &mut ZipArchive<R>,
+        snap_threshold: Distance,
         timer: &mut Timer,
     ) -> Result<(Self, GPSBounds)> {
         let mut gtfs = Self::empty();
@@ -117,7 +120,7 @@ impl GTFS {
         )?;
 
         if let Ok(osm_xml_input) = get_zip_file(archive, "osm_input.xml") {
-            snap::snap_routes(&mut gtfs, osm_xml_input, &gps_bounds, timer)?;
+            snap::snap_routes(&mut gtfs, osm_xml_input, &gps_bounds, snap_threshold, timer)?;
         }
 
         dump_bounding_box(&gps_bounds);
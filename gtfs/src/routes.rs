@@ -9,6 +9,12 @@ use super::{ServiceID, ShapeID, StopID, Trip, GTFS};
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct RouteID(String);
 
+impl RouteID {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Route {
     pub route_id: RouteID,
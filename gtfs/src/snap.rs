@@ -13,6 +13,7 @@ pub fn snap_routes<R: std::io::Read>(
     gtfs: &mut GTFS,
     mut reader: R,
     gps_bounds: &GPSBounds,
+    threshold: Distance,
     timer: &mut Timer,
 ) -> Result<()> {
     timer.start("snap routes to street network");
@@ -50,7 +51,6 @@ pub fn snap_routes<R: std::io::Read>(
             "snap route shapes",
             gtfs.shapes.iter().map(|(id, pl)| (id, pl)).collect(),
             |(id, pl)| {
-                let threshold = Distance::meters(50.0);
                 let mut result = None;
                 if let Some(((from_r, from_src_i), _)) =
                     closest.closest_pt(pl.first_pt(), threshold)
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use abstutil::Timer;
+use anyhow::Result;
+use geom::{Pt2D, Speed, Time};
+use gtfs::RouteVariantID;
+
+use crate::{DailyModel, LinearShape, ProjectedTrajectory, VehicleID};
+
+// Straight lines between sparse AVL points cut across blocks. While a vehicle serves a trip,
+// follow the trip's shape between where consecutive points project onto it instead.
+
+impl DailyModel {
+    /// The shape of a variant, snapped to the street network if possible
+    pub fn variant_shape(&self, variant: RouteVariantID) -> Result<LinearShape> {
+        let variant = self.gtfs.variant(variant);
+        let pl = match self.gtfs.snapped_shapes.get(&variant.shape_id) {
+            Some(pl) => pl.clone(),
+            None => variant.polyline(&self.gtfs)?,
+        };
+        Ok(LinearShape::new(pl))
+    }
+}
+
+/// Where vehicles are, following the shape of the trip they're serving. Projecting every run in
+/// the timetables is slow, so it happens once up-front.
+pub struct TripPaths(Vec<Vec<Option<ProjectedTrajectory>>>);
+
+impl TripPaths {
+    /// Projects the part of each vehicle's trajectory during each run in its timetable onto the
+    /// shape of that run's variant.
+    pub fn new(model: &DailyModel, timer: &mut Timer) -> Self {
+        // Many runs follow the same variant
+        let mut shapes: BTreeMap<RouteVariantID, Option<Arc<LinearShape>>> = BTreeMap::new();
+        for vehicle in &model.vehicles {
+            for (_, _, run) in &vehicle.timetable.0 {
+                shapes
+                    .entry(run.variant)
+                    .or_insert_with(|| model.variant_shape(run.variant).ok().map(Arc::new));
+            }
+        }
+
+        let shapes = &shapes;
+        Self(timer.parallelize(
+            "project trips onto shapes",
+            model.vehicles.iter().collect(),
+            |vehicle| {
+                vehicle
+                    .timetable
+                    .0
+                    .iter()
+                    .map(|(t1, t2, run)| {
+                        let shape = shapes[&run.variant].clone()?;
+                        let trajectory = vehicle.trajectory.clip_to_time(*t1, *t2).ok()?;
+                        Some(trajectory.project_onto_shape(shape, model.matching.stop_threshold))
+                    })
+                    .collect()
+            },
+        ))
+    }
+
+    /// Where a vehicle is at some time. While it serves a trip and stays on its shape, this
+    /// follows the shape between AVL points. Otherwise it's `Trajectory::interpolate`.
+    pub fn interpolate(
+        &self,
+        model: &DailyModel,
+        vehicle: VehicleID,
+        time: Time,
+    ) -> Option<(Pt2D, Speed)> {
+        let vehicle = &model.vehicles[vehicle.0];
+        if let Some(idx) = vehicle
+            .timetable
+            .0
+            .iter()
+            .position(|(t1, t2, _)| time >= *t1 && time <= *t2)
+        {
+            if let Some(projected) = &self.0[vehicle.id.0][idx] {
+                if let Some(result) = projected.interpolate(time) {
+                    return Some(result);
+                }
+            }
+        }
+        vehicle.trajectory.interpolate(time)
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+
+use gtfs::RouteVariantID;
+
+use super::confidence::mean_schedule_offset;
+use crate::{ActualTrip, DailyModel};
+
+// Each vehicle's schedule is inferred independently, and each run picks its closest GTFS trip on
+// its own. So two vehicles can claim the same trip, and a run nobody scheduled still claims one.
+// Resolve this globally: per variant, match runs to scheduled trips, minimizing the total time
+// difference. Runs left over, or too far from any trip left, are unscheduled extras.
+
+// Using a forbidden pair costs this much. Every run can always be an extra, so this is never
+// needed in an optimal solution.
+const FORBIDDEN: f64 = 1e12;
+
+impl DailyModel {
+    /// Reassigns the GTFS trip of every run, so that each trip is served at most once. Runs that
+    /// don't get a trip are marked unscheduled and keep their closest trip for reference.
+    pub fn assign_trips_globally(&self, runs: &mut [ActualTrip]) {
+        let mut runs_per_variant: BTreeMap<RouteVariantID, Vec<&mut ActualTrip>> = BTreeMap::new();
+        for run in runs {
+            runs_per_variant
+                .entry(run.variant)
+                .or_insert_with(Vec::new)
+                .push(run);
+        }
+
+        let max_offset = self.matching.max_schedule_offset.inner_seconds();
+        for (variant, mut runs) in runs_per_variant {
+            let gtfs_trips = &self.gtfs.variant(variant).trips;
+
+            // Rows are runs. The first columns are scheduled trips, then one "extra" column per
+            // run, so that every run can stay unscheduled.
+            let num_cols = gtfs_trips.len() + runs.len();
+            let mut cost = Vec::new();
+            for run in &runs {
+                let mut row = Vec::with_capacity(num_cols);
+                for trip in gtfs_trips {
+                    let offset = mean_schedule_offset(&run.stop_times, trip).inner_seconds();
+                    row.push(if offset <= max_offset {
+                        offset
+                    } else {
+                        FORBIDDEN
+                    });
+                }
+                row.extend(std::iter::repeat(max_offset).take(runs.len()));
+                cost.push(row);
+            }
+
+            let assignment = min_cost_assignment(&cost);
+            for (idx, run) in runs.iter_mut().enumerate() {
+                let col = assignment[idx];
+                if col < gtfs_trips.len() && cost[idx][col] < FORBIDDEN {
+                    let trip = &gtfs_trips[col];
+                    if run.trip != trip.id {
+                        run.trip = trip.id;
+                        run.confidence.rescore_schedule(&run.stop_times, trip);
+                    }
+                    run.unscheduled = false;
+                } else {
+                    run.unscheduled = true;
+                }
+            }
+        }
+    }
+}
+
+// The Hungarian algorithm, using potentials. Each row is assigned a different column, minimizing
+// the total cost. Needs at least as many columns as rows. Returns the column for each row.
+fn min_cost_assignment(cost: &[Vec<f64>]) -> Vec<usize> {
+    let rows = cost.len();
+    if rows == 0 {
+        return Vec::new();
+    }
+    let cols = cost[0].len();
+    assert!(rows <= cols);
+
+    // Everything is 1-indexed, with row and column 0 as sentinels
+    let mut row_potential = vec![0.0; rows + 1];
+    let mut col_potential = vec![0.0; cols + 1];
+    // The row assigned to each column, or 0
+    let mut row_for_col = vec![0; cols + 1];
+    let mut prev_col = vec![0; cols + 1];
+
+    for row in 1..=rows {
+        row_for_col[0] = row;
+        let mut col0 = 0;
+        let mut min_slack = vec![f64::INFINITY; cols + 1];
+        let mut used = vec![false; cols + 1];
+        // Grow an alternating path from this row until it reaches a free column
+        loop {
+            used[col0] = true;
+            let row0 = row_for_col[col0];
+            let mut delta = f64::INFINITY;
+            let mut col1 = 0;
+            for col in 1..=cols {
+                if used[col] {
+                    continue;
+                }
+                let slack = cost[row0 - 1][col - 1] - row_potential[row0] - col_potential[col];
+                if slack < min_slack[col] {
+                    min_slack[col] = slack;
+                    prev_col[col] = col0;
+                }
+                if min_slack[col] < delta {
+                    delta = min_slack[col];
+                    col1 = col;
+                }
+            }
+            for col in 0..=cols {
+                if used[col] {
+                    row_potential[row_for_col[col]] += delta;
+                    col_potential[col] -= delta;
+                } else {
+                    min_slack[col] -= delta;
+                }
+            }
+            col0 = col1;
+            if row_for_col[col0] == 0 {
+                break;
+            }
+        }
+        // Flip the path
+        while col0 != 0 {
+            let col1 = prev_col[col0];
+            row_for_col[col0] = row_for_col[col1];
+            col0 = col1;
+        }
+    }
+
+    let mut result = vec![0; rows];
+    for (col, row) in row_for_col.into_iter().enumerate().skip(1) {
+        if row != 0 {
+            result[row - 1] = col - 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optimum_beats_greedy() {
+        // Giving the first row its cheapest column would leave the second row with a terrible one
+        let cost = vec![vec![1.0, 2.0], vec![1.0, 100.0]];
+        assert_eq!(min_cost_assignment(&cost), vec![1, 0]);
+    }
+
+    #[test]
+    fn more_runs_than_trips() {
+        // One scheduled trip, then an extra column per run, like assign_trips_globally builds
+        let extra = 100.0;
+        let cost = vec![
+            vec![50.0, extra, extra, extra],
+            vec![10.0, extra, extra, extra],
+            vec![30.0, extra, extra, extra],
+        ];
+        let assignment = min_cost_assignment(&cost);
+        assert_eq!(assignment[1], 0);
+        let mut cols = assignment.clone();
+        cols.sort();
+        cols.dedup();
+        assert_eq!(cols.len(), 3);
+        assert!(cols.iter().all(|col| *col < 4));
+
+        assert!(min_cost_assignment(&[]).is_empty());
+    }
+
+    #[test]
+    fn forbidden_pairs() {
+        let extra = 60.0;
+        // The run that can't take the trip becomes an extra, whichever row it's in
+        let cost = vec![vec![FORBIDDEN, extra, extra], vec![40.0, extra, extra]];
+        assert_eq!(min_cost_assignment(&cost), vec![1, 0]);
+        let cost = vec![vec![10.0, extra, extra], vec![FORBIDDEN, extra, extra]];
+        assert_eq!(min_cost_assignment(&cost), vec![0, 1]);
+        // With nothing else allowed, the run stays unscheduled
+        assert_eq!(min_cost_assignment(&[vec![FORBIDDEN, extra]]), vec![1]);
+    }
+}
@@ -1,12 +1,14 @@
 use std::collections::{BTreeMap, BTreeSet};
 
-use abstutil::{prettyprint_usize, Timer};
+use abstutil::Timer;
 use anyhow::Result;
-use geom::{Histogram, Time};
+use geom::{Duration, Time};
 use serde::{Deserialize, Serialize};
 
-use crate::{DailyModel, JourneyID, Timetable, VehicleID};
-use gtfs::{RouteVariantID, StopID, TripID};
+use super::confidence::Confidence;
+use super::report::{MatchingReport, Summary, VariantReport, VehicleReport};
+use crate::{DailyModel, JourneyID, ServedTrip, Timetable, VehicleID};
+use gtfs::{DateFilter, RouteVariantID, StopID, TripID, VariantFilter, GTFS};
 
 // TODO UIs
 // - for just a variant (click in the world)
@@ -20,7 +22,7 @@ use gtfs::{RouteVariantID, StopID, TripID};
 // Most of the high-level UI could be built on top of this, and the data model could omit all the
 // raw AVL and BIL data.
 //
-// Uniquely keyed by (TripID, StopID)
+// Uniquely keyed by (TripID, StopID), except for unscheduled runs
 #[derive(Clone, Serialize, Deserialize)]
 pub struct BoardingEvent {
     pub vehicle: VehicleID,
@@ -30,8 +32,31 @@ pub struct BoardingEvent {
     pub stop: StopID,
     pub arrival_time: Time,
     pub departure_time: Time,
+    /// Roughly how far off the time the vehicle passed the stop might be, when that was between
+    /// two sparse AVL points. None if unknown.
+    pub uncertainty: Option<Duration>,
     pub new_riders: Vec<JourneyID>,
     pub transfers: Vec<JourneyID>,
+    /// How much to trust the trip this event belongs to. Every event on one trip shares this. None
+    /// for models imported before this was calculated.
+    pub confidence: Option<Confidence>,
+    /// This run didn't match any scheduled trip, and `trip` is only the closest one
+    pub unscheduled: bool,
+}
+
+impl BoardingEvent {
+    /// How long the vehicle stopped here. Zero if it didn't stop, or for models imported before
+    /// this was detected.
+    pub fn dwell_time(&self) -> Duration {
+        self.departure_time - self.arrival_time
+    }
+
+    /// Events with unknown confidence pass
+    pub fn is_confident(&self, min_score: f64) -> bool {
+        self.confidence
+            .map(|c| c.score() >= min_score)
+            .unwrap_or(true)
+    }
 }
 
 // It's basically SQL at this point, happy?
@@ -39,7 +64,7 @@ impl DailyModel {
     pub fn find_boarding_event(&self, trip: TripID, stop: StopID) -> Option<&BoardingEvent> {
         self.boardings
             .iter()
-            .find(|ev| ev.trip == trip && ev.stop == stop)
+            .find(|ev| ev.trip == trip && ev.stop == stop && !ev.unscheduled)
     }
 
     pub fn boarding_event_for_vehicle_stop_time(
@@ -67,89 +92,143 @@ impl DailyModel {
     pub fn all_boarding_events_at_stop(&self, stop: StopID) -> Vec<&BoardingEvent> {
         self.boardings.iter().filter(|ev| ev.stop == stop).collect()
     }
+
+    /// Skips events on trips that don't look trustworthy
+    pub fn confident_boardings(&self, min_score: f64) -> impl Iterator<Item = &BoardingEvent> {
+        self.boardings
+            .iter()
+            .filter(move |ev| ev.is_confident(min_score))
+    }
+
+    /// The mean confidence over all of a vehicle's boarding events, so longer trips count more.
+    /// None if the vehicle wasn't matched to any trips, or confidence is unknown.
+    pub fn vehicle_confidence(&self, vehicle: VehicleID) -> Option<f64> {
+        let scores: Vec<f64> = self
+            .boardings
+            .iter()
+            .filter(|ev| ev.vehicle == vehicle)
+            .filter_map(|ev| ev.confidence.map(|c| c.score()))
+            .collect();
+        if scores.is_empty() {
+            return None;
+        }
+        Some(scores.iter().sum::<f64>() / scores.len() as f64)
+    }
 }
 
 pub fn populate_boarding(model: &mut DailyModel, timer: &mut Timer) -> Result<()> {
     timer.start("populate_boarding");
 
     // Every stop a vehicle visits through the day, in order
-    let mut events_per_vehicle: BTreeMap<VehicleID, Vec<BoardingEvent>> = BTreeMap::new();
+    let mut events_per_vehicle: BTreeMap<VehicleID, Vec<BoardingEvent>> =
+        model.vehicles.iter().map(|v| (v.id, Vec::new())).collect();
 
-    let vehicle_to_variants = model.vehicles_to_possible_routes()?;
+    let evidence_per_vehicle = model.evidence_per_vehicle(timer)?;
 
-    // Fill out empty BoardingEvents for each stop along each trip
-    let mut all_trip_durations = Histogram::new();
-    for (vehicle, events, trip_durations, timetable) in timer.parallelize(
+    let mut runs = Vec::new();
+    for trips in timer.parallelize(
         "calculate schedule for vehicles",
         model.vehicles.iter().map(|v| v.id).collect(),
         |vehicle| {
-            let mut events = Vec::new();
-            let mut trip_durations = Vec::new();
-            // Build up a summary timetable
-            let mut timetable = Timetable::new();
-
-            let debug = false;
-            for trip in model.infer_vehicle_schedule(
-                vehicle,
-                debug,
-                Some(
-                    vehicle_to_variants
-                        .get(&vehicle)
-                        .cloned()
-                        .unwrap_or_else(Vec::new),
-                ),
-            ) {
-                timetable.assign((trip.start_time(), trip.end_time()), trip.trip);
-
-                let variant = model.gtfs.variant(trip.variant);
-                assert_eq!(trip.stop_times.len(), variant.stops().len());
-                trip_durations.push(trip.end_time() - trip.start_time());
-                for (time, stop) in trip.stop_times.into_iter().zip(variant.stops().into_iter()) {
-                    events.push(BoardingEvent {
-                        vehicle: trip.vehicle,
-                        variant: trip.variant,
-                        trip: trip.trip,
-                        stop,
-                        arrival_time: time,
-                        departure_time: time,
-                        new_riders: Vec::new(),
-                        transfers: Vec::new(),
-                    });
-                }
-            }
-            (vehicle, events, trip_durations, timetable)
+            model
+                .infer_vehicle_schedule(
+                    vehicle,
+                    Some(
+                        evidence_per_vehicle
+                            .get(&vehicle)
+                            .cloned()
+                            .unwrap_or_default(),
+                    ),
+                )
+                .trips
         },
     ) {
-        events_per_vehicle.insert(vehicle, events);
-        for d in trip_durations {
-            all_trip_durations.add(d);
+        runs.extend(trips);
+    }
+    // Make sure no trip is served by two vehicles
+    model.assign_trips_globally(&mut runs);
+
+    // Fill out empty BoardingEvents for each stop along each trip
+    let mut all_trip_durations = Vec::new();
+    let mut unscheduled_runs = 0;
+    let mut timetables: BTreeMap<VehicleID, Timetable<ServedTrip>> = BTreeMap::new();
+    for trip in runs {
+        // Build up a summary timetable of every run, scheduled or not
+        if trip.unscheduled {
+            unscheduled_runs += 1;
         }
+        timetables
+            .entry(trip.vehicle)
+            .or_insert_with(Timetable::new)
+            .assign(
+                (trip.start_time(), trip.end_time()),
+                ServedTrip {
+                    trip: trip.trip,
+                    variant: trip.variant,
+                    unscheduled: trip.unscheduled,
+                },
+            );
 
-        model.vehicles[vehicle.0].timetable = timetable;
+        let variant = model.gtfs.variant(trip.variant);
+        assert_eq!(trip.stop_times.len(), variant.stops().len());
+        all_trip_durations.push(trip.end_time() - trip.start_time());
+        let events = events_per_vehicle.get_mut(&trip.vehicle).unwrap();
+        for ((visit, uncertainty), stop) in trip
+            .visits
+            .into_iter()
+            .zip(trip.uncertainty)
+            .zip(variant.stops().into_iter())
+        {
+            // Nobody boards at skipped stops
+            let (arrival_time, departure_time) = match visit {
+                Some(visit) => visit,
+                None => continue,
+            };
+            events.push(BoardingEvent {
+                vehicle: trip.vehicle,
+                variant: trip.variant,
+                trip: trip.trip,
+                stop,
+                arrival_time,
+                departure_time,
+                uncertainty,
+                new_riders: Vec::new(),
+                transfers: Vec::new(),
+                confidence: Some(trip.confidence),
+                unscheduled: trip.unscheduled,
+            });
+        }
+    }
+    for vehicle in &mut model.vehicles {
+        vehicle.timetable = timetables
+            .remove(&vehicle.id)
+            .unwrap_or_else(Timetable::new);
     }
 
-    // Sanity check multiple vehicles aren't assigned to the same trip.
+    // Sanity check multiple vehicles aren't assigned to the same trip. Global assignment should
+    // prevent this.
     let mut trip_to_vehicles: BTreeMap<TripID, BTreeSet<VehicleID>> = BTreeMap::new();
     for events in events_per_vehicle.values() {
-        for event in events {
+        for event in events.iter().filter(|ev| !ev.unscheduled) {
             trip_to_vehicles
                 .entry(event.trip)
                 .or_insert_with(BTreeSet::new)
                 .insert(event.vehicle);
         }
     }
-    let mut trip_problems = 0;
-    let total_found_trips = trip_to_vehicles.len();
-    for (trip, vehicles) in trip_to_vehicles {
+    let mut report = MatchingReport {
+        trips_matched: trip_to_vehicles.len(),
+        unscheduled_runs,
+        trip_durations: Summary::new(all_trip_durations),
+        ..Default::default()
+    };
+    for (trip, vehicles) in &trip_to_vehicles {
         if vehicles.len() > 1 {
-            trip_problems += 1;
-            error!(
-                "{:?} is assigned to multiple vehicles: {:?}",
-                trip, vehicles
-            );
+            report
+                .trips_with_multiple_vehicles
+                .push((*trip, vehicles.iter().cloned().collect()));
         }
     }
-    error!("{} trips with multiple vehicles", trip_problems);
 
     // Debug one vehicle
     let debug_vehicle = VehicleID(220);
@@ -160,19 +239,44 @@ pub fn populate_boarding(model: &mut DailyModel, timer: &mut Timer) -> Result<()
         }
     }
 
+    let mut per_vehicle: BTreeMap<VehicleID, VehicleReport> = model
+        .vehicles
+        .iter()
+        .map(|v| {
+            (
+                v.id,
+                VehicleReport {
+                    vehicle: v.id,
+                    possible_variants: evidence_per_vehicle
+                        .get(&v.id)
+                        .map(|x| x.possible_variants.len())
+                        .unwrap_or(0),
+                    trips: v
+                        .timetable
+                        .0
+                        .iter()
+                        .filter(|(_, _, run)| !run.unscheduled)
+                        .count(),
+                    ticketing_matched: 0,
+                    ticketing_unmatched: 0,
+                    route_name_mismatches: 0,
+                },
+            )
+        })
+        .collect();
+
     // Match each ticketing event to the appropriate vehicle. Assume people tap on AFTER boarding
     // the bus and match to the most recent stop time.
-    let mut matched_events = 0;
-    let mut unmatched_events = 0;
-
-    let mut route_name_mismatches = 0;
-    let mut delay_before_ticketing = Histogram::new();
-    let mut stop_dist_to_ticketing = Histogram::new();
+    let mut delay_before_ticketing = Vec::new();
+    let mut stop_dist_to_ticketing = Vec::new();
+    // Per vehicle and trip, how many matched ticketing events name the route, out of the total
+    let mut ticketing_agreement: BTreeMap<(VehicleID, TripID), (usize, usize)> = BTreeMap::new();
     for (journey_idx, journey) in model.journeys.iter().enumerate() {
         for (leg_idx, leg) in journey.legs.iter().enumerate() {
             let mut ok = false;
 
-            if let Ok(vehicle) = model.vehicle_ids.lookup(&leg.vehicle_name) {
+            let vehicle = model.vehicle_ids.lookup(&leg.vehicle_name).ok();
+            if let Some(vehicle) = vehicle {
                 // Check stops in reverse, so we can find the first stop occurring before this
                 // ticketing event
                 for event in events_per_vehicle
@@ -183,24 +287,32 @@ pub fn populate_boarding(model: &mut DailyModel, timer: &mut Timer) -> Result<()
                 {
                     if leg.time >= event.arrival_time {
                         // Sanity check: does the route on the ticketing event match what we've
-                        // assigned the vehicle?
-                        let vehicle_route = model.gtfs.routes
-                            [&model.gtfs.variant(event.variant).route_id]
-                            .short_name
-                            .as_ref()
-                            .unwrap();
-                        if &leg.route_short_name != vehicle_route {
-                            route_name_mismatches += 1;
+                        // assigned the vehicle? This refines the trip's ticketing signal, which
+                        // was based on all taps during the trip. Without a short name to compare,
+                        // the tap says nothing either way.
+                        if let Some(agrees) =
+                            tap_agrees(&model.gtfs, event.variant, &leg.route_short_name)
+                        {
+                            let agreement = ticketing_agreement
+                                .entry((vehicle, event.trip))
+                                .or_insert((0, 0));
+                            agreement.1 += 1;
+                            if agrees {
+                                agreement.0 += 1;
+                            } else {
+                                report.route_name_mismatches += 1;
+                                per_vehicle.get_mut(&vehicle).unwrap().route_name_mismatches += 1;
+                            }
                         }
 
                         // Sanity check: the ticketing event should happen shortly after the
                         // vehicle arrives at the stop
-                        delay_before_ticketing.add(leg.time - event.arrival_time);
+                        delay_before_ticketing.push(leg.time - event.arrival_time);
 
                         // Sanity check: the ticketing event shouldn't occur too far physically
                         // from the stop
                         stop_dist_to_ticketing
-                            .add(leg.pos.dist_to(model.gtfs.stops[&event.stop].pos));
+                            .push(leg.pos.dist_to(model.gtfs.stops[&event.stop].pos));
 
                         ok = true;
                         if leg_idx == 0 {
@@ -219,43 +331,65 @@ pub fn populate_boarding(model: &mut DailyModel, timer: &mut Timer) -> Result<()
             }
 
             if ok {
-                matched_events += 1;
+                report.ticketing_matched += 1;
             } else {
-                unmatched_events += 1;
+                report.ticketing_unmatched += 1;
+            }
+            if let Some(vehicle) = vehicle {
+                let per = per_vehicle.get_mut(&vehicle).unwrap();
+                if ok {
+                    per.ticketing_matched += 1;
+                } else {
+                    per.ticketing_unmatched += 1;
+                }
             }
         }
     }
+    for events in events_per_vehicle.values_mut() {
+        for event in events {
+            if let (Some(confidence), Some((agree, total))) = (
+                event.confidence.as_mut(),
+                ticketing_agreement.get(&(event.vehicle, event.trip)),
+            ) {
+                confidence.ticketing = Some(*agree as f64 / *total as f64);
+            }
+        }
+    }
+    report.tap_delay = Summary::new(delay_before_ticketing);
+    report.tap_distance = Summary::new(stop_dist_to_ticketing);
+    report.per_vehicle = per_vehicle.into_values().collect();
+
+    // Compare against every variant scheduled today
+    let filter = VariantFilter {
+        date_filter: DateFilter::SingleDay(model.date),
+        minimum_trips_per_day: 0,
+        route_type: None,
+        description_substring: String::new(),
+    };
+    for id in model.gtfs.variants_matching_filter(&filter) {
+        let variant = model.gtfs.variant(id);
+        let mut vehicles = BTreeSet::new();
+        let mut trips_matched = 0;
+        for trip in &variant.trips {
+            if let Some(list) = trip_to_vehicles.get(&trip.id) {
+                trips_matched += 1;
+                vehicles.extend(list.iter().cloned());
+            }
+        }
+        report.trips_expected += variant.trips.len();
+        report.per_variant.push(VariantReport {
+            variant: id,
+            trips_expected: variant.trips.len(),
+            trips_matched,
+            vehicles: vehicles.len(),
+        });
+    }
 
     info!("Final model quality");
-    let total_expected_gtfs_trips = model
-        .get_gtfs_trip_demand()
-        .into_values()
-        .map(|list| list.len())
-        .sum();
-    println!(
-        "{} total trips matched (GTFS says to expect {})",
-        prettyprint_usize(total_found_trips),
-        prettyprint_usize(total_expected_gtfs_trips)
-    );
-    println!("Trip durations: {}", all_trip_durations.describe());
-
-    println!(
-        "{} ticketing events matched to actual trips. {} unmatched",
-        prettyprint_usize(matched_events),
-        prettyprint_usize(unmatched_events)
-    );
-    println!(
-        "Of the matched, {} don't actually match the route name",
-        prettyprint_usize(route_name_mismatches)
-    );
-    println!(
-        "Of the matched, how long between the bus arriving and the ticketing? {}",
-        delay_before_ticketing.describe()
-    );
-    println!(
-        "Of the matched, how far between the bus stop and the ticketing event? {}",
-        stop_dist_to_ticketing.describe()
-    );
+    for line in report.describe() {
+        info!("{line}");
+    }
+    model.matching_report = report;
 
     // Flatten (not sure how boarding events will be used yet; this is obviously not the final
     // structure)
@@ -267,3 +401,34 @@ pub fn populate_boarding(model: &mut DailyModel, timer: &mut Timer) -> Result<()
     timer.stop("populate_boarding");
     Ok(())
 }
+
+// Does a tap naming some route agree with the variant the vehicle was matched to? None if the
+// variant's route has no short name to compare against.
+fn tap_agrees(gtfs: &GTFS, variant: RouteVariantID, route_short_name: &str) -> Option<bool> {
+    let name = gtfs.parent_of_variant(variant).short_name.as_ref()?;
+    Some(name == route_short_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use geom::Pt2D;
+
+    use super::*;
+    use crate::fixtures::{gtfs, RouteFixture};
+
+    #[test]
+    fn taps_on_routes_without_short_names() {
+        let route = |short_name| RouteFixture {
+            short_name,
+            stops: vec![0, 1],
+            start_time: Time::START_OF_DAY,
+        };
+        let gtfs = gtfs(
+            &[Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)],
+            vec![route(Some("7")), route(None)],
+        );
+        assert_eq!(tap_agrees(&gtfs, RouteVariantID(0), "7"), Some(true));
+        assert_eq!(tap_agrees(&gtfs, RouteVariantID(0), "8"), Some(false));
+        assert_eq!(tap_agrees(&gtfs, RouteVariantID(1), "7"), None);
+    }
+}
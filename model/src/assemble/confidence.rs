@@ -0,0 +1,134 @@
+use std::fmt;
+
+use geom::{Distance, Duration, Pt2D, Time};
+use serde::{Deserialize, Serialize};
+
+use gtfs::Trip;
+
+use crate::Trajectory;
+
+/// How much to trust an inferred trip. Each signal is between 0 (implausible) and 1 (looks
+/// perfect), and they're combined into one score.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Confidence {
+    /// Do the times between consecutive stops resemble the scheduled runtimes?
+    pub stop_gaps: f64,
+    /// How close does the vehicle pass to each stop, relative to the matching threshold?
+    pub stop_distance: f64,
+    /// How close are the actual times to the matched GTFS trip?
+    pub schedule_offset: f64,
+    /// What fraction of ticketing events name the same route? While choosing each vehicle's
+    /// schedule, this counts every tap on the vehicle between the start and end of the trip. Once
+    /// boardings are filled out, it's refined to just the taps matched to this trip's stops, if
+    /// there are any. None if there were no taps, which is common and not suspicious by itself.
+    pub ticketing: Option<f64>,
+}
+
+impl Confidence {
+    /// Calculates everything except the ticketing signal, which comes later. Skipped stops aren't
+    /// judged; `ActualTrip::quality` accounts for them.
+    pub fn new(
+        stop_times: &[Option<Time>],
+        stop_positions: &[Pt2D],
+        gtfs_trip: &Trip,
+        trajectory: &Trajectory,
+        stop_threshold: Distance,
+    ) -> Self {
+        let mut distance_scores = Vec::new();
+        for (time, pos) in stop_times.iter().zip(stop_positions) {
+            let time = match time {
+                Some(t) => t,
+                None => continue,
+            };
+            distance_scores.push(match trajectory.interpolate(*time) {
+                Some((pt, _)) => 1.0 - (pt.dist_to(*pos) / stop_threshold).min(1.0),
+                None => 0.0,
+            });
+        }
+
+        let mut confidence = Self {
+            stop_gaps: 0.0,
+            stop_distance: mean(distance_scores),
+            schedule_offset: 0.0,
+            ticketing: None,
+        };
+        confidence.rescore_schedule(stop_times, gtfs_trip);
+        confidence
+    }
+
+    /// Recalculates the signals that depend on which GTFS trip was matched
+    pub fn rescore_schedule(&mut self, stop_times: &[Option<Time>], gtfs_trip: &Trip) {
+        // Scheduled and actual gaps are compared as a ratio, with some slack, so that being 30s
+        // off between two close stops isn't disastrous. Only consecutive stops both visited are
+        // compared.
+        let slack = Duration::minutes(1);
+        let mut gap_scores = Vec::new();
+        for (actual, scheduled) in stop_times.windows(2).zip(gtfs_trip.stop_times.windows(2)) {
+            let actual = match actual {
+                [Some(t1), Some(t2)] => *t2 - *t1,
+                _ => continue,
+            };
+            let scheduled = scheduled[1].arrival_time - scheduled[0].arrival_time;
+            // Naive assembly can visit stops out of order, which is as implausible as it gets
+            if actual < Duration::ZERO || scheduled < Duration::ZERO {
+                gap_scores.push(0.0);
+                continue;
+            }
+            let (actual, scheduled) = (actual + slack, scheduled + slack);
+            gap_scores.push(actual.min(scheduled) / actual.max(scheduled));
+        }
+        self.stop_gaps = mean(gap_scores);
+
+        // Being this far off the schedule on average halves the signal
+        let offset_scale = Duration::minutes(10);
+        self.schedule_offset =
+            1.0 / (1.0 + mean_schedule_offset(stop_times, gtfs_trip) / offset_scale);
+    }
+
+    /// The geometric mean of all known signals, between 0 and 1. One terrible signal drags the
+    /// score down more than an average would.
+    pub fn score(&self) -> f64 {
+        let mut signals = vec![self.stop_gaps, self.stop_distance, self.schedule_offset];
+        signals.extend(self.ticketing);
+        let product: f64 = signals.iter().product();
+        product.powf(1.0 / signals.len() as f64)
+    }
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.0}% (stop gaps {:.0}%, stop distance {:.0}%, schedule offset {:.0}%, ticketing {})",
+            100.0 * self.score(),
+            100.0 * self.stop_gaps,
+            100.0 * self.stop_distance,
+            100.0 * self.schedule_offset,
+            match self.ticketing {
+                Some(x) => format!("{:.0}%", 100.0 * x),
+                None => "unknown".to_string(),
+            }
+        )
+    }
+}
+
+/// The average time difference between actual and scheduled arrivals at each visited stop
+pub fn mean_schedule_offset(stop_times: &[Option<Time>], gtfs_trip: &Trip) -> Duration {
+    let mut total = Duration::ZERO;
+    let mut count = 0;
+    for (actual, scheduled) in stop_times.iter().zip(&gtfs_trip.stop_times) {
+        if let Some(actual) = actual {
+            total += (*actual - scheduled.arrival_time).abs();
+            count += 1;
+        }
+    }
+    total / (count.max(1) as f64)
+}
+
+// 1 if there's nothing to judge
+fn mean(values: Vec<f64>) -> f64 {
+    if values.is_empty() {
+        return 1.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
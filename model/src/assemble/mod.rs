@@ -1,8 +1,23 @@
 // Stuff to assemble the AVL, GTFS, and BIL data together to tell a coherent story.
 
+mod along_route;
+mod assignment;
 mod boarding;
+mod confidence;
+mod report;
+mod spatial_candidates;
+mod stages;
+mod stop_passage;
 mod to_trips;
 mod vehicle_to_routes;
 
+pub use along_route::TripPaths;
 pub use boarding::{populate_boarding, BoardingEvent};
-pub use to_trips::{ActualTrip, BUS_TO_STOP_THRESHOLD};
+pub use confidence::Confidence;
+pub use report::{MatchingReport, Summary, VariantReport, VehicleReport};
+pub use stages::{load_depots, segment_vehicles, Depot, SegmentationConfig, Stage};
+pub use to_trips::{
+    ActualTrip, MatchingConfig, RejectedTrip, Rejection, ScheduleStrategy, SpatialCandidates,
+    StopPassage, TripAssembly, VehicleSchedule,
+};
+pub use vehicle_to_routes::{CandidateWindows, VehicleEvidence};
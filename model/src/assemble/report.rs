@@ -0,0 +1,157 @@
+use std::fmt::Display;
+
+use abstutil::prettyprint_usize;
+use geom::{Distance, Duration};
+use serde::{Deserialize, Serialize};
+
+use crate::VehicleID;
+use gtfs::{RouteVariantID, TripID};
+
+/// How well AVL, GTFS, and ticketing data were matched for one day. This is saved with each
+/// `DailyModel`, so data quality can be tracked over time.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MatchingReport {
+    /// Distinct GTFS trips matched to at least one vehicle
+    pub trips_matched: usize,
+    /// Trips GTFS schedules for this day
+    pub trips_expected: usize,
+    /// Trips matched to more than one vehicle. Probably a matching problem.
+    pub trips_with_multiple_vehicles: Vec<(TripID, Vec<VehicleID>)>,
+    /// Runs that didn't match any scheduled trip
+    pub unscheduled_runs: usize,
+    pub trip_durations: Option<Summary<Duration>>,
+
+    pub ticketing_matched: usize,
+    pub ticketing_unmatched: usize,
+    /// Matched ticketing events that name a different route than the vehicle was matched to
+    pub route_name_mismatches: usize,
+    /// For matched ticketing events, the time between the vehicle arriving at the stop and the
+    /// ticketing
+    pub tap_delay: Option<Summary<Duration>>,
+    /// For matched ticketing events, the distance between the stop and the ticketing
+    pub tap_distance: Option<Summary<Distance>>,
+
+    pub per_vehicle: Vec<VehicleReport>,
+    pub per_variant: Vec<VariantReport>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VehicleReport {
+    pub vehicle: VehicleID,
+    /// Variants the vehicle might serve, according to ticketing and AVL hints
+    pub possible_variants: usize,
+    /// Scheduled trips served. Unscheduled runs are in the vehicle's timetable, but not counted.
+    pub trips: usize,
+    pub ticketing_matched: usize,
+    pub ticketing_unmatched: usize,
+    pub route_name_mismatches: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VariantReport {
+    pub variant: RouteVariantID,
+    pub trips_expected: usize,
+    pub trips_matched: usize,
+    pub vehicles: usize,
+}
+
+/// Percentiles of some measurement
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Summary<T> {
+    pub count: usize,
+    pub min: T,
+    pub p50: T,
+    pub p90: T,
+    pub p99: T,
+    pub max: T,
+}
+
+impl<T: Copy + PartialOrd + Display> Summary<T> {
+    /// None if there are no values
+    pub fn new(mut values: Vec<T>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let pct = |p: f64| values[((values.len() - 1) as f64 * p) as usize];
+        Some(Self {
+            count: values.len(),
+            min: values[0],
+            p50: pct(0.5),
+            p90: pct(0.9),
+            p99: pct(0.99),
+            max: *values.last().unwrap(),
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} values. min {}, 50%ile {}, 90%ile {}, 99%ile {}, max {}",
+            prettyprint_usize(self.count),
+            self.min,
+            self.p50,
+            self.p90,
+            self.p99,
+            self.max
+        )
+    }
+}
+
+impl MatchingReport {
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!(
+                "{} trips matched (GTFS says to expect {}). {} matched to multiple vehicles",
+                prettyprint_usize(self.trips_matched),
+                prettyprint_usize(self.trips_expected),
+                prettyprint_usize(self.trips_with_multiple_vehicles.len())
+            ),
+            format!(
+                "{} runs don't match any scheduled trip",
+                prettyprint_usize(self.unscheduled_runs)
+            ),
+            format!("Trip durations: {}", describe(&self.trip_durations)),
+            format!(
+                "{} ticketing events matched to actual trips. {} unmatched",
+                prettyprint_usize(self.ticketing_matched),
+                prettyprint_usize(self.ticketing_unmatched)
+            ),
+            format!(
+                "Of the matched, {} don't actually match the route name",
+                prettyprint_usize(self.route_name_mismatches)
+            ),
+            format!(
+                "Of the matched, how long between the bus arriving and the ticketing? {}",
+                describe(&self.tap_delay)
+            ),
+            format!(
+                "Of the matched, how far between the bus stop and the ticketing event? {}",
+                describe(&self.tap_distance)
+            ),
+        ];
+        let vehicles_with_trips = self.per_vehicle.iter().filter(|v| v.trips > 0).count();
+        lines.push(format!(
+            "{} of {} vehicles matched to at least one trip",
+            prettyprint_usize(vehicles_with_trips),
+            prettyprint_usize(self.per_vehicle.len())
+        ));
+        let variants_with_trips = self
+            .per_variant
+            .iter()
+            .filter(|v| v.trips_matched > 0)
+            .count();
+        lines.push(format!(
+            "{} of {} variants scheduled today matched to at least one trip",
+            prettyprint_usize(variants_with_trips),
+            prettyprint_usize(self.per_variant.len())
+        ));
+        lines
+    }
+}
+
+fn describe<T: Copy + PartialOrd + Display>(summary: &Option<Summary<T>>) -> String {
+    match summary {
+        Some(x) => x.describe(),
+        None => "no values".to_string(),
+    }
+}
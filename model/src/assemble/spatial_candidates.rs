@@ -0,0 +1,102 @@
+use abstutil::Timer;
+use geom::{Bounds, Distance, Pt2D};
+use gtfs::{DateFilter, RouteVariantID, VariantFilter};
+
+use crate::{DailyModel, Trajectory, VehicleID};
+
+// Without ticketing, there's no direct evidence of what route a vehicle serves. Instead, guess from
+// AVL alone: score every variant active that day by how much of its shape and stops the
+// trajectory covers.
+
+// Where one variant goes
+struct Footprint {
+    variant: RouteVariantID,
+    stops: Vec<Pt2D>,
+    shape_samples: Vec<Pt2D>,
+    bounds: Bounds,
+}
+
+impl DailyModel {
+    /// For each vehicle, the variants whose shape and stops its trajectory covers well enough,
+    /// with their coverage score from 0 to 1
+    pub fn spatial_candidates(
+        &self,
+        vehicles: Vec<VehicleID>,
+        timer: &mut Timer,
+    ) -> Vec<(VehicleID, Vec<(RouteVariantID, f64)>)> {
+        let footprints = self.variant_footprints();
+        timer.parallelize("find spatial candidates", vehicles, |vehicle| {
+            let trajectory = &self.vehicles[vehicle.0].trajectory;
+            let bounds = trajectory.as_polyline().get_bounds();
+            let mut candidates = Vec::new();
+            for footprint in &footprints {
+                if !overlaps(&bounds, &footprint.bounds) {
+                    continue;
+                }
+                let score = self.coverage(trajectory, footprint);
+                if score >= self.matching.min_spatial_coverage {
+                    candidates.push((footprint.variant, score));
+                }
+            }
+            (vehicle, candidates)
+        })
+    }
+
+    fn variant_footprints(&self) -> Vec<Footprint> {
+        let filter = VariantFilter {
+            date_filter: DateFilter::SingleDay(self.date),
+            minimum_trips_per_day: 0,
+            route_type: None,
+            description_substring: String::new(),
+        };
+        let mut footprints = Vec::new();
+        for id in self.gtfs.variants_matching_filter(&filter) {
+            let variant = self.gtfs.variant(id);
+            let shape = match self.variant_shape(id) {
+                Ok(shape) => shape,
+                Err(_) => continue,
+            };
+            let pl = shape.polyline();
+            // Check the shape is covered this often
+            let spacing = Distance::meters(100.0);
+            let mut shape_samples = Vec::new();
+            let mut dist = Distance::ZERO;
+            while dist <= pl.length() {
+                if let Ok((pt, _)) = pl.dist_along(dist) {
+                    shape_samples.push(pt);
+                }
+                dist += spacing;
+            }
+            footprints.push(Footprint {
+                variant: id,
+                stops: variant
+                    .stops()
+                    .into_iter()
+                    .map(|stop| self.gtfs.stops[&stop].pos)
+                    .collect(),
+                shape_samples,
+                bounds: pl.get_bounds(),
+            });
+        }
+        footprints
+    }
+
+    // The fraction of stops the trajectory passes, averaged with the fraction of the shape
+    fn coverage(&self, trajectory: &Trajectory, footprint: &Footprint) -> f64 {
+        let fraction_near = |pts: &[Pt2D]| {
+            if pts.is_empty() {
+                return 0.0;
+            }
+            let near = pts
+                .iter()
+                .filter(|pt| trajectory.passes_near(**pt, self.matching.stop_threshold))
+                .count();
+            near as f64 / pts.len() as f64
+        };
+        (fraction_near(&footprint.stops) + fraction_near(&footprint.shape_samples)) / 2.0
+    }
+}
+
+fn overlaps(b1: &Bounds, b2: &Bounds) -> bool {
+    b1.min_x <= b2.max_x && b2.min_x <= b1.max_x && b1.min_y <= b2.max_y && b2.min_y <= b1.max_y
+}
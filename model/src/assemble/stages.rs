@@ -0,0 +1,321 @@
+use std::collections::BTreeSet;
+
+use abstutil::Timer;
+use anyhow::Result;
+use geom::{Distance, Duration, FindClosest, GPSBounds, LonLat, Polygon, Pt2D, Ring, Time};
+use serde::{Deserialize, Serialize};
+
+use crate::{DailyModel, Timetable, VehicleID};
+use gtfs::StopID;
+
+// Label every part of a vehicle's day with what it's doing. This runs after vehicles are matched
+// to trips, since "in service" comes from the timetable.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Stage {
+    /// Moving while serving a trip
+    InService,
+    /// Stopped at a stop, either while serving a trip or away from a terminal
+    DwellingAtStop,
+    /// Stopped at the first or last stop of a variant the vehicle serves that day, between trips
+    LayoverAtTerminal,
+    /// Inside a user-provided depot polygon
+    InDepot,
+    /// Moving without serving a trip
+    Deadheading,
+    /// Stopped away from any stop
+    StoppedInTraffic,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SegmentationConfig {
+    /// A vehicle staying within this distance of one spot...
+    pub stationary_radius: Distance,
+    /// ...for at least this long is considered stopped
+    pub min_stationary: Duration,
+}
+
+impl Default for SegmentationConfig {
+    fn default() -> Self {
+        Self {
+            stationary_radius: Distance::meters(10.0),
+            min_stationary: Duration::seconds(20.0),
+        }
+    }
+}
+
+pub struct Depot {
+    pub name: Option<String>,
+    pub polygon: Polygon,
+}
+
+/// Reads polygons from a GeoJSON FeatureCollection. The optional `name` property is kept.
+pub fn load_depots<R: std::io::Read>(mut reader: R, gps_bounds: &GPSBounds) -> Result<Vec<Depot>> {
+    use geojson::{GeoJson, Value};
+
+    let mut raw = String::new();
+    reader.read_to_string(&mut raw)?;
+    let collection = match raw.parse::<GeoJson>()? {
+        GeoJson::FeatureCollection(x) => x,
+        _ => bail!("Depots must be a GeoJSON FeatureCollection"),
+    };
+
+    let mut depots = Vec::new();
+    for feature in collection.features {
+        let name = feature
+            .property("name")
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_string());
+        let polygons = match feature.geometry.map(|g| g.value) {
+            Some(Value::Polygon(rings)) => vec![rings],
+            Some(Value::MultiPolygon(polygons)) => polygons,
+            _ => {
+                warn!("Skipping a depot ({:?}) that isn't a polygon", name);
+                continue;
+            }
+        };
+        for mut rings in polygons {
+            // Ignore holes
+            if rings.is_empty() {
+                continue;
+            }
+            let pts = rings
+                .remove(0)
+                .into_iter()
+                .map(|pos| LonLat::new(pos[0], pos[1]).to_pt(gps_bounds))
+                .collect();
+            match Ring::new(pts) {
+                Ok(ring) => depots.push(Depot {
+                    name: name.clone(),
+                    polygon: ring.into_polygon(),
+                }),
+                Err(err) => {
+                    warn!("Skipping a depot ({:?}) with a bad polygon: {}", name, err);
+                }
+            }
+        }
+    }
+    Ok(depots)
+}
+
+pub fn segment_vehicles(
+    model: &mut DailyModel,
+    depots: &[Depot],
+    config: &SegmentationConfig,
+    timer: &mut Timer,
+) {
+    let mut closest_stop: FindClosest<StopID> = FindClosest::new();
+    for (id, stop) in &model.gtfs.stops {
+        closest_stop.add(*id, &[stop.pos]);
+    }
+    let model_ref = &*model;
+    for (vehicle, stages) in timer.parallelize(
+        "segment vehicle trajectories",
+        model.vehicles.iter().map(|v| v.id).collect(),
+        |vehicle| {
+            (
+                vehicle,
+                segment(model_ref, vehicle, depots, &closest_stop, config),
+            )
+        },
+    ) {
+        model.vehicles[vehicle.0].stages = stages;
+    }
+}
+
+fn segment(
+    model: &DailyModel,
+    id: VehicleID,
+    depots: &[Depot],
+    closest_stop: &FindClosest<StopID>,
+    config: &SegmentationConfig,
+) -> Timetable<Stage> {
+    let vehicle = &model.vehicles[id.0];
+    // Only the ends of variants this vehicle serves count. Idling at the terminal of some other
+    // route is just stopping there.
+    let mut terminals = BTreeSet::new();
+    for (_, _, run) in &vehicle.timetable.0 {
+        let stops = model.gtfs.variant(run.variant).stops();
+        if let (Some(first), Some(last)) = (stops.first(), stops.last()) {
+            terminals.insert(*first);
+            terminals.insert(*last);
+        }
+    }
+    let stationary = vehicle
+        .trajectory
+        .stationary_periods(config.stationary_radius, config.min_stationary);
+
+    // Split the day everywhere something might change, then label each piece by what's happening
+    // in the middle of it
+    let mut breakpoints = vec![
+        vehicle.trajectory.start_time(),
+        vehicle.trajectory.end_time(),
+    ];
+    for (_, t1, t2) in &stationary {
+        breakpoints.push(*t1);
+        breakpoints.push(*t2);
+    }
+    for (t1, t2, _) in &vehicle.timetable.0 {
+        breakpoints.push(*t1);
+        breakpoints.push(*t2);
+    }
+    breakpoints
+        .retain(|t| *t >= vehicle.trajectory.start_time() && *t <= vehicle.trajectory.end_time());
+    breakpoints.sort();
+    breakpoints.dedup();
+
+    let in_depot = |pt: Pt2D| depots.iter().any(|d| d.polygon.contains_pt(pt));
+
+    let mut stages: Vec<(Time, Time, Stage)> = Vec::new();
+    for pair in breakpoints.windows(2) {
+        let (t1, t2) = (pair[0], pair[1]);
+        let mid = t1 + (t2 - t1) / 2.0;
+        let in_service = vehicle.timetable.get_at_time(mid).is_some();
+        let stopped_at = stationary
+            .iter()
+            .find(|(_, start, end)| mid >= *start && mid <= *end)
+            .map(|(pt, _, _)| *pt);
+
+        let stage = if let Some(pt) = stopped_at {
+            if in_depot(pt) {
+                Stage::InDepot
+            } else if let Some((stop, _)) =
+                closest_stop.closest_pt(pt, model.matching.stop_threshold)
+            {
+                if !in_service && terminals.contains(&stop) {
+                    Stage::LayoverAtTerminal
+                } else {
+                    Stage::DwellingAtStop
+                }
+            } else {
+                Stage::StoppedInTraffic
+            }
+        } else {
+            let pt = vehicle.trajectory.interpolate(mid).unwrap().0;
+            if in_depot(pt) {
+                Stage::InDepot
+            } else if in_service {
+                Stage::InService
+            } else {
+                Stage::Deadheading
+            }
+        };
+
+        match stages.last_mut() {
+            Some(last) if last.2 == stage => {
+                last.1 = t2;
+            }
+            _ => {
+                stages.push((t1, t2, stage));
+            }
+        }
+    }
+    Timetable(stages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{daily_model, gtfs, trajectory, RouteFixture};
+    use crate::ServedTrip;
+    use gtfs::{CheapID, RouteVariantID};
+
+    fn time(secs: f64) -> Time {
+        Time::START_OF_DAY + Duration::seconds(secs)
+    }
+
+    #[test]
+    fn layovers_only_at_own_terminals() {
+        let gtfs = gtfs(
+            &[
+                Pt2D::new(0.0, 0.0),
+                Pt2D::new(1000.0, 0.0),
+                Pt2D::new(2000.0, 0.0),
+                Pt2D::new(3000.0, 0.0),
+            ],
+            vec![
+                RouteFixture {
+                    short_name: Some("1"),
+                    stops: vec![0, 1],
+                    start_time: time(60.0),
+                },
+                RouteFixture {
+                    short_name: Some("2"),
+                    stops: vec![2, 3],
+                    start_time: time(60.0),
+                },
+            ],
+        );
+        // Wait at the start of route 1, serve it, wait at its end, then drive to the start of
+        // route 2 and wait there
+        let mut model = daily_model(
+            gtfs,
+            vec![trajectory(vec![
+                (0.0, 0.0, 0.0),
+                (0.0, 0.0, 60.0),
+                (1000.0, 0.0, 160.0),
+                (1000.0, 0.0, 220.0),
+                (2000.0, 0.0, 320.0),
+                (2000.0, 0.0, 380.0),
+            ])],
+        );
+        model.vehicles[0].timetable = Timetable(vec![(
+            time(60.0),
+            time(160.0),
+            ServedTrip {
+                trip: CheapID::new(0),
+                variant: RouteVariantID(0),
+                unscheduled: false,
+            },
+        )]);
+
+        segment_vehicles(
+            &mut model,
+            &[],
+            &SegmentationConfig::default(),
+            &mut Timer::throwaway(),
+        );
+        assert_eq!(
+            model.vehicles[0].stages.0,
+            vec![
+                (time(0.0), time(60.0), Stage::LayoverAtTerminal),
+                (time(60.0), time(160.0), Stage::InService),
+                (time(160.0), time(220.0), Stage::LayoverAtTerminal),
+                (time(220.0), time(320.0), Stage::Deadheading),
+                (time(320.0), time(380.0), Stage::DwellingAtStop),
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_bad_depots() {
+        let mut gps_bounds = GPSBounds::new();
+        gps_bounds.update(LonLat::new(10.0, 50.0));
+        gps_bounds.update(LonLat::new(10.1, 50.1));
+        let geojson = r#"{
+  "type": "FeatureCollection",
+  "features": [
+    {
+      "type": "Feature",
+      "properties": { "name": "unclosed" },
+      "geometry": {
+        "type": "Polygon",
+        "coordinates": [[[10.01, 50.01], [10.02, 50.01], [10.02, 50.02]]]
+      }
+    },
+    {
+      "type": "Feature",
+      "properties": { "name": "north" },
+      "geometry": {
+        "type": "Polygon",
+        "coordinates": [[[10.01, 50.01], [10.02, 50.01], [10.02, 50.02], [10.01, 50.02], [10.01, 50.01]]]
+      }
+    }
+  ]
+}"#;
+        let depots = load_depots(geojson.as_bytes(), &gps_bounds).unwrap();
+        assert_eq!(depots.len(), 1);
+        assert_eq!(depots[0].name.as_deref(), Some("north"));
+    }
+}
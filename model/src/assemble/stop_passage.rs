@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use geom::{Distance, Time};
+use gtfs::RouteVariantID;
+
+use crate::{DailyModel, LinearShape, ProjectedTrajectory, VehicleID};
+
+// Passing within some radius of a stop doesn't mean visiting it. A bus on the other side of the
+// street headed the other way, or crossing the route at an intersection, also passes close by.
+// Instead, follow the vehicle's progress along the variant's shape, and only count moving forwards
+// past the stop's distance along it.
+
+impl DailyModel {
+    /// Projects a vehicle's trajectory onto a variant's shape, from `variant_shape`. Points further
+    /// than `stop_threshold` from the shape are off-route.
+    pub fn project_onto_variant(
+        &self,
+        vehicle: VehicleID,
+        variant: RouteVariantID,
+    ) -> Result<ProjectedTrajectory> {
+        let shape = Arc::new(self.variant_shape(variant)?);
+        Ok(self.vehicles[vehicle.0]
+            .trajectory
+            .project_onto_shape(shape, self.matching.stop_threshold))
+    }
+
+    /// The distance along the shape of each of the variant's stops, in order. A shape passing the
+    /// same spot twice doesn't confuse them.
+    pub fn stop_dists_along_shape(
+        &self,
+        variant: RouteVariantID,
+        shape: &LinearShape,
+    ) -> Option<Vec<Distance>> {
+        let mut dists = Vec::new();
+        let mut last_dist = Distance::ZERO;
+        for stop in self.gtfs.variant(variant).stops() {
+            let dist = shape.dist_along_after(
+                self.gtfs.stops[&stop].pos,
+                last_dist,
+                self.matching.stop_threshold,
+            )?;
+            dists.push(dist);
+            last_dist = dist;
+        }
+        Some(dists)
+    }
+
+    /// For each stop of the variant, all times the vehicle passes it while travelling along the
+    /// variant's shape, from `project_onto_variant`. None if the variant's stops can't be placed
+    /// along its shape.
+    pub(crate) fn directional_stop_passages(
+        &self,
+        projected: &ProjectedTrajectory,
+        variant: RouteVariantID,
+    ) -> Option<Vec<Vec<Time>>> {
+        let stop_dists = self.stop_dists_along_shape(variant, projected.shape())?;
+        Some(projected.arrivals_at_each(&stop_dists, self.matching.dedupe_passes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geom::Pt2D;
+
+    use super::*;
+    use crate::fixtures::{daily_model, gtfs, trajectory, RouteFixture};
+
+    // Route 0 heads east along y=0, and route 1 heads back west on the other side of the street,
+    // with each stop paired across from one of route 0's
+    fn model(trajectories: Vec<Vec<(f64, f64, f64)>>) -> DailyModel {
+        let gtfs = gtfs(
+            &[
+                Pt2D::new(0.0, 0.0),
+                Pt2D::new(500.0, 0.0),
+                Pt2D::new(1000.0, 0.0),
+                Pt2D::new(1000.0, -15.0),
+                Pt2D::new(500.0, -15.0),
+                Pt2D::new(0.0, -15.0),
+            ],
+            vec![
+                RouteFixture {
+                    short_name: Some("east"),
+                    stops: vec![0, 1, 2],
+                    start_time: Time::START_OF_DAY,
+                },
+                RouteFixture {
+                    short_name: Some("west"),
+                    stops: vec![3, 4, 5],
+                    start_time: Time::START_OF_DAY,
+                },
+            ],
+        );
+        daily_model(gtfs, trajectories.into_iter().map(trajectory).collect())
+    }
+
+    // Every 100m at 10m/s, offset from the street's center
+    fn drive(from_x: f64, to_x: f64, y: f64) -> Vec<(f64, f64, f64)> {
+        (0..=10)
+            .map(|i| {
+                let pct = i as f64 / 10.0;
+                (from_x + (to_x - from_x) * pct, y, 10.0 * i as f64)
+            })
+            .collect()
+    }
+
+    fn passages(model: &DailyModel, vehicle: usize, variant: usize) -> Vec<Vec<f64>> {
+        let variant = RouteVariantID(variant);
+        let projected = model
+            .project_onto_variant(VehicleID(vehicle), variant)
+            .unwrap();
+        model
+            .directional_stop_passages(&projected, variant)
+            .unwrap()
+            .into_iter()
+            .map(|times| {
+                times
+                    .into_iter()
+                    .map(|t| (t - Time::START_OF_DAY).inner_seconds().round())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn forward_pass() {
+        let model = model(vec![drive(0.0, 1000.0, 5.0)]);
+        assert_eq!(
+            passages(&model, 0, 0),
+            vec![vec![0.0], vec![50.0], vec![100.0]]
+        );
+    }
+
+    #[test]
+    fn opposite_direction() {
+        // Close enough to route 0's stops to count with a radius, but headed the wrong way
+        let model = model(vec![drive(1000.0, 0.0, -10.0)]);
+        assert_eq!(passages(&model, 0, 0), vec![Vec::<f64>::new(); 3]);
+        assert_eq!(
+            passages(&model, 0, 1),
+            vec![vec![0.0], vec![50.0], vec![100.0]]
+        );
+    }
+
+    #[test]
+    fn perpendicular_crossing() {
+        // Crossing the street right at the middle stop, slightly diagonally, so the vehicle
+        // does move forwards along the shape, just not enough
+        let model = model(vec![vec![
+            (490.0, -300.0, 0.0),
+            (490.0, -20.0, 28.0),
+            (510.0, 20.0, 32.0),
+            (510.0, 300.0, 60.0),
+        ]]);
+        assert_eq!(passages(&model, 0, 0), vec![Vec::<f64>::new(); 3]);
+        assert_eq!(passages(&model, 0, 1), vec![Vec::<f64>::new(); 3]);
+    }
+}
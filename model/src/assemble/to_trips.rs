@@ -1,64 +1,217 @@
-use geom::{Distance, Duration, Time};
+use geom::{Distance, Duration, Pt2D, Time};
+use serde::{Deserialize, Serialize};
 
-use crate::{DailyModel, Timetable, VehicleID};
+use super::{Confidence, VehicleEvidence};
+use crate::{DailyModel, ProjectedTrajectory, Timetable, VehicleID};
 use gtfs::{RouteVariantID, TripID};
 
-pub const BUS_TO_STOP_THRESHOLD: Distance = Distance::const_meters(30.0);
+/// Everything controlling how AVL, GTFS, and ticketing data are matched. The values used are
+/// saved in each `DailyModel`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MatchingConfig {
+    /// A vehicle passing this close to a stop is considered to visit it
+    pub stop_threshold: Distance,
+    /// Passes near the same stop closer together than this count as one visit
+    pub dedupe_passes: Duration,
+    /// A card's ticketing events within this long of its first event belong to one journey...
+    pub journey_window: Duration,
+    /// ...as long as the journey has no more than this many legs
+    pub max_journey_legs: usize,
+    /// How close the ends of a GTFS shape must be to a road to snap it to the street network
+    pub snap_threshold: Distance,
+    pub stop_passage: StopPassage,
+    /// A run further than this from every scheduled trip left, on average per stop, is an
+    /// unscheduled extra
+    pub max_schedule_offset: Duration,
+    pub schedule_strategy: ScheduleStrategy,
+    pub trip_assembly: TripAssembly,
+    /// With `TripAssembly::InOrder`, a trip may miss this many of its stops, including stops cut
+    /// off at the start or end
+    pub max_skipped_stops: usize,
+    /// With `TripAssembly::InOrder`, a vehicle taking longer than this between two stops of a trip
+    /// is assumed to have abandoned it. Its later times belong to another trip.
+    pub max_stop_gap: Duration,
+    pub spatial_candidates: SpatialCandidates,
+    /// Variants guessed from AVL alone must have at least this fraction of their stops and shape
+    /// covered by the trajectory
+    pub min_spatial_coverage: f64,
+}
+
+impl Default for MatchingConfig {
+    fn default() -> Self {
+        Self {
+            stop_threshold: Distance::meters(30.0),
+            dedupe_passes: Duration::seconds(30.0),
+            journey_window: Duration::hours(2),
+            max_journey_legs: 4,
+            snap_threshold: Distance::meters(50.0),
+            stop_passage: StopPassage::Directional,
+            max_schedule_offset: Duration::minutes(20),
+            schedule_strategy: ScheduleStrategy::MaxQuality,
+            trip_assembly: TripAssembly::InOrder,
+            max_skipped_stops: 3,
+            max_stop_gap: Duration::minutes(30),
+            spatial_candidates: SpatialCandidates::Fallback,
+            min_spatial_coverage: 0.8,
+        }
+    }
+}
+
+/// How to find the times a vehicle visits a stop
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopPassage {
+    /// Any pass within `stop_threshold` of the stop, in any direction
+    Radius,
+    /// Follow the vehicle along the variant's shape, and find when it moves forwards past the
+    /// stop. Passes on the other side of the street or across the route don't count. Falls back
+    /// to `Radius` when the stops can't be placed along the shape.
+    Directional,
+}
+
+/// How `infer_vehicle_schedule` chooses between overlapping candidate trips
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleStrategy {
+    /// Walk through in order of start time, greedily adding a trip if it doesn't overlap. Long
+    /// trips (usually buggy) often win.
+    EarliestStart,
+    /// Sort by trip duration, then insert those into a schedule as they fit
+    ShortestFirst,
+    /// Choose the non-overlapping trips with the most total `ActualTrip::quality`. Exact, unlike
+    /// the greedy strategies.
+    MaxQuality,
+}
+
+/// When to guess the variants a vehicle might serve from its trajectory alone, instead of
+/// ticketing and AVL hints
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpatialCandidates {
+    Never,
+    /// Only for vehicles without any other evidence
+    Fallback,
+    /// For every vehicle, in addition to other evidence. Slow.
+    Always,
+}
+
+/// How times near each stop are assembled into trips
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TripAssembly {
+    /// The Nth trip uses the Nth time at every stop
+    Naive,
+    /// Start from the earliest time at one of the first stops, and always require times to
+    /// increase, skipping times that are out of order. Stops may be missed, within
+    /// `max_skipped_stops` and `max_stop_gap`.
+    InOrder,
+}
 
 impl DailyModel {
     /// Given one vehicle, use `get_trips_for_vehicle_and_variant` against all possible variants,
     /// then merge the results into one schedule through the day. Returns non-overlapping trips in
-    /// order.
+    /// order, and why every other candidate was rejected.
     ///
     /// Note we don't check that the same TripID isn't covered by two different vehicles. This
-    /// method looks at one vehicle only.
+    /// method looks at one vehicle only; `assign_trips_globally` resolves that afterwards.
     ///
-    /// Pass in `possible_variants` for optimization, otherwise this calculates it.
+    /// Pass in `evidence` for optimization, otherwise this calculates it.
     pub fn infer_vehicle_schedule(
         &self,
         vehicle: VehicleID,
-        debug: bool,
-        possible_variants: Option<Vec<RouteVariantID>>,
-    ) -> Vec<ActualTrip> {
+        evidence: Option<VehicleEvidence>,
+    ) -> VehicleSchedule {
+        let evidence = evidence.unwrap_or_else(|| self.vehicle_evidence(vehicle));
         let mut all_possible_trips = Vec::new();
-        for variant in possible_variants.unwrap_or_else(|| self.vehicle_to_possible_routes(vehicle))
-        {
-            all_possible_trips.extend(self.get_trips_for_vehicle_and_variant(vehicle, variant));
+        let mut rejected = Vec::new();
+        for (variant, windows) in &evidence.possible_variants {
+            let route_short_name = self.gtfs.parent_of_variant(*variant).short_name.as_ref();
+            for mut trip in self.get_trips_for_vehicle_and_variant(vehicle, *variant) {
+                if !windows
+                    .iter()
+                    .any(|(t1, t2)| *t1 <= trip.start_time() && trip.end_time() <= *t2)
+                {
+                    rejected.push(RejectedTrip {
+                        reason: Rejection::OutsideWindows(windows.clone()),
+                        trip,
+                    });
+                    continue;
+                }
+                if let Some(name) = route_short_name {
+                    trip.confidence.ticketing =
+                        evidence.ticketing_agreement(trip.start_time(), trip.end_time(), name);
+                }
+                all_possible_trips.push(trip);
+            }
         }
 
-        // Walk through in order of start time. Greedily add a trip if the time intervals don't
-        // overlap.
-        //
-        // Long trips (usually buggy) often win.
-        if false {
+        if self.matching.schedule_strategy == ScheduleStrategy::EarliestStart {
             all_possible_trips.sort_by_key(|t| t.start_time());
-            let mut final_schedule: Vec<ActualTrip> = Vec::new();
+            let mut trips: Vec<ActualTrip> = Vec::new();
             for trip in all_possible_trips {
-                if final_schedule
-                    .last()
-                    .as_ref()
-                    .map(|last| last.end_time() < trip.start_time())
-                    .unwrap_or(true)
-                {
-                    final_schedule.push(trip);
-                } else if debug {
-                    println!("Skipping {}", trip.summary());
+                match trips.last() {
+                    Some(last) if last.end_time() >= trip.start_time() => {
+                        rejected.push(RejectedTrip {
+                            reason: Rejection::Overlaps(vec![last.trip]),
+                            trip,
+                        });
+                    }
+                    _ => {
+                        trips.push(trip);
+                    }
                 }
             }
-            return final_schedule;
+            return VehicleSchedule { trips, rejected };
         }
 
-        // Sort by trip duration, then insert those into a schedule as they fit.
-        all_possible_trips.sort_by_key(|t| t.end_time() - t.start_time());
-        let mut timetable = Timetable::new();
-        for trip in all_possible_trips {
-            if timetable.is_free((trip.start_time(), trip.end_time())) {
-                timetable.assign((trip.start_time(), trip.end_time()), trip);
-            } else if debug {
-                println!("Skipping {}", trip.summary());
+        if self.matching.schedule_strategy == ScheduleStrategy::ShortestFirst {
+            all_possible_trips.sort_by_key(|t| t.end_time() - t.start_time());
+            let mut timetable = Timetable::new();
+            for trip in all_possible_trips {
+                let interval = (trip.start_time(), trip.end_time());
+                if timetable.is_free(interval) {
+                    timetable.assign(interval, trip);
+                } else {
+                    rejected.push(RejectedTrip {
+                        reason: Rejection::Overlaps(
+                            timetable
+                                .overlapping(interval)
+                                .into_iter()
+                                .map(|t| t.trip)
+                                .collect(),
+                        ),
+                        trip,
+                    });
+                }
             }
+            return VehicleSchedule {
+                trips: timetable.0.into_iter().map(|(_, _, trip)| trip).collect(),
+                rejected,
+            };
+        }
+
+        let (timetable, losers) = Timetable::max_weight(
+            all_possible_trips
+                .into_iter()
+                .map(|trip| (trip.start_time(), trip.end_time(), trip.quality(), trip))
+                .collect(),
+        );
+        for (t1, t2, quality, trip) in losers {
+            // A candidate only loses to the chosen trips it overlaps. Swapping it in for those
+            // would otherwise increase the total.
+            let chosen = timetable.overlapping((t1, t2));
+            let reason = if quality <= 0.0 {
+                Rejection::Worthless
+            } else {
+                Rejection::Outweighed {
+                    quality,
+                    chosen: chosen.iter().map(|t| t.trip).collect(),
+                    chosen_quality: chosen.iter().map(|t| t.quality()).sum(),
+                }
+            };
+            rejected.push(RejectedTrip { trip, reason });
+        }
+        VehicleSchedule {
+            trips: timetable.0.into_iter().map(|(_, _, trip)| trip).collect(),
+            rejected,
         }
-        timetable.0.into_iter().map(|(_, _, trip)| trip).collect()
     }
 
     /// Given a vehicle and one variant it possibly serves (according to ticketing), match its
@@ -72,7 +225,8 @@ impl DailyModel {
         vehicle: VehicleID,
         variant: RouteVariantID,
     ) -> Vec<ActualTrip> {
-        let trips = self.get_trip_times(vehicle, variant);
+        let projected = self.project_onto_variant(vehicle, variant).ok();
+        let trips = self.get_trip_times(vehicle, variant, projected.as_ref());
 
         let gtfs_trips = &self.gtfs.variant(variant).trips;
         if trips.len() > gtfs_trips.len() {
@@ -84,28 +238,64 @@ impl DailyModel {
             );
         }
 
+        let trajectory = &self.vehicles[vehicle.0].trajectory;
+        let stop_positions: Vec<Pt2D> = self
+            .gtfs
+            .variant(variant)
+            .stops()
+            .into_iter()
+            .map(|stop| self.gtfs.stops[&stop].pos)
+            .collect();
         let mut results = Vec::new();
 
         for stop_times in trips {
             // Which GTFS trip is this? If all scheduled trips occurred, we could just match them
             // up in order, but that's rarely the case. Minimize the sum of time differences over
             // all stops.
-            let trip = gtfs_trips
+            let gtfs_trip = gtfs_trips
                 .iter()
                 .min_by_key(|trip| {
                     let mut sum_diff = Duration::ZERO;
                     for (actual_time, stop_time) in stop_times.iter().zip(trip.stop_times.iter()) {
-                        sum_diff += (*actual_time - stop_time.arrival_time).abs();
+                        if let Some(actual_time) = actual_time {
+                            sum_diff += (*actual_time - stop_time.arrival_time).abs();
+                        }
                     }
                     sum_diff
                 })
-                .map(|trip| trip.id)
                 .unwrap();
+            // The vehicle may stop for a while around when it passed each stop
+            let visits: Vec<Option<(Time, Time)>> = stop_times
+                .iter()
+                .zip(&stop_positions)
+                .map(|(time, pos)| {
+                    time.map(|t| trajectory.stop_visit(*pos, self.matching.stop_threshold, t))
+                })
+                .collect();
+            let uncertainty = stop_times
+                .iter()
+                .map(|time| {
+                    projected
+                        .as_ref()
+                        .and_then(|projected| projected.time_uncertainty((*time)?))
+                })
+                .collect();
+            let confidence = Confidence::new(
+                &stop_times,
+                &stop_positions,
+                gtfs_trip,
+                trajectory,
+                self.matching.stop_threshold,
+            );
             results.push(ActualTrip {
                 vehicle,
                 variant,
-                trip,
+                trip: gtfs_trip.id,
                 stop_times,
+                visits,
+                uncertainty,
+                confidence,
+                unscheduled: false,
             });
         }
 
@@ -113,71 +303,115 @@ impl DailyModel {
     }
 
     // Look for all times the vehicle passes close to each stop. Then assemble those into trip
-    // sequences, forcing times to be in order.
+    // sequences, forcing times to be in order. Stops the trip missed are None.
     //
     // Errors seem to happen when the distance threshold is too low, and other cases not yet
     // understood. Simple validation is to look for huge times between stops (over an hour).
-    fn get_trip_times(&self, vehicle: VehicleID, variant: RouteVariantID) -> Vec<Vec<Time>> {
-        let trajectory = &self.vehicles[vehicle.0].trajectory;
-        let variant = self.gtfs.variant(variant);
-
-        let mut times_near_stops: Vec<Vec<Time>> = Vec::new();
-        let mut min_times = usize::MAX;
-        for stop in variant.stops() {
-            let stop_pos = self.gtfs.stops[&stop].pos;
-            let times: Vec<Time> = trajectory
-                .times_near_pos(stop_pos, BUS_TO_STOP_THRESHOLD)
-                .into_iter()
-                .map(|(t, _)| t)
-                .collect();
-            min_times = min_times.min(times.len());
-            times_near_stops.push(times);
+    fn get_trip_times(
+        &self,
+        vehicle: VehicleID,
+        variant: RouteVariantID,
+        projected: Option<&ProjectedTrajectory>,
+    ) -> Vec<Vec<Option<Time>>> {
+        let mut times_near_stops = match (self.matching.stop_passage, projected) {
+            (StopPassage::Directional, Some(projected)) => {
+                self.directional_stop_passages(projected, variant)
+            }
+            _ => None,
         }
+        .unwrap_or_else(|| self.times_near_stops(vehicle, variant));
+        let min_times = times_near_stops
+            .iter()
+            .map(|times| times.len())
+            .min()
+            .unwrap_or(0);
 
         // Assemble into trips
-        let mut trips: Vec<Vec<Time>> = Vec::new();
+        let mut trips: Vec<Vec<Option<Time>>> = Vec::new();
 
-        if false {
-            // The naive approach
+        if self.matching.trip_assembly == TripAssembly::Naive {
             for trip_idx in 0..min_times {
-                let times: Vec<Time> = times_near_stops
+                let times: Vec<Option<Time>> = times_near_stops
                     .iter()
-                    .map(|times| times[trip_idx])
+                    .map(|times| Some(times[trip_idx]))
                     .collect();
                 trips.push(times);
             }
         } else {
-            // Assume the first time at the first stop is correct, then build up from there and always
-            // require the time to increase. Skip some times if needed
-            let mut skipped = 0;
-            let mut last_time = Time::START_OF_DAY;
-            'OUTER: loop {
-                let mut trip_times = Vec::new();
-                for times in &mut times_near_stops {
+            // A trip may start a few stops in, so start from the earliest time at any of the
+            // first stops, then build up from there
+            let max_skipped = self.matching.max_skipped_stops;
+            let min_observed = times_near_stops.len().min(2);
+            let mut out_of_order = 0;
+            loop {
+                let start_idx = match times_near_stops
+                    .iter()
+                    .take(max_skipped + 1)
+                    .enumerate()
+                    .filter_map(|(idx, times)| times.first().map(|t| (*t, idx)))
+                    .min()
+                {
+                    Some((_, idx)) => idx,
+                    None => break,
+                };
+                let mut trip_times = vec![None; times_near_stops.len()];
+                let mut last_time = times_near_stops[start_idx].remove(0);
+                trip_times[start_idx] = Some(last_time);
+
+                for (idx, times) in times_near_stops.iter_mut().enumerate().skip(start_idx + 1) {
                     // Shift while the first time is too early
                     while !times.is_empty() && times[0] < last_time {
                         times.remove(0);
-                        skipped += 1;
+                        out_of_order += 1;
                     }
-                    if times.is_empty() {
-                        break 'OUTER;
+                    // If the next visit is much later, the vehicle skipped this stop, or
+                    // abandoned the trip. Leave the time for a later trip.
+                    if let Some(time) = times.first().cloned() {
+                        if time - last_time <= self.matching.max_stop_gap {
+                            times.remove(0);
+                            trip_times[idx] = Some(time);
+                            last_time = time;
+                        }
                     }
-                    last_time = times.remove(0);
-                    trip_times.push(last_time);
                 }
-                trips.push(trip_times);
+
+                let observed = trip_times.iter().flatten().count();
+                if trip_times.len() - observed <= max_skipped && observed >= min_observed {
+                    trips.push(trip_times);
+                }
             }
 
             if false {
                 println!(
                     "For below, skipped {} times at different stops because they're out-of-order",
-                    skipped
+                    out_of_order
                 );
             }
         }
 
         trips
     }
+
+    // For each stop of the variant, all times the vehicle passes within the threshold
+    fn times_near_stops(&self, vehicle: VehicleID, variant: RouteVariantID) -> Vec<Vec<Time>> {
+        let trajectory = &self.vehicles[vehicle.0].trajectory;
+        self.gtfs
+            .variant(variant)
+            .stops()
+            .into_iter()
+            .map(|stop| {
+                trajectory
+                    .times_near_pos(
+                        self.gtfs.stops[&stop].pos,
+                        self.matching.stop_threshold,
+                        self.matching.dedupe_passes,
+                    )
+                    .into_iter()
+                    .map(|(t, _)| t)
+                    .collect()
+            })
+            .collect()
+    }
 }
 
 pub struct ActualTrip {
@@ -186,18 +420,102 @@ pub struct ActualTrip {
     pub variant: RouteVariantID,
     pub trip: TripID,
 
-    pub stop_times: Vec<Time>,
+    /// Aligned with the variant's stops. None where the vehicle skipped a stop, including stops
+    /// cut off at the start or end of a short trip. At least one is present.
+    pub stop_times: Vec<Option<Time>>,
+    /// When the vehicle arrived at and departed each stop, aligned with `stop_times`. The vehicle
+    /// passed the stop at the time in `stop_times`, but may have stopped there a while before
+    /// or after.
+    pub visits: Vec<Option<(Time, Time)>>,
+    /// Roughly how far off each time in `stop_times` might be, when the vehicle passed the stop
+    /// between two sparse AVL points. None where the stop was skipped or the vehicle was off the
+    /// variant's shape.
+    pub uncertainty: Vec<Option<Duration>>,
+    /// The ticketing signal starts from all taps on the vehicle during the trip. Boarding events
+    /// refine it using only the taps matched to the trip.
+    pub confidence: Confidence,
+    /// No scheduled trip was left for this run, so `trip` is only the closest one. Set by
+    /// `assign_trips_globally`.
+    pub unscheduled: bool,
+}
+
+/// The result of `infer_vehicle_schedule`
+pub struct VehicleSchedule {
+    /// Non-overlapping, in order
+    pub trips: Vec<ActualTrip>,
+    pub rejected: Vec<RejectedTrip>,
+}
+
+/// A candidate trip that didn't make it into a vehicle's schedule
+pub struct RejectedTrip {
+    pub trip: ActualTrip,
+    pub reason: Rejection,
+}
+
+#[derive(Clone, Debug)]
+pub enum Rejection {
+    /// A greedy strategy already chose these overlapping trips
+    Overlaps(Vec<TripID>),
+    /// The overlapping trips chosen instead have at least as much total quality
+    Outweighed {
+        quality: f64,
+        chosen: Vec<TripID>,
+        chosen_quality: f64,
+    },
+    /// The trip has no quality at all, so it can only get in the way
+    Worthless,
+    /// Ticketing says the vehicle only served this variant during these times, and the trip
+    /// doesn't fit in any of them
+    OutsideWindows(Vec<(Time, Time)>),
+}
+
+impl RejectedTrip {
+    pub fn describe(&self) -> String {
+        let reason = match &self.reason {
+            Rejection::Overlaps(trips) => format!("overlaps {:?}, chosen first", trips),
+            Rejection::Outweighed {
+                quality,
+                chosen,
+                chosen_quality,
+            } => format!(
+                "quality {:.1} loses to overlapping {:?} with quality {:.1}",
+                quality, chosen, chosen_quality
+            ),
+            Rejection::Worthless => "quality is 0".to_string(),
+            Rejection::OutsideWindows(windows) => format!(
+                "outside the times this variant was served ({})",
+                windows
+                    .iter()
+                    .map(|(t1, t2)| format!("{t1} to {t2}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        format!("Skipping {}: {}", self.trip.summary(), reason)
+    }
 }
 
 impl ActualTrip {
     pub fn summary(&self) -> String {
+        let skipped = self.skipped_stops().len();
         format!(
-            "{:?} ({:?}) from {} to {} ({} total)",
+            "{}{:?} ({:?}) from {} to {} ({} total){}, confidence {}",
+            if self.unscheduled {
+                "unscheduled, near "
+            } else {
+                ""
+            },
             self.trip,
             self.variant,
-            self.stop_times[0],
-            self.stop_times.last().unwrap(),
-            *self.stop_times.last().unwrap() - self.stop_times[0]
+            self.start_time(),
+            self.end_time(),
+            self.end_time() - self.start_time(),
+            if skipped > 0 {
+                format!(", skipping {} stops", skipped)
+            } else {
+                String::new()
+            },
+            self.confidence
         )
     }
 
@@ -211,44 +529,140 @@ impl ActualTrip {
                 self.stop_times
                     .iter()
                     .enumerate()
-                    .map(|(idx, t)| format!("{} @ {}", idx + 1, t))
+                    .filter_map(|(idx, t)| t.map(|t| format!("{} @ {}", idx + 1, t)))
                     .collect::<Vec<_>>()
                     .join(", ")
             ));
 
             // Look for impossible bits, show the stops
             for (idx, pair) in self.stop_times.windows(2).enumerate() {
-                if pair[1] < pair[0] {
-                    out.push(format!(
-                        "  - Something funny near stop {} ({}) -> {} ({})",
-                        idx + 1,
-                        pair[0],
-                        idx + 2,
-                        pair[1]
-                    ));
+                if let [Some(t1), Some(t2)] = pair {
+                    if t2 < t1 {
+                        out.push(format!(
+                            "  - Something funny near stop {} ({}) -> {} ({})",
+                            idx + 1,
+                            t1,
+                            idx + 2,
+                            t2
+                        ));
+                    }
                 }
             }
         }
 
-        let mut last_time = self.stop_times[0];
-        for (idx, time) in self.stop_times.iter().enumerate() {
-            let time = *time;
-            out.push(format!(
-                "  Stop {}: {} ({})",
-                idx + 1,
-                time,
-                time - last_time
-            ));
-            last_time = time;
+        // Show the time travelling from the previous stop
+        let mut last_time = self.visits.iter().flatten().next().unwrap().0;
+        for (idx, visit) in self.visits.iter().enumerate() {
+            if let Some((arrival, departure)) = *visit {
+                out.push(format!(
+                    "  Stop {}: {}{} ({}){}",
+                    idx + 1,
+                    arrival,
+                    self.describe_uncertainty(idx),
+                    arrival - last_time,
+                    if departure > arrival {
+                        format!(", dwelled {}", departure - arrival)
+                    } else {
+                        String::new()
+                    }
+                ));
+                last_time = departure;
+            } else {
+                out.push(format!("  Stop {}: skipped", idx + 1));
+            }
         }
         out
     }
 
     pub fn start_time(&self) -> Time {
-        self.stop_times[0]
+        self.stop_times.iter().flatten().next().cloned().unwrap()
+    }
+
+    /// How much this trip is worth to a schedule: the stops it covers, weighted by confidence
+    pub fn quality(&self) -> f64 {
+        self.stop_times.iter().flatten().count() as f64 * self.confidence.score()
     }
 
     pub fn end_time(&self) -> Time {
-        *self.stop_times.last().unwrap()
+        self.stop_times
+            .iter()
+            .rev()
+            .flatten()
+            .next()
+            .cloned()
+            .unwrap()
+    }
+
+    /// Describes the uncertainty of one arrival, if it's worth mentioning
+    pub fn describe_uncertainty(&self, idx: usize) -> String {
+        match self.uncertainty[idx] {
+            Some(x) if x >= Duration::seconds(1.0) => format!(" ± {}", x),
+            _ => String::new(),
+        }
+    }
+
+    /// The indices of the variant's stops this trip didn't visit
+    pub fn skipped_stops(&self) -> Vec<usize> {
+        self.stop_times
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.is_none())
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{daily_model, gtfs, trajectory, RouteFixture};
+
+    #[test]
+    fn sparse_pings_cutting_a_corner() {
+        // The route turns a corner at stop 1
+        let gtfs = gtfs(
+            &[
+                Pt2D::new(0.0, 0.0),
+                Pt2D::new(500.0, 0.0),
+                Pt2D::new(500.0, 500.0),
+            ],
+            vec![RouteFixture {
+                short_name: Some("1"),
+                stops: vec![0, 1, 2],
+                start_time: Time::START_OF_DAY,
+            }],
+        );
+        // Moving at 10m/s along the route, but the straight line between the pings around the
+        // corner never comes within 100m of stop 1
+        let model = daily_model(
+            gtfs,
+            vec![trajectory(vec![
+                (0.0, 0.0, 0.0),
+                (300.0, 0.0, 30.0),
+                (500.0, 200.0, 70.0),
+                (500.0, 500.0, 100.0),
+            ])],
+        );
+
+        let trips = model.get_trips_for_vehicle_and_variant(VehicleID(0), RouteVariantID(0));
+        assert_eq!(trips.len(), 1);
+        let stop_times: Vec<f64> = trips[0]
+            .stop_times
+            .iter()
+            .map(|t| (t.unwrap() - Time::START_OF_DAY).inner_seconds().round())
+            .collect();
+        assert_eq!(stop_times, vec![0.0, 50.0, 100.0]);
+
+        // Stops 0 and 2 are right at pings. Stop 1 is halfway along the shape between two pings
+        // 40s apart, and covering the 400m between them takes at least 400m / 30m/s.
+        let uncertainty: Vec<f64> = trips[0]
+            .uncertainty
+            .iter()
+            .map(|x| x.unwrap().inner_seconds())
+            .collect();
+        assert_eq!(uncertainty[0], 0.0);
+        assert_eq!(uncertainty[2], 0.0);
+        let expected = (40.0 - 400.0 / 30.0) * 0.25;
+        assert!((uncertainty[1] - expected).abs() < 0.01);
     }
 }
@@ -1,84 +1,265 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
+use abstutil::Timer;
 use anyhow::Result;
 use geom::Time;
 
-use crate::{DailyModel, VehicleID, VehicleName};
+use crate::{DailyModel, SpatialCandidates, VehicleID, VehicleName};
 use gtfs::{DateFilter, RouteVariantID};
 
 // BIL data says somebody boarded a vehicle and rode route_short_name. Use that, along with the
-// variants actually served that day, to figure out possible route variants per vehicle.
+// variants actually served that day, to figure out possible route variants per vehicle. Some AVL
+// sources also give line and journey references, which are used the same way. Without any of
+// those, the trajectory itself is compared to every variant; see spatial_candidates.rs.
 
 impl DailyModel {
     // Expensive -- calculates a mapping for the full day, but only uses one vehicle
     pub fn vehicle_to_possible_routes(&self, id: VehicleID) -> Vec<RouteVariantID> {
-        match self.vehicles_to_possible_routes().unwrap().remove(&id) {
+        match self
+            .vehicles_to_possible_routes(&mut Timer::throwaway())
+            .unwrap()
+            .remove(&id)
+        {
             Some(list) => list,
             None => Vec::new(),
         }
     }
 
+    /// Everything ticketing and AVL hints say about one vehicle. Expensive -- calculates this for
+    /// the full day, but only uses one vehicle
+    pub fn vehicle_evidence(&self, id: VehicleID) -> VehicleEvidence {
+        match self
+            .evidence_per_vehicle(&mut Timer::throwaway())
+            .unwrap()
+            .remove(&id)
+        {
+            Some(x) => x,
+            None => VehicleEvidence::default(),
+        }
+    }
+
+    pub(crate) fn evidence_per_vehicle(
+        &self,
+        timer: &mut Timer,
+    ) -> Result<BTreeMap<VehicleID, VehicleEvidence>> {
+        let mut result: BTreeMap<VehicleID, VehicleEvidence> = BTreeMap::new();
+        for (vehicle, possible_variants) in self.vehicles_to_candidate_variants(timer)? {
+            result.entry(vehicle).or_default().possible_variants = possible_variants;
+        }
+        for journey in &self.journeys {
+            for leg in &journey.legs {
+                if let Ok(vehicle) = self.vehicle_ids.lookup(&leg.vehicle_name) {
+                    result
+                        .entry(vehicle)
+                        .or_default()
+                        .taps
+                        .push((leg.time, leg.route_short_name.clone()));
+                }
+            }
+        }
+        for evidence in result.values_mut() {
+            evidence.taps.sort();
+        }
+        Ok(result)
+    }
+
     pub(crate) fn vehicles_to_possible_routes(
         &self,
+        timer: &mut Timer,
     ) -> Result<BTreeMap<VehicleID, Vec<RouteVariantID>>> {
+        Ok(self
+            .vehicles_to_candidate_variants(timer)?
+            .into_iter()
+            .map(|(vehicle, candidates)| (vehicle, candidates.into_keys().collect()))
+            .collect())
+    }
+
+    // Per vehicle, the variants it might serve and the time windows when it might. A vehicle
+    // serving several routes through the day has one window per segment of its ticketing
+    // assignment. Each window stretches from the last tap of the previous segment to the first tap
+    // of the next, since the switch happens somewhere in between.
+    fn vehicles_to_candidate_variants(
+        &self,
+        timer: &mut Timer,
+    ) -> Result<BTreeMap<VehicleID, CandidateWindows>> {
         let services = self
             .gtfs
             .calendar
             .services_matching_dates(&DateFilter::SingleDay(self.date));
-        let mut result = BTreeMap::new();
-        for (vehicle, assignment) in self.vehicle_to_route_short_name()? {
-            // Start simple
-            if assignment.segments.len() != 1 {
-                continue;
-            }
-            // What variants match?
-            let mut variants = Vec::new();
-            for route in self.gtfs.routes.values() {
-                if route.short_name.as_ref() != Some(&assignment.segments[0].2) {
-                    continue;
-                }
+        let mut variants_per_route: BTreeMap<&str, Vec<RouteVariantID>> = BTreeMap::new();
+        for route in self.gtfs.routes.values() {
+            if let Some(ref name) = route.short_name {
                 for variant in &route.variants {
                     if services.contains(&variant.service_id) {
-                        variants.push(variant.variant_id);
+                        variants_per_route
+                            .entry(name.as_str())
+                            .or_insert_with(Vec::new)
+                            .push(variant.variant_id);
                     }
                 }
             }
-            if !variants.is_empty() {
-                result.insert(vehicle, variants);
+        }
+
+        let mut result: BTreeMap<VehicleID, CandidateWindows> = BTreeMap::new();
+        for (vehicle, assignment) in self.vehicle_to_route_short_name()? {
+            let trajectory = &self.vehicles[vehicle.0].trajectory;
+            for (t1, t2, route) in
+                assignment.windows(trajectory.start_time(), trajectory.end_time())
+            {
+                for variant in variants_per_route.get(route).into_iter().flatten() {
+                    result
+                        .entry(vehicle)
+                        .or_insert_with(BTreeMap::new)
+                        .entry(*variant)
+                        .or_insert_with(Vec::new)
+                        .push((t1, t2));
+                }
+            }
+        }
+
+        for (vehicle, hinted) in self.vehicles_to_hinted_routes() {
+            for (variant, windows) in hinted {
+                for window in windows {
+                    add_candidate(&mut result, vehicle, variant, window);
+                }
+            }
+        }
+
+        let need_spatial: Vec<VehicleID> = match self.matching.spatial_candidates {
+            SpatialCandidates::Never => Vec::new(),
+            SpatialCandidates::Fallback => self
+                .vehicles
+                .iter()
+                .map(|v| v.id)
+                .filter(|v| !result.contains_key(v))
+                .collect(),
+            SpatialCandidates::Always => self.vehicles.iter().map(|v| v.id).collect(),
+        };
+        if !need_spatial.is_empty() {
+            let mut found = 0;
+            for (vehicle, candidates) in self.spatial_candidates(need_spatial, timer) {
+                if !candidates.is_empty() {
+                    found += 1;
+                }
+                for (v, _) in candidates {
+                    self.add_all_day_candidate(&mut result, vehicle, v);
+                }
             }
+            info!("Found variants for {found} vehicles from AVL alone");
         }
+
         Ok(result)
     }
 
-    pub(crate) fn vehicle_to_route_short_name(&self) -> Result<BTreeMap<VehicleID, Assignment>> {
-        let mut vehicles: BTreeMap<VehicleID, Assignment> = BTreeMap::new();
-        for journey in &self.journeys {
-            for leg in &journey.legs {
-                // Ignore when BIL refers to vehicles we don't know from AVL
-                let vehicle = if let Ok(x) = self.vehicle_ids.lookup(&leg.vehicle_name) {
-                    x
+    fn add_all_day_candidate(
+        &self,
+        result: &mut BTreeMap<VehicleID, CandidateWindows>,
+        vehicle: VehicleID,
+        variant: RouteVariantID,
+    ) {
+        let trajectory = &self.vehicles[vehicle.0].trajectory;
+        let all_day = (trajectory.start_time(), trajectory.end_time());
+        add_candidate(result, vehicle, variant, all_day);
+    }
+
+    // Use the DatedVehicleJourneyRef and LineRef from AVL sources like SIRI-VM. A journey ref
+    // usually names a GTFS trip directly. A line ref could be the route ID or short name, so
+    // check both. Like ticketing, each hint's window stretches from the end of the previous hint
+    // to the start of the next, since the switch happens somewhere in between.
+    fn vehicles_to_hinted_routes(&self) -> BTreeMap<VehicleID, CandidateWindows> {
+        let mut result = BTreeMap::new();
+        if self.vehicles.iter().all(|v| v.route_hints.is_empty()) {
+            return result;
+        }
+
+        let services = self
+            .gtfs
+            .calendar
+            .services_matching_dates(&DateFilter::SingleDay(self.date));
+        let mut variants_per_trip: BTreeMap<&str, RouteVariantID> = BTreeMap::new();
+        let mut variants_per_line: BTreeMap<&str, BTreeSet<RouteVariantID>> = BTreeMap::new();
+        for route in self.gtfs.routes.values() {
+            for variant in &route.variants {
+                if !services.contains(&variant.service_id) {
+                    continue;
+                }
+                for trip in &variant.trips {
+                    variants_per_trip.insert(trip.orig_id.as_str(), variant.variant_id);
+                }
+                for line in std::iter::once(route.route_id.as_str())
+                    .chain(route.short_name.as_ref().map(|x| x.as_str()))
+                {
+                    variants_per_line
+                        .entry(line)
+                        .or_insert_with(BTreeSet::new)
+                        .insert(variant.variant_id);
+                }
+            }
+        }
+
+        for vehicle in &self.vehicles {
+            let hints = &vehicle.route_hints;
+            for (idx, hint) in hints.iter().enumerate() {
+                let variants: BTreeSet<RouteVariantID> = if let Some(v) = hint
+                    .journey_ref
+                    .as_ref()
+                    .and_then(|x| variants_per_trip.get(x.as_str()))
+                {
+                    std::iter::once(*v).collect()
+                } else if let Some(list) = hint
+                    .line_ref
+                    .as_ref()
+                    .and_then(|x| variants_per_line.get(x.as_str()))
+                {
+                    list.clone()
                 } else {
                     continue;
                 };
-
-                // Somebody boarded a particular vehicle at some time, and the record claims that
-                // vehicle was serving a particular route
-                vehicles
-                    .entry(vehicle)
-                    .or_insert_with(Assignment::new)
-                    .update(leg.time, &leg.route_short_name);
+                let window = (
+                    match idx.checked_sub(1) {
+                        Some(prev) => hints[prev].end_time,
+                        None => vehicle.trajectory.start_time(),
+                    },
+                    match hints.get(idx + 1) {
+                        Some(next) => next.start_time,
+                        None => vehicle.trajectory.end_time(),
+                    },
+                );
+                for variant in variants {
+                    add_candidate(&mut result, vehicle.id, variant, window);
+                }
             }
         }
+        result
+    }
 
-        for assignment in vehicles.values_mut() {
-            assignment.segments.sort_by_key(|(t1, _, _)| *t1);
+    pub(crate) fn vehicle_to_route_short_name(&self) -> Result<BTreeMap<VehicleID, Assignment>> {
+        // Somebody boarded a particular vehicle at some time, and the record claims that vehicle
+        // was serving a particular route
+        let mut taps_per_vehicle: BTreeMap<VehicleID, Vec<(Time, &str)>> = BTreeMap::new();
+        for journey in &self.journeys {
+            for leg in &journey.legs {
+                // Ignore when BIL refers to vehicles we don't know from AVL
+                if let Ok(vehicle) = self.vehicle_ids.lookup(&leg.vehicle_name) {
+                    taps_per_vehicle
+                        .entry(vehicle)
+                        .or_insert_with(Vec::new)
+                        .push((leg.time, &leg.route_short_name));
+                }
+            }
         }
+        let vehicles: BTreeMap<VehicleID, Assignment> = taps_per_vehicle
+            .into_iter()
+            .map(|(vehicle, mut taps)| {
+                taps.sort();
+                (vehicle, Assignment::from_taps(taps))
+            })
+            .collect();
 
         // Just print some stats
         if false {
             let mut one_route = 0;
-            let mut multiple_routes_normal = 0;
-            let mut multiple_routes_overlapping = 0;
+            let mut multiple_routes = 0;
             for (vehicle, assignment) in &vehicles {
                 // Most serve 1 route; that's the simple case for matching
                 if assignment.segments.len() == 1 {
@@ -86,26 +267,21 @@ impl DailyModel {
                     continue;
                 }
 
-                if !assignment.has_overlaps() {
-                    multiple_routes_normal += 1;
-                    continue;
-                }
-
-                multiple_routes_overlapping += 1;
+                multiple_routes += 1;
                 println!(
-                    "{:?} serves {} routes, partly overlapping",
+                    "{:?} switches routes {} times",
                     vehicle,
-                    assignment.segments.len()
+                    assignment.segments.len() - 1
                 );
                 for (t1, t2, route) in &assignment.segments {
                     println!("  - from {t1} to {t2}: {route}");
                 }
             }
 
-            println!("{one_route} vehicles serve 1 route, {multiple_routes_normal} serve multiple normally, {multiple_routes_overlapping} have weird overlaps");
+            println!("{one_route} vehicles serve 1 route, {multiple_routes} serve multiple");
         }
 
-        // Manually debug vehicles assigned to multiple routes with apparent overlap
+        // Manually debug vehicles assigned to multiple routes
         // To understand best, then do `sort -n vehicle_assignment.csv`
         if false && cfg!(not(target_arch = "wasm32")) {
             use std::fs::File;
@@ -128,55 +304,187 @@ impl DailyModel {
     }
 }
 
-// TODO Move this to experiments, and simplify the above implementations, which just look for all
-// possible variants and ignore time / possible overlaps entirely.
+pub type CandidateWindows = BTreeMap<RouteVariantID, Vec<(Time, Time)>>;
+
+fn add_candidate(
+    result: &mut BTreeMap<VehicleID, CandidateWindows>,
+    vehicle: VehicleID,
+    variant: RouteVariantID,
+    window: (Time, Time),
+) {
+    let windows = result
+        .entry(vehicle)
+        .or_insert_with(BTreeMap::new)
+        .entry(variant)
+        .or_insert_with(Vec::new);
+    if !windows.contains(&window) {
+        windows.push(window);
+    }
+}
+
+/// What ticketing and AVL hints say about one vehicle, used to infer its schedule
+#[derive(Clone, Default)]
+pub struct VehicleEvidence {
+    /// Variants the vehicle might serve, with the time windows when it might. Trips outside all
+    /// windows are ruled out.
+    pub possible_variants: CandidateWindows,
+    /// Every ticketing event on this vehicle, as (time, route short name), sorted by time
+    pub taps: Vec<(Time, String)>,
+}
+
+impl VehicleEvidence {
+    /// Of the ticketing events during some time, what fraction name this route? None if there
+    /// are none.
+    pub fn ticketing_agreement(&self, t1: Time, t2: Time, route_short_name: &str) -> Option<f64> {
+        let start = self.taps.partition_point(|(t, _)| *t < t1);
+        let taps = &self.taps[start..];
+        let end = taps.partition_point(|(t, _)| *t <= t2);
+        let taps = &taps[..end];
+        if taps.is_empty() {
+            return None;
+        }
+        let agree = taps
+            .iter()
+            .filter(|(_, name)| name == route_short_name)
+            .count();
+        Some(agree as f64 / taps.len() as f64)
+    }
+}
 
 // If a vehicle serves multiple routes over the day, try to figure out when they switch.
-//
-// Current implementation doesn't handle a sequence like (route1, route2, route1). Could improve it
-// by first sorting by time all ticketing events per vehicle, then processing those in order.
 pub(crate) struct Assignment {
-    // (time1, time2, route short name)
-    // Ordered by time1
+    // (first tap, last tap, route short name)
+    // Ordered and non-overlapping. The same route may appear in several segments, like (route1,
+    // route2, route1).
     pub(crate) segments: Vec<(Time, Time, String)>,
 }
 
 impl Assignment {
-    fn new() -> Self {
-        Self {
-            segments: Vec::new(),
+    // Taps must be sorted by time
+    fn from_taps(taps: Vec<(Time, &str)>) -> Self {
+        // Group consecutive taps naming the same route, counting them
+        let mut groups: Vec<(Time, Time, &str, usize)> = Vec::new();
+        for (time, route) in taps {
+            match groups.last_mut() {
+                Some(last) if last.2 == route => {
+                    last.1 = time;
+                    last.3 += 1;
+                }
+                _ => {
+                    groups.push((time, time, route, 1));
+                }
+            }
         }
-    }
 
-    fn update(&mut self, time: Time, route: &str) {
-        // Do we already serve this route?
-        for (t1, t2, route_short_name) in &mut self.segments {
-            if route != route_short_name {
+        // A lone tap naming a different route in the middle of a segment is probably somebody
+        // tapping late after the vehicle switched routes, or a mistake. Skip it, so the segment
+        // isn't split. Around a real switch, a late tap and the first tap on the new route both
+        // look like this and are both skipped, leaving the two segments.
+        let mut segments: Vec<(Time, Time, String)> = Vec::new();
+        for (idx, (t1, t2, route, count)) in groups.iter().enumerate() {
+            if *count == 1
+                && idx > 0
+                && idx + 1 < groups.len()
+                && groups[idx - 1].2 == groups[idx + 1].2
+            {
                 continue;
             }
-            // Extend the time interval if needed
-            if time < *t1 {
-                *t1 = time;
-            } else if time > *t2 {
-                *t2 = time;
+            match segments.last_mut() {
+                Some(last) if last.2 == *route => {
+                    last.1 = *t2;
+                }
+                _ => {
+                    segments.push((*t1, *t2, route.to_string()));
+                }
             }
-            return;
         }
+        Self { segments }
+    }
 
-        // This vehicle is serving another route. Start an empty interval for it.
-        self.segments.push((time, time, route.to_string()));
+    // When could the vehicle be serving each segment's route? A segment's window stretches from
+    // the previous segment's last tap to the next segment's first tap. The first and last windows
+    // extend to the start and end of the vehicle's day.
+    fn windows(&self, start: Time, end: Time) -> Vec<(Time, Time, &str)> {
+        let mut windows = Vec::new();
+        for (idx, (_, _, route)) in self.segments.iter().enumerate() {
+            let t1 = if idx == 0 {
+                start
+            } else {
+                self.segments[idx - 1].1
+            };
+            let t2 = match self.segments.get(idx + 1) {
+                Some(next) => next.0,
+                None => end,
+            };
+            windows.push((t1, t2, route.as_str()));
+        }
+        windows
     }
+}
 
-    // Checks for unusual situations worth debugging further. Assumes segments have been sorted.
-    fn has_overlaps(&self) -> bool {
-        for pair in self.segments.windows(2) {
-            let (_t1, t2, _) = pair[0];
-            let (t3, _t4, _) = pair[1];
+#[cfg(test)]
+mod tests {
+    use geom::{Duration, Pt2D};
 
-            if t3 < t2 {
-                return true;
-            }
+    use super::*;
+    use crate::fixtures::{daily_model, gtfs, trajectory, RouteFixture};
+    use crate::RouteHint;
+
+    fn time(hours: usize, minutes: usize) -> Time {
+        Time::START_OF_DAY + Duration::hours(hours) + Duration::minutes(minutes)
+    }
+
+    fn hint(
+        start: Time,
+        end: Time,
+        line_ref: Option<&str>,
+        journey_ref: Option<&str>,
+    ) -> RouteHint {
+        RouteHint {
+            start_time: start,
+            end_time: end,
+            line_ref: line_ref.map(|x| x.to_string()),
+            journey_ref: journey_ref.map(|x| x.to_string()),
         }
-        false
+    }
+
+    #[test]
+    fn hinted_routes() {
+        let route = |short_name| RouteFixture {
+            short_name,
+            stops: vec![0, 1],
+            start_time: time(8, 0),
+        };
+        let gtfs = gtfs(
+            &[Pt2D::new(0.0, 0.0), Pt2D::new(100.0, 0.0)],
+            vec![route(Some("7")), route(None)],
+        );
+        let mut model = daily_model(
+            gtfs,
+            vec![trajectory(vec![
+                (0.0, 0.0, 7.0 * 3600.0),
+                (100.0, 0.0, 10.0 * 3600.0),
+            ])],
+        );
+        model.vehicles[0].route_hints = vec![
+            // By route ID, for a route without a short name
+            hint(time(8, 0), time(8, 10), Some("route1"), None),
+            // By trip ID, which wins over the line
+            hint(time(8, 20), time(8, 30), Some("route1"), Some("trip0")),
+            // By short name
+            hint(time(8, 40), time(8, 50), Some("7"), None),
+            // Unknown
+            hint(time(9, 0), time(9, 10), Some("99"), Some("trip99")),
+        ];
+
+        let mut expected = CandidateWindows::new();
+        expected.insert(RouteVariantID(1), vec![(time(7, 0), time(8, 20))]);
+        expected.insert(
+            RouteVariantID(0),
+            vec![(time(8, 10), time(8, 40)), (time(8, 30), time(9, 0))],
+        );
+        let mut result = model.vehicles_to_hinted_routes();
+        assert_eq!(result.remove(&VehicleID(0)), Some(expected));
+        assert!(result.is_empty());
     }
 }
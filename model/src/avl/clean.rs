@@ -0,0 +1,403 @@
+use std::collections::BTreeMap;
+
+use geom::{Distance, Duration, GPSBounds, LonLat, Pt2D, Speed, Time};
+use serde::{Deserialize, Serialize};
+
+use crate::{Trajectory, VehicleName};
+
+// Raw AVL has teleports, fixes at (0, 0), repeated timestamps, and jitter while stopped. Clean up
+// each vehicle's points before building a Trajectory.
+
+/// Controls how raw AVL points are cleaned
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CleaningConfig {
+    /// Points arriving out of order by up to this much are sorted back into place. Anything
+    /// further behind is dropped.
+    pub max_reorder: Duration,
+    /// Points further than this outside the GTFS bounding box are dropped
+    pub bounds_buffer: Distance,
+    /// Points implying the vehicle moved faster than this from the previous kept point are
+    /// dropped
+    pub max_speed: Speed,
+    /// If every point is rejected for this long after the last kept point, assume the vehicle
+    /// really did relocate (maybe the device was off) and start trusting points again
+    pub jump_recovery_time: Duration,
+    /// Optionally smooth positions
+    pub smoothing: Option<KalmanConfig>,
+}
+
+impl Default for CleaningConfig {
+    fn default() -> Self {
+        Self {
+            max_reorder: Duration::seconds(30.0),
+            bounds_buffer: Distance::meters(5000.0),
+            max_speed: Speed::km_per_hour(120.0),
+            jump_recovery_time: Duration::minutes(5),
+            smoothing: None,
+        }
+    }
+}
+
+/// A constant velocity Kalman filter, run independently on each axis
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KalmanConfig {
+    /// Standard deviation of the GPS error
+    pub measurement_noise: Distance,
+    /// Standard deviation of acceleration, in m/s^2
+    pub acceleration_noise: f64,
+}
+
+/// What happened to one vehicle's raw points
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct CleaningStats {
+    pub raw_points: usize,
+    pub reordered: usize,
+    pub dropped_out_of_order: usize,
+    pub merged_duplicates: usize,
+    pub dropped_out_of_bounds: usize,
+    pub dropped_impossible_speed: usize,
+    pub smoothed: bool,
+    pub final_points: usize,
+}
+
+impl CleaningStats {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} raw points -> {}. {} reordered, {} dropped out-of-order, {} duplicate timestamps merged, {} out of bounds, {} impossible speed{}",
+            self.raw_points,
+            self.final_points,
+            self.reordered,
+            self.dropped_out_of_order,
+            self.merged_duplicates,
+            self.dropped_out_of_bounds,
+            self.dropped_impossible_speed,
+            if self.smoothed { ", then smoothed" } else { "" }
+        )
+    }
+}
+
+/// Takes raw points per vehicle, in the order they appear in the input. Vehicles left with fewer
+/// than two points are skipped.
+pub fn build_trajectories(
+    raw: BTreeMap<VehicleName, Vec<(LonLat, Time)>>,
+    gps_bounds: &GPSBounds,
+    config: &CleaningConfig,
+) -> BTreeMap<VehicleName, (Trajectory, CleaningStats)> {
+    let mut results = BTreeMap::new();
+    for (vehicle_name, raw_pts) in raw {
+        let (points, stats) = clean(raw_pts, gps_bounds, config);
+        match Trajectory::new(points) {
+            Ok(trajectory) => {
+                results.insert(vehicle_name, (trajectory, stats));
+            }
+            Err(err) => {
+                warn!(
+                    "Skipping {:?} after cleaning AVL ({}): {}",
+                    vehicle_name,
+                    stats.describe(),
+                    err
+                );
+            }
+        }
+    }
+    results
+}
+
+fn clean(
+    raw_pts: Vec<(LonLat, Time)>,
+    gps_bounds: &GPSBounds,
+    config: &CleaningConfig,
+) -> (Vec<(Pt2D, Time)>, CleaningStats) {
+    let mut stats = CleaningStats {
+        raw_points: raw_pts.len(),
+        ..Default::default()
+    };
+
+    // Drop fixes outside the area of interest first, so a junk timestamp on one of them can't
+    // make later points look out of order. (0, 0) is a common way for devices to report no fix
+    // at all.
+    let bounds = gps_bounds.to_bounds();
+    let buffer = config.bounds_buffer.inner_meters();
+    let mut in_bounds = Vec::new();
+    for (gps, time) in raw_pts {
+        let pt = gps.to_pt(gps_bounds);
+        if (gps.x() == 0.0 && gps.y() == 0.0)
+            || !gps.x().is_finite()
+            || !gps.y().is_finite()
+            || pt.x() < bounds.min_x - buffer
+            || pt.x() > bounds.max_x + buffer
+            || pt.y() < bounds.min_y - buffer
+            || pt.y() > bounds.max_y + buffer
+        {
+            stats.dropped_out_of_bounds += 1;
+            continue;
+        }
+        in_bounds.push((pt, time));
+    }
+
+    // Reorder near-simultaneous points, and drop anything much older than what we've seen
+    let mut latest = Time::START_OF_DAY;
+    let mut pts = Vec::new();
+    for (pt, time) in in_bounds {
+        if time < latest {
+            if latest - time > config.max_reorder {
+                stats.dropped_out_of_order += 1;
+                continue;
+            }
+            stats.reordered += 1;
+        }
+        latest = latest.max(time);
+        pts.push((pt, time));
+    }
+    // Stable, so duplicate timestamps stay in input order
+    pts.sort_by_key(|(_, t)| *t);
+
+    // Merge points with the same timestamp into their centroid
+    let mut merged: Vec<(Pt2D, Time)> = Vec::new();
+    let mut group: Vec<Pt2D> = Vec::new();
+    for (pt, time) in pts {
+        if merged.last().map(|(_, t)| *t == time).unwrap_or(false) {
+            group.push(pt);
+            stats.merged_duplicates += 1;
+            merged.last_mut().unwrap().0 = Pt2D::center(&group);
+        } else {
+            group = vec![pt];
+            merged.push((pt, time));
+        }
+    }
+
+    // Drop points implying impossible speeds from the last good point. If the very first point
+    // were bad, that would drop everything until jump_recovery_time, so start from the first two
+    // points consistent with each other.
+    let plausible = |(pt1, t1): &(Pt2D, Time), (pt2, t2): &(Pt2D, Time)| {
+        Speed::from_dist_time(pt1.dist_to(*pt2), *t2 - *t1) <= config.max_speed
+    };
+    let start = merged
+        .windows(2)
+        .position(|pair| plausible(&pair[0], &pair[1]))
+        .unwrap_or(0);
+    stats.dropped_impossible_speed += start;
+    let mut result: Vec<(Pt2D, Time)> = Vec::new();
+    for (pt, time) in merged.into_iter().skip(start) {
+        if let Some(last) = result.last() {
+            if !plausible(last, &(pt, time)) && time - last.1 < config.jump_recovery_time {
+                stats.dropped_impossible_speed += 1;
+                continue;
+            }
+        }
+        result.push((pt, time));
+    }
+
+    if let Some(ref kalman) = config.smoothing {
+        smooth(&mut result, kalman);
+        stats.smoothed = true;
+    }
+
+    stats.final_points = result.len();
+    (result, stats)
+}
+
+// Assumes times are strictly increasing
+fn smooth(pts: &mut [(Pt2D, Time)], config: &KalmanConfig) {
+    if pts.is_empty() {
+        return;
+    }
+    let r = config.measurement_noise.inner_meters().powi(2);
+    let q = config.acceleration_noise.powi(2);
+
+    let mut x_axis = AxisFilter::new(pts[0].0.x(), r);
+    let mut y_axis = AxisFilter::new(pts[0].0.y(), r);
+    let mut last_time = pts[0].1;
+    for (pt, time) in pts.iter_mut().skip(1) {
+        let dt = (*time - last_time).inner_seconds();
+        last_time = *time;
+        let x = x_axis.step(pt.x(), dt, q, r);
+        let y = y_axis.step(pt.y(), dt, q, r);
+        *pt = Pt2D::new(x, y);
+    }
+}
+
+// State is position and velocity, with a 2x2 covariance matrix
+struct AxisFilter {
+    pos: f64,
+    vel: f64,
+    p00: f64,
+    p01: f64,
+    p10: f64,
+    p11: f64,
+}
+
+impl AxisFilter {
+    fn new(pos: f64, r: f64) -> Self {
+        Self {
+            pos,
+            vel: 0.0,
+            p00: r,
+            p01: 0.0,
+            p10: 0.0,
+            // We know nothing about the initial velocity
+            p11: 100.0,
+        }
+    }
+
+    // Returns the filtered position
+    fn step(&mut self, measurement: f64, dt: f64, q: f64, r: f64) -> f64 {
+        // Predict
+        self.pos += self.vel * dt;
+        let p00 = self.p00 + dt * (self.p10 + self.p01) + dt * dt * self.p11 + q * dt.powi(4) / 4.0;
+        let p01 = self.p01 + dt * self.p11 + q * dt.powi(3) / 2.0;
+        let p10 = self.p10 + dt * self.p11 + q * dt.powi(3) / 2.0;
+        let p11 = self.p11 + q * dt * dt;
+
+        // Update
+        let s = p00 + r;
+        let k0 = p00 / s;
+        let k1 = p10 / s;
+        let residual = measurement - self.pos;
+        self.pos += k0 * residual;
+        self.vel += k1 * residual;
+        self.p00 = (1.0 - k0) * p00;
+        self.p01 = (1.0 - k0) * p01;
+        self.p10 = p10 - k1 * p00;
+        self.p11 = p11 - k1 * p01;
+
+        self.pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Roughly 111m of latitude
+    const STEP: f64 = 0.001;
+
+    fn gps_bounds() -> GPSBounds {
+        let mut gps_bounds = GPSBounds::new();
+        gps_bounds.update(LonLat::new(10.0, 50.0));
+        gps_bounds.update(LonLat::new(10.1, 50.1));
+        gps_bounds
+    }
+
+    // Heading north along a street, `steps` apart
+    fn pt(steps: f64) -> LonLat {
+        LonLat::new(10.05, 50.01 + steps * STEP)
+    }
+
+    fn time(secs: f64) -> Time {
+        Time::START_OF_DAY + Duration::hours(8) + Duration::seconds(secs)
+    }
+
+    fn times(pts: &[(Pt2D, Time)]) -> Vec<Time> {
+        pts.iter().map(|(_, t)| *t).collect()
+    }
+
+    fn times_of(raw: &[(LonLat, Time)]) -> Vec<Time> {
+        raw.iter().map(|(_, t)| *t).collect()
+    }
+
+    #[test]
+    fn reordering() {
+        let (pts, stats) = clean(
+            vec![
+                (pt(0.0), time(0.0)),
+                (pt(2.0), time(20.0)),
+                // Slightly late
+                (pt(1.0), time(10.0)),
+                (pt(3.0), time(100.0)),
+                // Much too late
+                (pt(0.5), time(5.0)),
+            ],
+            &gps_bounds(),
+            &CleaningConfig::default(),
+        );
+        assert_eq!(
+            times(&pts),
+            vec![time(0.0), time(10.0), time(20.0), time(100.0)]
+        );
+        assert_eq!(stats.reordered, 1);
+        assert_eq!(stats.dropped_out_of_order, 1);
+    }
+
+    #[test]
+    fn merging() {
+        let gps_bounds = gps_bounds();
+        let (pts, stats) = clean(
+            vec![
+                (pt(0.0), time(0.0)),
+                (pt(1.0), time(10.0)),
+                (LonLat::new(10.0502, 50.011), time(10.0)),
+                (pt(2.0), time(20.0)),
+            ],
+            &gps_bounds,
+            &CleaningConfig::default(),
+        );
+        assert_eq!(times(&pts), vec![time(0.0), time(10.0), time(20.0)]);
+        assert_eq!(
+            pts[1].0,
+            Pt2D::center(&[
+                pt(1.0).to_pt(&gps_bounds),
+                LonLat::new(10.0502, 50.011).to_pt(&gps_bounds)
+            ])
+        );
+        assert_eq!(stats.merged_duplicates, 1);
+    }
+
+    #[test]
+    fn jumps() {
+        let good: Vec<(LonLat, Time)> = (1..=6)
+            .map(|i| (pt(i as f64), time(10.0 * i as f64)))
+            .collect();
+
+        // A bad first fix, kilometers away
+        let mut raw = vec![(pt(50.0), time(0.0))];
+        raw.extend(good.clone());
+        let (pts, stats) = clean(raw, &gps_bounds(), &CleaningConfig::default());
+        assert_eq!(times(&pts), times_of(&good));
+        assert_eq!(stats.dropped_impossible_speed, 1);
+
+        // A teleport partway through
+        let mut raw = good.clone();
+        raw.insert(3, (pt(50.0), time(35.0)));
+        let (pts, stats) = clean(raw, &gps_bounds(), &CleaningConfig::default());
+        assert_eq!(times(&pts), times_of(&good));
+        assert_eq!(stats.dropped_impossible_speed, 1);
+
+        // Points elsewhere for long enough are trusted again
+        let config = CleaningConfig {
+            jump_recovery_time: Duration::minutes(1),
+            ..Default::default()
+        };
+        let mut raw = good.clone();
+        let relocated: Vec<(LonLat, Time)> = (0..6)
+            .map(|i| (pt(50.0 + i as f64), time(80.0 + 10.0 * i as f64)))
+            .collect();
+        raw.extend(relocated.clone());
+        let (pts, stats) = clean(raw, &gps_bounds(), &config);
+        let mut expected = times_of(&good);
+        expected.extend(times_of(&relocated[4..]));
+        assert_eq!(times(&pts), expected);
+        assert_eq!(stats.dropped_impossible_speed, 4);
+    }
+
+    #[test]
+    fn bounds() {
+        let (pts, stats) = clean(
+            vec![
+                // No fix, with a junk timestamp far in the future
+                (LonLat::new(0.0, 0.0), time(3600.0)),
+                (pt(0.0), time(0.0)),
+                // Far outside the GTFS
+                (LonLat::new(11.0, 50.05), time(5.0)),
+                (pt(1.0), time(10.0)),
+                (pt(2.0), time(20.0)),
+            ],
+            &gps_bounds(),
+            &CleaningConfig::default(),
+        );
+        assert_eq!(times(&pts), vec![time(0.0), time(10.0), time(20.0)]);
+        assert_eq!(stats.dropped_out_of_bounds, 2);
+        assert_eq!(stats.dropped_out_of_order, 0);
+    }
+}
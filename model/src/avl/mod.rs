@@ -2,16 +2,33 @@ use std::collections::BTreeMap;
 
 use anyhow::Result;
 use chrono::{NaiveDate, NaiveDateTime, Timelike};
-use geom::{Duration, GPSBounds, LonLat, Pt2D, Time};
-use serde::Deserialize;
+use geom::{Duration, GPSBounds, LonLat, Time};
+use serde::{Deserialize, Serialize};
 
+pub use self::clean::{CleaningConfig, CleaningStats, KalmanConfig};
 use crate::{Trajectory, VehicleName};
 
+mod clean;
+pub mod siri;
+
+/// Some AVL sources also report what the vehicle claims to be serving. The CSV input doesn't, but
+/// SIRI-VM does. These are just hints for matching vehicles to routes; they may be wrong.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RouteHint {
+    pub start_time: Time,
+    pub end_time: Time,
+    /// Usually a GTFS route_id or route_short_name
+    pub line_ref: Option<String>,
+    /// Usually a GTFS trip_id
+    pub journey_ref: Option<String>,
+}
+
 pub fn load_trajectories<R: std::io::Read>(
     reader: R,
     gps_bounds: &GPSBounds,
     expected_date: NaiveDate,
-) -> Result<BTreeMap<VehicleName, Trajectory>> {
+    cleaning: &CleaningConfig,
+) -> Result<BTreeMap<VehicleName, (Trajectory, CleaningStats)>> {
     // Read raw data
     let mut data_per_vehicle: BTreeMap<VehicleName, Vec<(LonLat, Time)>> = BTreeMap::new();
     for rec in csv::Reader::from_reader(reader).deserialize() {
@@ -40,16 +57,11 @@ pub fn load_trajectories<R: std::io::Read>(
             .push((pos, time));
     }
 
-    // Calculate trajectories
-    let mut results = BTreeMap::new();
-    for (vehicle_name, raw_pts) in data_per_vehicle {
-        let mut points: Vec<(Pt2D, Time)> = Vec::new();
-        for (gps, time) in raw_pts {
-            points.push((gps.to_pt(&gps_bounds), time));
-        }
-        results.insert(vehicle_name, Trajectory::new(points)?);
-    }
-    Ok(results)
+    Ok(clean::build_trajectories(
+        data_per_vehicle,
+        gps_bounds,
+        cleaning,
+    ))
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Timelike};
+use geom::{Duration, GPSBounds, LonLat, Time};
+use roxmltree::Node;
+
+use super::{clean, CleaningConfig, CleaningStats, RouteHint};
+use crate::{Trajectory, VehicleName};
+
+// Some operators deliver AVL as SIRI Vehicle Monitoring (SIRI-VM) XML dumps instead of CSV. Each
+// VehicleActivity is one sample of a vehicle's position. Besides the trajectories, this returns
+// the line and journey each vehicle claims to be serving over time, as hints for matching.
+pub fn load<R: std::io::Read>(
+    mut reader: R,
+    gps_bounds: &GPSBounds,
+    expected_date: NaiveDate,
+    cleaning: &CleaningConfig,
+) -> Result<(
+    BTreeMap<VehicleName, (Trajectory, CleaningStats)>,
+    BTreeMap<VehicleName, Vec<RouteHint>>,
+)> {
+    let mut xml = String::new();
+    reader.read_to_string(&mut xml)?;
+    let doc = roxmltree::Document::parse(&xml)?;
+
+    // Read raw data
+    let mut data_per_vehicle: BTreeMap<VehicleName, Vec<Sample>> = BTreeMap::new();
+    for activity in doc
+        .descendants()
+        .filter(|n| n.tag_name().name() == "VehicleActivity")
+    {
+        let journey = if let Some(x) = child(activity, "MonitoredVehicleJourney") {
+            x
+        } else {
+            warn!("Skipping a VehicleActivity without a MonitoredVehicleJourney");
+            continue;
+        };
+        let vehicle_name = if let Some(x) = text_of(journey, "VehicleRef") {
+            VehicleName(x.to_string())
+        } else {
+            warn!("Skipping a VehicleActivity without a VehicleRef");
+            continue;
+        };
+
+        let recorded_at = text_of(activity, "RecordedAtTime").ok_or_else(|| {
+            anyhow!(
+                "{:?} has a VehicleActivity without a RecordedAtTime",
+                vehicle_name
+            )
+        })?;
+        // Use the local time of the producer, like the CSV format
+        let datetime = DateTime::parse_from_rfc3339(recorded_at)?.naive_local();
+        if datetime.date() != expected_date {
+            bail!(
+                "A SIRI-VM file for {} contains a record for {}",
+                expected_date,
+                datetime
+            );
+        }
+
+        let time = datetime.time();
+        let time = Time::START_OF_DAY
+            + Duration::hours(time.hour() as usize)
+            + Duration::minutes(time.minute() as usize)
+            + Duration::seconds(time.second() as f64);
+
+        let location = child(journey, "VehicleLocation").ok_or_else(|| {
+            anyhow!(
+                "{:?} has a VehicleActivity without a VehicleLocation",
+                vehicle_name
+            )
+        })?;
+        let longitude = text_of(location, "Longitude")
+            .ok_or_else(|| anyhow!("VehicleLocation missing Longitude"))?
+            .parse::<f64>()?;
+        let latitude = text_of(location, "Latitude")
+            .ok_or_else(|| anyhow!("VehicleLocation missing Latitude"))?
+            .parse::<f64>()?;
+
+        data_per_vehicle
+            .entry(vehicle_name)
+            .or_insert_with(Vec::new)
+            .push(Sample {
+                pos: LonLat::new(longitude, latitude),
+                time,
+                line_ref: text_of(journey, "LineRef").map(|x| x.to_string()),
+                // Either directly in the journey (SIRI 1.x) or inside FramedVehicleJourneyRef
+                journey_ref: text_of(journey, "DatedVehicleJourneyRef").map(|x| x.to_string()),
+            });
+    }
+
+    // Calculate trajectories and hints
+    let mut raw_points = BTreeMap::new();
+    let mut hints = BTreeMap::new();
+    for (vehicle_name, mut samples) in data_per_vehicle {
+        // Deliveries aren't necessarily in order. The same activity is often repeated across
+        // deliveries; cleaning merges those.
+        samples.sort_by_key(|s| s.time);
+
+        let mut points = Vec::new();
+        let mut route_hints: Vec<RouteHint> = Vec::new();
+        for sample in samples {
+            points.push((sample.pos, sample.time));
+            if sample.line_ref.is_none() && sample.journey_ref.is_none() {
+                continue;
+            }
+            // Collapse consecutive samples claiming the same thing into one interval
+            match route_hints.last_mut() {
+                Some(hint)
+                    if hint.line_ref == sample.line_ref
+                        && hint.journey_ref == sample.journey_ref =>
+                {
+                    hint.end_time = sample.time;
+                }
+                _ => {
+                    route_hints.push(RouteHint {
+                        start_time: sample.time,
+                        end_time: sample.time,
+                        line_ref: sample.line_ref,
+                        journey_ref: sample.journey_ref,
+                    });
+                }
+            }
+        }
+
+        raw_points.insert(vehicle_name.clone(), points);
+        hints.insert(vehicle_name, route_hints);
+    }
+    Ok((
+        clean::build_trajectories(raw_points, gps_bounds, cleaning),
+        hints,
+    ))
+}
+
+struct Sample {
+    pos: LonLat,
+    time: Time,
+    line_ref: Option<String>,
+    journey_ref: Option<String>,
+}
+
+// Producers differ in namespaces and prefixes, so just match the local tag name.
+fn child<'a, 'input>(node: Node<'a, 'input>, tag: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.tag_name().name() == tag)
+}
+
+// Searches all descendants, not just direct children
+fn text_of<'a, 'input>(node: Node<'a, 'input>, tag: &str) -> Option<&'a str> {
+    node.descendants()
+        .find(|n| n.tag_name().name() == tag)
+        .and_then(|n| n.text())
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bus 1's first two activities are out of order, and its third doesn't claim any line or
+    // journey. bus 2 has only one activity.
+    const FIXTURE: &str = r#"<Siri xmlns="http://www.siri.org.uk/siri" version="2.0">
+<ServiceDelivery>
+  <VehicleMonitoringDelivery>
+    <VehicleActivity>
+      <RecordedAtTime>2022-03-14T08:00:30+01:00</RecordedAtTime>
+      <MonitoredVehicleJourney>
+        <LineRef>10</LineRef>
+        <FramedVehicleJourneyRef>
+          <DataFrameRef>2022-03-14</DataFrameRef>
+          <DatedVehicleJourneyRef>A</DatedVehicleJourneyRef>
+        </FramedVehicleJourneyRef>
+        <VehicleLocation>
+          <Longitude>-122.301</Longitude>
+          <Latitude>47.6</Latitude>
+        </VehicleLocation>
+        <VehicleRef>bus 1</VehicleRef>
+      </MonitoredVehicleJourney>
+    </VehicleActivity>
+    <VehicleActivity>
+      <RecordedAtTime>2022-03-14T08:00:00+01:00</RecordedAtTime>
+      <MonitoredVehicleJourney>
+        <LineRef>10</LineRef>
+        <FramedVehicleJourneyRef>
+          <DataFrameRef>2022-03-14</DataFrameRef>
+          <DatedVehicleJourneyRef>A</DatedVehicleJourneyRef>
+        </FramedVehicleJourneyRef>
+        <VehicleLocation>
+          <Longitude>-122.300</Longitude>
+          <Latitude>47.6</Latitude>
+        </VehicleLocation>
+        <VehicleRef>bus 1</VehicleRef>
+      </MonitoredVehicleJourney>
+    </VehicleActivity>
+    <VehicleActivity>
+      <RecordedAtTime>2022-03-14T08:01:00+01:00</RecordedAtTime>
+      <MonitoredVehicleJourney>
+        <VehicleLocation>
+          <Longitude>-122.302</Longitude>
+          <Latitude>47.6</Latitude>
+        </VehicleLocation>
+        <VehicleRef>bus 1</VehicleRef>
+      </MonitoredVehicleJourney>
+    </VehicleActivity>
+    <VehicleActivity>
+      <RecordedAtTime>2022-03-14T08:01:30+01:00</RecordedAtTime>
+      <MonitoredVehicleJourney>
+        <LineRef>10</LineRef>
+        <FramedVehicleJourneyRef>
+          <DataFrameRef>2022-03-14</DataFrameRef>
+          <DatedVehicleJourneyRef>B</DatedVehicleJourneyRef>
+        </FramedVehicleJourneyRef>
+        <VehicleLocation>
+          <Longitude>-122.303</Longitude>
+          <Latitude>47.6</Latitude>
+        </VehicleLocation>
+        <VehicleRef>bus 1</VehicleRef>
+      </MonitoredVehicleJourney>
+    </VehicleActivity>
+    <VehicleActivity>
+      <RecordedAtTime>2022-03-14T08:00:00+01:00</RecordedAtTime>
+      <MonitoredVehicleJourney>
+        <VehicleLocation>
+          <Longitude>-122.300</Longitude>
+          <Latitude>47.6</Latitude>
+        </VehicleLocation>
+        <VehicleRef>bus 2</VehicleRef>
+      </MonitoredVehicleJourney>
+    </VehicleActivity>
+  </VehicleMonitoringDelivery>
+</ServiceDelivery>
+</Siri>"#;
+
+    fn time(hours: usize, minutes: usize, seconds: f64) -> Time {
+        Time::START_OF_DAY
+            + Duration::hours(hours)
+            + Duration::minutes(minutes)
+            + Duration::seconds(seconds)
+    }
+
+    fn gps_bounds() -> GPSBounds {
+        let mut gps_bounds = GPSBounds::new();
+        gps_bounds.update(LonLat::new(-122.31, 47.59));
+        gps_bounds.update(LonLat::new(-122.29, 47.61));
+        gps_bounds
+    }
+
+    #[test]
+    fn trajectories_and_hints() {
+        let date = NaiveDate::from_ymd_opt(2022, 3, 14).unwrap();
+        let (trajectories, hints) = load(
+            FIXTURE.as_bytes(),
+            &gps_bounds(),
+            date,
+            &CleaningConfig::default(),
+        )
+        .unwrap();
+
+        let bus1 = VehicleName("bus 1".to_string());
+        let (trajectory, _) = &trajectories[&bus1];
+        assert_eq!(trajectory.num_points(), 4);
+        assert_eq!(trajectory.start_time(), time(8, 0, 0.0));
+        assert_eq!(trajectory.end_time(), time(8, 1, 30.0));
+        // Samples not claiming anything don't interrupt a hint
+        let summary: Vec<(Time, Time, Option<&str>, Option<&str>)> = hints[&bus1]
+            .iter()
+            .map(|h| {
+                (
+                    h.start_time,
+                    h.end_time,
+                    h.line_ref.as_deref(),
+                    h.journey_ref.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (time(8, 0, 0.0), time(8, 0, 30.0), Some("10"), Some("A")),
+                (time(8, 1, 30.0), time(8, 1, 30.0), Some("10"), Some("B")),
+            ]
+        );
+
+        // One point isn't enough for a trajectory
+        let bus2 = VehicleName("bus 2".to_string());
+        assert!(!trajectories.contains_key(&bus2));
+        assert!(hints[&bus2].is_empty());
+    }
+
+    #[test]
+    fn wrong_date() {
+        let date = NaiveDate::from_ymd_opt(2022, 3, 15).unwrap();
+        assert!(load(
+            FIXTURE.as_bytes(),
+            &gps_bounds(),
+            date,
+            &CleaningConfig::default()
+        )
+        .is_err());
+    }
+}
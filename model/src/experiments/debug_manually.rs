@@ -1,3 +1,4 @@
+use abstutil::Timer;
 use anyhow::Result;
 use geom::UnitFmt;
 
@@ -8,7 +9,7 @@ impl DailyModel {
     //
     // But they mostly seem to be very short routes around a campus
     pub fn vehicles_with_few_stops(&self) -> Result<()> {
-        for (vehicle, variants) in self.vehicles_to_possible_routes()? {
+        for (vehicle, variants) in self.vehicles_to_possible_routes(&mut Timer::throwaway())? {
             for v in variants {
                 let variant = self.gtfs.variant(v);
                 if variant.stops().len() < 15 {
@@ -1,7 +1,10 @@
 use anyhow::Result;
 use geom::{Distance, PolyLine, Time};
 
-use crate::{DailyModel, IDMapping, Timetable, Trajectory, Vehicle, VehicleName};
+use crate::{
+    CleaningStats, CompressionStats, DailyModel, IDMapping, LinearShape, Timetable, Trajectory,
+    Vehicle, VehicleName,
+};
 use gtfs::{DateFilter, RouteVariant, RouteVariantID, TripID, VariantFilter};
 
 impl DailyModel {
@@ -65,7 +68,11 @@ impl DailyModel {
                 id,
                 original_id,
                 trajectory,
+                route_hints: Vec::new(),
+                cleaning: CleaningStats::default(),
+                compression: CompressionStats::default(),
                 timetable: Timetable::new(),
+                stages: Timetable::new(),
             });
         }
     }
@@ -77,15 +84,14 @@ fn split_shape_by_stops(
     variant: &RouteVariant,
 ) -> Result<Vec<PolyLine>> {
     // Snap the stops to distances along the shape
-    let mut stop_distances = Vec::new();
-    for stop_id in variant.stops() {
-        let projected_pt = shape_pl.project_pt(model.gtfs.stops[&stop_id].pos);
-        if let Some((dist, _)) = shape_pl.dist_along_of_point(projected_pt) {
-            stop_distances.push(dist);
-        } else {
-            bail!("Couldn't find {:?} along shape", stop_id);
-        }
-    }
+    let stop_distances = model
+        .stop_dists_along_shape(variant.variant_id, &LinearShape::new(shape_pl.clone()))
+        .ok_or_else(|| {
+            anyhow!(
+                "Couldn't find stops of {:?} along shape",
+                variant.variant_id
+            )
+        })?;
 
     // Check the distance along is increasing
     for pair in stop_distances.windows(2) {
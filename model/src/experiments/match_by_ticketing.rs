@@ -87,7 +87,7 @@ impl PossibleMatch {
         t1: Time,
         t2: Time,
     ) -> Result<Self> {
-        let route_pl = variant.polyline(&model.gtfs)?;
+        let shape = model.variant_shape(variant.variant_id)?;
         let route_short_name = model.gtfs.routes[&variant.route_id]
             .short_name
             .clone()
@@ -99,9 +99,7 @@ impl PossibleMatch {
                 if leg.route_short_name == route_short_name && leg.time >= t1 && leg.time <= t2 {
                     // TODO When shapes double back on themselves, this will likely oscillate in
                     // weird ways
-                    if let Some((dist, _)) =
-                        route_pl.dist_along_of_point(route_pl.project_pt(leg.pos))
-                    {
+                    if let Some((dist, _)) = shape.closest(leg.pos) {
                         boardings.push((leg.time, dist));
                     }
                 }
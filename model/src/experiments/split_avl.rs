@@ -5,8 +5,8 @@ use crate::{DailyModel, Trajectory, VehicleID};
 use gtfs::RouteVariantID;
 
 impl DailyModel {
-    // Find all times the AVL is close to endpoints of a possible variant's shape. Use those to clip
-    // into multiple pieces, just view the result
+    // Find all times the AVL leaves the start of a possible variant's shape and reaches its end.
+    // Use those to clip into multiple pieces, just view the result
     //
     // This idea could be worth finishing, but is probably subsumed by matching to stops
     pub fn split_avl_by_route_shape(&self, vehicle: VehicleID) -> Result<()> {
@@ -23,30 +23,33 @@ impl DailyModel {
         vehicle: VehicleID,
         variant: RouteVariantID,
     ) -> Vec<Trajectory> {
-        let variant = self.gtfs.variant(variant);
-        let shape_pl = &self.gtfs.shapes[&variant.shape_id];
-
-        let vehicle_trajectory = &self.vehicles[vehicle.0].trajectory;
+        let projected = match self.project_onto_variant(vehicle, variant) {
+            Ok(projected) => projected,
+            Err(err) => {
+                println!("can't project onto {:?}: {}", variant, err);
+                return Vec::new();
+            }
+        };
 
-        let threshold = Distance::meters(10.0);
-        let times_near_start = vehicle_trajectory.times_near_pos(shape_pl.first_pt(), threshold);
-        let times_near_end = vehicle_trajectory.times_near_pos(shape_pl.last_pt(), threshold);
+        let dedupe = self.matching.dedupe_passes;
+        let times_near_start = projected.arrivals_at(Distance::ZERO, dedupe);
+        let times_near_end = projected.arrivals_at(projected.shape().length(), dedupe);
 
         if true {
-            println!("does {:?} match {:?}?", vehicle, variant.variant_id);
-            println!("near start at:");
-            for (t, _) in &times_near_start {
+            println!("does {:?} match {:?}?", vehicle, variant);
+            println!("leaves start at:");
+            for t in &times_near_start {
                 println!("- {t}");
             }
-            println!("near end at:");
-            for (t, _) in &times_near_end {
+            println!("reaches end at:");
+            for t in &times_near_end {
                 println!("- {t}");
             }
         }
 
         // Attempt to match up the times
         let mut intervals = Vec::new();
-        for ((t1, _), (t2, _)) in times_near_start.into_iter().zip(times_near_end.into_iter()) {
+        for (t1, t2) in times_near_start.into_iter().zip(times_near_end.into_iter()) {
             if t1 < t2 {
                 if intervals
                     .last()
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use geojson::{Feature, FeatureCollection, GeoJson};
+
+use crate::{DailyModel, MultidayModel};
+
+impl DailyModel {
+    /// One row per BoardingEvent, in the same format as the multiday export
+    pub fn export_to_csv(&self) -> Result<String> {
+        MultidayModel::new_from_daily_models(std::slice::from_ref(self))?.export_to_csv()
+    }
+
+    /// The matching report as JSON
+    pub fn export_matching_report(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.matching_report)?)
+    }
+
+    /// One LineString per vehicle trajectory
+    pub fn export_to_geojson(&self) -> Result<String> {
+        let mut features = Vec::new();
+        for vehicle in &self.vehicles {
+            let mut feature = Feature {
+                bbox: None,
+                geometry: Some(
+                    vehicle
+                        .trajectory
+                        .as_polyline()
+                        .to_geojson(Some(&self.gps_bounds)),
+                ),
+                id: None,
+                properties: None,
+                foreign_members: None,
+            };
+            feature.set_property("vehicle", vehicle.original_id.as_str());
+            feature.set_property("trips", vehicle.timetable.0.len());
+            feature.set_property("start_time", vehicle.trajectory.start_time().to_string());
+            feature.set_property("end_time", vehicle.trajectory.end_time().to_string());
+            features.push(feature);
+        }
+        to_string(features)
+    }
+}
+
+impl MultidayModel {
+    /// One Point per stop, with the total boardings over all days. Stops from every feed version
+    /// are included, matched by their original ID and placed where the newest feed has them. All
+    /// boardings are counted, regardless of confidence.
+    pub fn export_to_geojson(&self) -> Result<String> {
+        let boardings = self.count_boardings_by_orig_stop(0.0);
+        let mut stops = BTreeMap::new();
+        for feed in &self.feeds {
+            for stop in feed.gtfs.stops.values() {
+                stops.insert(stop.orig_id.clone(), (stop, &feed.gps_bounds));
+            }
+        }
+
+        let mut features = Vec::new();
+        for (stop, gps_bounds) in stops.into_values() {
+            let pos = stop.pos.to_gps(gps_bounds);
+            let mut feature = Feature {
+                bbox: None,
+                geometry: Some(geojson::Geometry::new(geojson::Value::Point(vec![
+                    pos.x(),
+                    pos.y(),
+                ]))),
+                id: None,
+                properties: None,
+                foreign_members: None,
+            };
+            feature.set_property("stop_id", stop.orig_id.as_str());
+            if let Some(ref name) = stop.name {
+                feature.set_property("name", name.as_str());
+            }
+            feature.set_property("boardings", boardings.get(stop.orig_id.clone()));
+            features.push(feature);
+        }
+        to_string(features)
+    }
+}
+
+fn to_string(features: Vec<Feature>) -> Result<String> {
+    let gj = GeoJson::FeatureCollection(FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: None,
+    });
+    Ok(serde_json::to_string(&gj)?)
+}
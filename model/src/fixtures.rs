@@ -0,0 +1,174 @@
+// Small synthetic feeds and models for unit tests
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use geom::{Bounds, Duration, GPSBounds, Pt2D, Time};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    CleaningStats, CompressionStats, DailyModel, MatchingConfig, MatchingReport, Timetable,
+    Trajectory, Vehicle, VehicleID, VehicleName,
+};
+use gtfs::{
+    CheapID, DaysOfWeek, IDMapping, Route, RouteID, RouteType, RouteVariant, RouteVariantID,
+    Service, ServiceID, Stop, StopID, StopTime, Trip, GTFS,
+};
+
+/// One route with a single variant and trip
+pub struct RouteFixture {
+    pub short_name: Option<&'static str>,
+    /// Indices into the feed's stops. The shape is the straight line between them.
+    pub stops: Vec<usize>,
+    /// The trip reaches each stop two minutes after the previous one
+    pub start_time: Time,
+}
+
+/// The day of every fixture model
+pub fn date() -> NaiveDate {
+    NaiveDate::from_ymd_opt(2022, 3, 14).unwrap()
+}
+
+/// Every route runs on `date()`. Route `i` has the variant `RouteVariantID(i)` and trip
+/// `TripID::new(i)`, and its original IDs are `route{i}` and `trip{i}`. Stop `i` is `stop{i}`.
+pub fn gtfs(stop_positions: &[Pt2D], routes: Vec<RouteFixture>) -> GTFS {
+    let mut gtfs = GTFS::empty();
+    let service_id: ServiceID = id("daily");
+    gtfs.calendar.services.insert(
+        service_id.clone(),
+        Service {
+            service_id,
+            days_of_week: DaysOfWeek::all(),
+            start_date: date(),
+            end_date: date(),
+            extra_days: BTreeSet::new(),
+            removed_days: BTreeSet::new(),
+        },
+    );
+    for (idx, pos) in stop_positions.iter().enumerate() {
+        gtfs.stops.insert(
+            StopID::new(idx),
+            Stop {
+                id: StopID::new(idx),
+                orig_id: id(&format!("stop{idx}")),
+                pos: *pos,
+                code: None,
+                name: None,
+                description: None,
+                route_variants: BTreeSet::new(),
+            },
+        );
+    }
+
+    for (idx, route) in routes.into_iter().enumerate() {
+        let variant_id = RouteVariantID(idx);
+        let stop_times = route
+            .stops
+            .iter()
+            .enumerate()
+            .map(|(i, stop)| {
+                let time = route.start_time + Duration::minutes(2 * i);
+                StopTime {
+                    arrival_time: time,
+                    departure_time: time,
+                    stop_id: StopID::new(*stop),
+                }
+            })
+            .collect();
+        for stop in &route.stops {
+            gtfs.stops
+                .get_mut(&StopID::new(*stop))
+                .unwrap()
+                .route_variants
+                .insert(variant_id);
+        }
+
+        let route_id: RouteID = id(&format!("route{idx}"));
+        let trip = Trip {
+            id: CheapID::new(idx),
+            orig_id: id(&format!("trip{idx}")),
+            route_id: route_id.clone(),
+            shape_id: id(&format!("shape{idx}")),
+            service_id: id("daily"),
+            headsign: None,
+            outbound_direction: true,
+            stop_times,
+        };
+        gtfs.routes.insert(
+            route_id.clone(),
+            Route {
+                route_id: route_id.clone(),
+                route_type: RouteType::Bus,
+                short_name: route.short_name.map(|x| x.to_string()),
+                long_name: None,
+                description: None,
+                variants: vec![RouteVariant {
+                    route_id,
+                    variant_id,
+                    headsign: None,
+                    service_id: trip.service_id.clone(),
+                    shape_id: trip.shape_id.clone(),
+                    trips: vec![trip],
+                }],
+            },
+        );
+    }
+    gtfs
+}
+
+/// Vehicle `i` is named `bus{i}` and follows the trajectory. Nothing is matched yet.
+pub fn daily_model(gtfs: GTFS, trajectories: Vec<Trajectory>) -> DailyModel {
+    let mut vehicle_ids = IDMapping::new();
+    let vehicles = trajectories
+        .into_iter()
+        .enumerate()
+        .map(|(idx, trajectory)| {
+            let original_id = VehicleName(format!("bus{idx}"));
+            let id: VehicleID = vehicle_ids.insert_new(original_id.clone()).unwrap();
+            Vehicle {
+                id,
+                original_id,
+                trajectory,
+                route_hints: Vec::new(),
+                cleaning: CleaningStats::default(),
+                compression: CompressionStats::default(),
+                timetable: Timetable::new(),
+                stages: Timetable::new(),
+            }
+        })
+        .collect();
+    DailyModel {
+        date: date(),
+        bounds: Bounds::from(&[Pt2D::zero(), Pt2D::new(1.0, 1.0)]),
+        gps_bounds: GPSBounds::new(),
+        vehicles,
+        vehicle_ids,
+        gtfs: Arc::new(gtfs),
+        gtfs_hash: 0,
+        journeys: Vec::new(),
+        boardings: Vec::new(),
+        matching: MatchingConfig::default(),
+        matching_report: MatchingReport::default(),
+    }
+}
+
+/// A trajectory through each point, reached some number of seconds after midnight
+pub fn trajectory(pts: Vec<(f64, f64, f64)>) -> Trajectory {
+    Trajectory::new(
+        pts.into_iter()
+            .map(|(x, y, secs)| {
+                (
+                    Pt2D::new(x, y),
+                    Time::START_OF_DAY + Duration::seconds(secs),
+                )
+            })
+            .collect(),
+    )
+    .unwrap()
+}
+
+// The GTFS string IDs can only be built by deserializing
+fn id<T: DeserializeOwned>(x: &str) -> T {
+    serde_json::from_value(serde_json::Value::String(x.to_string())).unwrap()
+}
@@ -0,0 +1,608 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use geom::{Bounds, GPSBounds};
+use serde::de::DeserializeOwned;
+
+use gtfs::{IDMapping, GTFS};
+
+use crate::{
+    DailyModel, FeedVersion, MatchingConfig, MultidayModel, SharedGtfs, VehicleID, VehicleName,
+};
+
+// Models are saved in a small container format, so that changing a struct doesn't silently break
+// every saved file, and so that big parts of a model can be skipped when loading.
+//
+// - 8 bytes of magic, `BUSMODEL`
+// - 1 byte for the kind of file: 0 for a daily model, 1 for multiday, 2 for a GTFS feed shared by
+//   many models
+// - the schema version, as a little-endian u32
+// - the number of sections, as a little-endian u32
+// - per section: a 1 byte ID, then the offset from the start of the file and the length, both as
+//   little-endian u64s
+// - the sections, each one `abstutil::to_binary` of some part of the model
+//
+// Multiday models may span several GTFS feed versions, so their GTFS section holds a list of feeds.
+//
+// Files from before this format are base64-encoded `abstutil::to_binary` of the entire model.
+// Those can still be read, as long as the structs haven't changed since. Files saved from the
+// browser are base64-encoded containers.
+
+const MAGIC: &[u8; 8] = b"BUSMODEL";
+const HEADER_LEN: usize = 8 + 1 + 4 + 4;
+const ENTRY_LEN: usize = 1 + 8 + 8;
+
+/// Bump this whenever the serialized form of any section changes, and teach `migrate` how to
+/// upgrade from the previous version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Independently loadable parts of a saved model
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    /// Date, bounds, and vehicle IDs. Always loaded.
+    Meta,
+    Gtfs,
+    /// Trajectories and everything else per vehicle. Only in daily models.
+    Vehicles,
+    /// Only in daily models
+    Journeys,
+    Boardings,
+    /// The MatchingReport. Only in daily models.
+    Report,
+}
+
+impl Section {
+    fn id(self) -> u8 {
+        match self {
+            Section::Meta => 0,
+            Section::Gtfs => 1,
+            Section::Vehicles => 2,
+            Section::Journeys => 3,
+            Section::Boardings => 4,
+            Section::Report => 5,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        Ok(match id {
+            0 => Section::Meta,
+            1 => Section::Gtfs,
+            2 => Section::Vehicles,
+            3 => Section::Journeys,
+            4 => Section::Boardings,
+            5 => Section::Report,
+            _ => bail!("Unknown section {id}"),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Daily,
+    Multiday,
+    Gtfs,
+}
+
+impl DailyModel {
+    /// If `embed_gtfs` is false, the GTFS feed must be saved separately with
+    /// `SharedGtfs::to_file_bytes` and passed in when loading.
+    pub fn to_file_bytes(&self, embed_gtfs: bool) -> Vec<u8> {
+        let mut sections = BTreeMap::new();
+        sections.insert(
+            Section::Meta,
+            abstutil::to_binary(&(
+                &self.date,
+                &self.bounds,
+                &self.gps_bounds,
+                &self.vehicle_ids,
+                &self.gtfs_hash,
+                &self.matching,
+            )),
+        );
+        if embed_gtfs {
+            sections.insert(Section::Gtfs, abstutil::to_binary(&*self.gtfs));
+        }
+        sections.insert(Section::Vehicles, abstutil::to_binary(&self.vehicles));
+        sections.insert(Section::Journeys, abstutil::to_binary(&self.journeys));
+        sections.insert(Section::Boardings, abstutil::to_binary(&self.boardings));
+        sections.insert(Section::Report, abstutil::to_binary(&self.matching_report));
+        write_container(Kind::Daily, sections)
+    }
+
+    /// If the file doesn't embed its GTFS feed, the matching shared feed must be passed in. If it
+    /// does and a shared feed matches, the shared one is used to save memory.
+    pub fn from_file_bytes(bytes: &[u8], shared_gtfs: &[SharedGtfs]) -> Result<Self> {
+        Self::from_file_bytes_partial(
+            bytes,
+            shared_gtfs,
+            &[
+                Section::Gtfs,
+                Section::Vehicles,
+                Section::Journeys,
+                Section::Boardings,
+                Section::Report,
+            ],
+        )
+    }
+
+    /// Only loads some sections. Everything else is left empty.
+    pub fn from_file_bytes_partial(
+        bytes: &[u8],
+        shared_gtfs: &[SharedGtfs],
+        sections: &[Section],
+    ) -> Result<Self> {
+        let bytes = unwrap_base64(bytes)?;
+        let container = match Container::parse(&bytes)? {
+            Some(x) => x,
+            None => return legacy_load(&bytes),
+        };
+        container.expect_kind(Kind::Daily)?;
+
+        let (date, bounds, gps_bounds, vehicle_ids, gtfs_hash, matching) =
+            container.load(Section::Meta)?;
+        let mut model = Self::empty();
+        model.date = date;
+        model.bounds = bounds;
+        model.gps_bounds = gps_bounds;
+        model.vehicle_ids = vehicle_ids;
+        model.gtfs_hash = gtfs_hash;
+        model.matching = matching;
+        for section in sections {
+            match section {
+                Section::Meta => {}
+                Section::Gtfs => {
+                    model.gtfs = container
+                        .resolve_gtfs(&[gtfs_hash], shared_gtfs)?
+                        .pop()
+                        .unwrap();
+                }
+                Section::Vehicles => {
+                    model.vehicles = container.load(Section::Vehicles)?;
+                }
+                Section::Journeys => {
+                    model.journeys = container.load(Section::Journeys)?;
+                }
+                Section::Boardings => {
+                    model.boardings = container.load(Section::Boardings)?;
+                }
+                Section::Report => {
+                    model.matching_report = container.load(Section::Report)?;
+                }
+            }
+        }
+        Ok(model)
+    }
+}
+
+impl MultidayModel {
+    /// If `embed_gtfs` is false, every feed version must be saved separately with
+    /// `SharedGtfs::to_file_bytes` and passed in when loading.
+    pub fn to_file_bytes(&self, embed_gtfs: bool) -> Vec<u8> {
+        let feeds: Vec<(u64, &GPSBounds, NaiveDate, NaiveDate)> = self
+            .feeds
+            .iter()
+            .map(|f| (f.hash, &f.gps_bounds, f.first_day, f.last_day))
+            .collect();
+        let mut sections = BTreeMap::new();
+        sections.insert(
+            Section::Meta,
+            abstutil::to_binary(&(&self.bounds, &self.gps_bounds, &self.vehicle_ids, &feeds)),
+        );
+        if embed_gtfs {
+            let gtfs: Vec<&GTFS> = self.feeds.iter().map(|f| &*f.gtfs).collect();
+            sections.insert(Section::Gtfs, abstutil::to_binary(&gtfs));
+        }
+        sections.insert(
+            Section::Boardings,
+            abstutil::to_binary(&self.boardings_per_day),
+        );
+        write_container(Kind::Multiday, sections)
+    }
+
+    /// A daily model can also be opened, as a multiday model with just that day. Only its
+    /// boardings are loaded, not the trajectories.
+    pub fn from_file_bytes(bytes: &[u8], shared_gtfs: &[SharedGtfs]) -> Result<Self> {
+        let bytes = unwrap_base64(bytes)?;
+        let container = match Container::parse(&bytes)? {
+            Some(x) => x,
+            None => return legacy_load(&bytes),
+        };
+        if container.kind == Kind::Daily {
+            let model = DailyModel::from_file_bytes_partial(
+                &bytes,
+                shared_gtfs,
+                &[Section::Gtfs, Section::Boardings],
+            )?;
+            return Self::new_from_daily_models(&[model]);
+        }
+        container.expect_kind(Kind::Multiday)?;
+
+        let (bounds, gps_bounds, vehicle_ids, feeds): (
+            Bounds,
+            GPSBounds,
+            IDMapping<VehicleName, VehicleID>,
+            Vec<(u64, GPSBounds, NaiveDate, NaiveDate)>,
+        ) = container.load(Section::Meta)?;
+        let hashes: Vec<u64> = feeds.iter().map(|(hash, _, _, _)| *hash).collect();
+        let gtfs = container.resolve_gtfs(&hashes, shared_gtfs)?;
+        let feeds: Vec<FeedVersion> = feeds
+            .into_iter()
+            .zip(gtfs)
+            .map(
+                |((hash, gps_bounds, first_day, last_day), gtfs)| FeedVersion {
+                    gtfs,
+                    hash,
+                    gps_bounds,
+                    first_day,
+                    last_day,
+                },
+            )
+            .collect();
+        Ok(Self {
+            bounds,
+            gps_bounds,
+            gtfs: feeds
+                .last()
+                .map(|f| f.gtfs.clone())
+                .unwrap_or_else(|| Arc::new(GTFS::empty())),
+            feeds,
+            boardings_per_day: container.load(Section::Boardings)?,
+            vehicle_ids,
+        })
+    }
+}
+
+impl SharedGtfs {
+    pub fn to_file_bytes(&self) -> Vec<u8> {
+        let mut sections = BTreeMap::new();
+        sections.insert(Section::Meta, abstutil::to_binary(&self.hash));
+        sections.insert(Section::Gtfs, abstutil::to_binary(&*self.gtfs));
+        write_container(Kind::Gtfs, sections)
+    }
+
+    pub fn from_file_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = unwrap_base64(bytes)?;
+        let container =
+            Container::parse(&bytes)?.ok_or_else(|| anyhow!("This isn't a saved GTFS feed"))?;
+        container.expect_kind(Kind::Gtfs)?;
+        let gtfs: GTFS = container.load(Section::Gtfs)?;
+        Ok(Self {
+            gtfs: Arc::new(gtfs),
+            hash: container.load(Section::Meta)?,
+        })
+    }
+}
+
+/// The GTFS feeds a saved model refers to, without loading anything else. Files from before the
+/// container format always embed their feed, so they refer to none.
+pub(crate) fn gtfs_hashes(bytes: &[u8]) -> Result<Vec<u64>> {
+    let bytes = unwrap_base64(bytes)?;
+    let container = match Container::parse(&bytes)? {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    match container.kind {
+        Kind::Daily => {
+            let (_, _, _, _, hash, _): (
+                NaiveDate,
+                Bounds,
+                GPSBounds,
+                IDMapping<VehicleName, VehicleID>,
+                u64,
+                MatchingConfig,
+            ) = container.load(Section::Meta)?;
+            Ok(vec![hash])
+        }
+        Kind::Multiday => {
+            let (_, _, _, feeds): (
+                Bounds,
+                GPSBounds,
+                IDMapping<VehicleName, VehicleID>,
+                Vec<(u64, GPSBounds, NaiveDate, NaiveDate)>,
+            ) = container.load(Section::Meta)?;
+            Ok(feeds.into_iter().map(|(hash, _, _, _)| hash).collect())
+        }
+        Kind::Gtfs => Ok(Vec::new()),
+    }
+}
+
+fn write_container(kind: Kind, sections: BTreeMap<Section, Vec<u8>>) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(match kind {
+        Kind::Daily => 0,
+        Kind::Multiday => 1,
+        Kind::Gtfs => 2,
+    });
+    out.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    out.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+
+    let mut offset = HEADER_LEN + ENTRY_LEN * sections.len();
+    for (section, bytes) in &sections {
+        out.push(section.id());
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+        out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        offset += bytes.len();
+    }
+    for bytes in sections.into_values() {
+        out.extend(bytes);
+    }
+    out
+}
+
+struct Container<'a> {
+    kind: Kind,
+    version: u32,
+    sections: BTreeMap<Section, &'a [u8]>,
+}
+
+impl<'a> Container<'a> {
+    /// None if this isn't the container format at all
+    fn parse(bytes: &'a [u8]) -> Result<Option<Self>> {
+        if !bytes.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        if bytes.len() < HEADER_LEN {
+            bail!("Model file is truncated");
+        }
+        let kind = match bytes[8] {
+            0 => Kind::Daily,
+            1 => Kind::Multiday,
+            2 => Kind::Gtfs,
+            x => bail!("Unknown model kind {x}"),
+        };
+        let version = u32::from_le_bytes(bytes[9..13].try_into()?);
+        if version > SCHEMA_VERSION {
+            bail!(
+                "This model was saved with schema version {version}, but this software only understands up to {SCHEMA_VERSION}. Upgrade it."
+            );
+        }
+        let count = u32::from_le_bytes(bytes[13..17].try_into()?) as usize;
+
+        let mut sections = BTreeMap::new();
+        for idx in 0..count {
+            let entry = bytes
+                .get(HEADER_LEN + idx * ENTRY_LEN..HEADER_LEN + (idx + 1) * ENTRY_LEN)
+                .ok_or_else(|| anyhow!("Model file is truncated"))?;
+            let section = Section::from_id(entry[0])?;
+            let offset = u64::from_le_bytes(entry[1..9].try_into()?) as usize;
+            let len = u64::from_le_bytes(entry[9..17].try_into()?) as usize;
+            let end = match offset.checked_add(len) {
+                Some(end) => end,
+                None => bail!("Model file is corrupt in {:?}", section),
+            };
+            let contents = bytes
+                .get(offset..end)
+                .ok_or_else(|| anyhow!("Model file is truncated in {:?}", section))?;
+            sections.insert(section, contents);
+        }
+        Ok(Some(Self {
+            kind,
+            version,
+            sections,
+        }))
+    }
+
+    fn expect_kind(&self, kind: Kind) -> Result<()> {
+        if self.kind != kind {
+            bail!(
+                "Expected a {:?} model, but this file is {:?}",
+                kind,
+                self.kind
+            );
+        }
+        Ok(())
+    }
+
+    // Find each feed, preferring shared ones. The embedded feeds are in the same order as the
+    // hashes.
+    fn resolve_gtfs(&self, hashes: &[u64], shared_gtfs: &[SharedGtfs]) -> Result<Vec<Arc<GTFS>>> {
+        let find_shared = |hash: u64| shared_gtfs.iter().find(|s| s.hash == hash);
+        let mut embedded: Vec<Option<GTFS>> = Vec::new();
+        if hashes.iter().any(|hash| find_shared(*hash).is_none())
+            && self.sections.contains_key(&Section::Gtfs)
+        {
+            embedded = if self.kind == Kind::Multiday {
+                let list: Vec<GTFS> = self.load(Section::Gtfs)?;
+                list.into_iter().map(Some).collect()
+            } else {
+                vec![Some(self.load(Section::Gtfs)?)]
+            };
+        }
+
+        let mut result = Vec::new();
+        for (idx, hash) in hashes.iter().enumerate() {
+            if let Some(shared) = find_shared(*hash) {
+                result.push(shared.gtfs.clone());
+            } else if let Some(gtfs) = embedded.get_mut(idx).and_then(|x| x.take()) {
+                result.push(Arc::new(gtfs));
+            } else if shared_gtfs.is_empty() {
+                bail!(
+                    "This model doesn't include its GTFS feed ({:x}). Load the gtfs-*.bin files saved with it.",
+                    hash
+                );
+            } else {
+                bail!(
+                    "This model was built from GTFS {:x}, but none of the GTFS files provided match",
+                    hash
+                );
+            }
+        }
+        Ok(result)
+    }
+
+    fn load<T: DeserializeOwned>(&self, section: Section) -> Result<T> {
+        let bytes = self
+            .sections
+            .get(&section)
+            .ok_or_else(|| anyhow!("Model file is missing {:?}", section))?;
+        if self.version == SCHEMA_VERSION {
+            abstutil::from_binary(bytes)
+        } else {
+            let mut bytes = bytes.to_vec();
+            for version in self.version..SCHEMA_VERSION {
+                bytes = self.migrate(version, section, &bytes)?;
+            }
+            abstutil::from_binary(&bytes)
+        }
+    }
+
+    // Upgrade one section from `version` to the next. Do this by deserializing into the old form
+    // and converting it, then serializing the new form. Keep the old forms of structs below this
+    // impl, named after the last schema version using them.
+    fn migrate(&self, version: u32, section: Section, _bytes: &[u8]) -> Result<Vec<u8>> {
+        bail!(
+            "Don't know how to upgrade {:?} from schema version {version} to {SCHEMA_VERSION}",
+            section
+        )
+    }
+}
+
+// The browser can only save text files, so models saved there are base64-encoded. Older files
+// are always base64-encoded.
+fn unwrap_base64(bytes: &[u8]) -> Result<Cow<[u8]>> {
+    if bytes.starts_with(MAGIC) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    let decoded = base64::decode(bytes)
+        .map_err(|_| anyhow!("This isn't a model file, or it's from an unknown older version"))?;
+    Ok(Cow::Owned(decoded))
+}
+
+// Before the container format, the entire model was serialized directly
+fn legacy_load<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    abstutil::from_binary(bytes).map_err(|err| {
+        anyhow!("This model was saved by an older version and can't be read anymore: {err}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use geom::{Distance, Duration, Time};
+    use gtfs::{CheapID, RouteVariantID};
+
+    use super::*;
+    use crate::{BoardingEvent, JourneyID};
+
+    const HASH: u64 = 0x1234;
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd(2022, 3, 14)
+    }
+
+    fn vehicle_ids() -> IDMapping<VehicleName, VehicleID> {
+        let mut ids = IDMapping::new();
+        ids.insert_new(VehicleName("bus 1".to_string())).unwrap();
+        ids
+    }
+
+    fn event() -> BoardingEvent {
+        BoardingEvent {
+            vehicle: VehicleID(0),
+            variant: RouteVariantID(0),
+            trip: CheapID::new(0),
+            stop: CheapID::new(0),
+            arrival_time: Time::START_OF_DAY + Duration::hours(8),
+            departure_time: Time::START_OF_DAY + Duration::hours(8) + Duration::seconds(30.0),
+            uncertainty: None,
+            new_riders: vec![JourneyID(0)],
+            transfers: Vec::new(),
+            confidence: None,
+            unscheduled: false,
+        }
+    }
+
+    fn matching() -> MatchingConfig {
+        MatchingConfig {
+            stop_threshold: Distance::meters(25.0),
+            max_skipped_stops: 2,
+            ..Default::default()
+        }
+    }
+
+    fn daily_model() -> DailyModel {
+        let mut model = DailyModel::empty();
+        model.date = date();
+        model.vehicle_ids = vehicle_ids();
+        model.gtfs_hash = HASH;
+        model.boardings = vec![event()];
+        model.matching = matching();
+        model
+    }
+
+    #[test]
+    fn round_trip_daily() {
+        let model = daily_model();
+        let shared = SharedGtfs {
+            gtfs: model.gtfs.clone(),
+            hash: HASH,
+        };
+
+        for bytes in [
+            model.to_file_bytes(true),
+            base64::encode(model.to_file_bytes(true)).into_bytes(),
+        ] {
+            let loaded = DailyModel::from_file_bytes(&bytes, &[]).unwrap();
+            assert_eq!(loaded.date, model.date);
+            assert_eq!(loaded.gtfs_hash, HASH);
+            assert_eq!(loaded.boardings.len(), 1);
+            assert_eq!(loaded.matching.stop_threshold, Distance::meters(25.0));
+        }
+
+        let bytes = model.to_file_bytes(false);
+        assert_eq!(gtfs_hashes(&bytes).unwrap(), vec![HASH]);
+        assert!(DailyModel::from_file_bytes(&bytes, &[]).is_err());
+        let loaded = DailyModel::from_file_bytes(&bytes, &[shared.clone()]).unwrap();
+        assert!(Arc::ptr_eq(&loaded.gtfs, &shared.gtfs));
+
+        let loaded = SharedGtfs::from_file_bytes(&shared.to_file_bytes()).unwrap();
+        assert_eq!(loaded.hash, HASH);
+    }
+
+    #[test]
+    fn round_trip_multiday() {
+        let multiday = MultidayModel::new_from_daily_models(&[daily_model()]).unwrap();
+        let loaded = MultidayModel::from_file_bytes(&multiday.to_file_bytes(true), &[]).unwrap();
+        assert_eq!(loaded.feeds.len(), 1);
+        assert_eq!(loaded.feeds[0].hash, HASH);
+        assert_eq!(loaded.boardings_per_day.len(), 1);
+
+        // A daily model opens as a multiday model with one day
+        let loaded =
+            MultidayModel::from_file_bytes(&daily_model().to_file_bytes(true), &[]).unwrap();
+        assert_eq!(loaded.boardings_per_day.len(), 1);
+        assert_eq!(loaded.boardings_per_day[0].0, date());
+    }
+
+    #[test]
+    fn corrupt_files() {
+        let bytes = daily_model().to_file_bytes(true);
+        for len in [HEADER_LEN - 1, HEADER_LEN + 3, bytes.len() - 1] {
+            assert!(DailyModel::from_file_bytes(&bytes[..len], &[]).is_err());
+        }
+
+        // The first section's offset, plus its length, overflows
+        let mut overflow = bytes.clone();
+        overflow[HEADER_LEN + 1..HEADER_LEN + 9].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(DailyModel::from_file_bytes(&overflow, &[]).is_err());
+
+        let mut newer = bytes.clone();
+        newer[9..13].copy_from_slice(&(SCHEMA_VERSION + 1).to_le_bytes());
+        assert!(DailyModel::from_file_bytes(&newer, &[]).is_err());
+
+        // There's nothing older to migrate from
+        let mut older = bytes.clone();
+        older[9..13].copy_from_slice(&(SCHEMA_VERSION - 1).to_le_bytes());
+        assert!(DailyModel::from_file_bytes(&older, &[]).is_err());
+
+        assert!(MultidayModel::from_file_bytes(
+            &SharedGtfs::new(GTFS::empty()).to_file_bytes(),
+            &[]
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,564 @@
+// Export a replayed day as GTFS-Realtime feeds, so downstream consumers (journey planners, arrival
+// displays) can be tested against something that looks like a live feed.
+//
+// Only the subset of gtfs-realtime.proto that we produce is defined here. Field tags match the
+// official spec.
+
+use std::collections::BTreeMap;
+
+use abstutil::Timer;
+use anyhow::Result;
+use chrono::{FixedOffset, NaiveDate};
+use geom::{Duration, Time};
+use prost::Message;
+
+use crate::{BoardingEvent, DailyModel, ServedTrip, TripPaths, Vehicle};
+use gtfs::{Trip, TripID};
+
+impl DailyModel {
+    /// Every `interval` through the day, write a snapshot of VehiclePositions and TripUpdates to
+    /// `{output_dir}/vehicle_positions/{timestamp}.pb` and `{output_dir}/trip_updates/{timestamp}.pb`.
+    /// The timestamp is POSIX time, so `utc_offset` describes the local time of the input data.
+    ///
+    /// Positions come from the AVL trajectories, following the shape of the trip being served, and
+    /// trips from each vehicle's inferred timetable.
+    /// Stops already visited at the time of a snapshot use the observed arrival time; later stops
+    /// are predicted by carrying over the most recent delay.
+    pub fn export_gtfs_rt(
+        &self,
+        output_dir: &str,
+        interval: Duration,
+        utc_offset: FixedOffset,
+        timer: &mut Timer,
+    ) -> Result<()> {
+        if interval <= Duration::ZERO {
+            bail!("The export interval must be positive, not {interval}");
+        }
+        if self.vehicles.is_empty() {
+            bail!("This model has no vehicles to export");
+        }
+
+        std::fs::create_dir_all(format!("{output_dir}/vehicle_positions"))?;
+        std::fs::create_dir_all(format!("{output_dir}/trip_updates"))?;
+
+        let mut trips: BTreeMap<TripID, &Trip> = BTreeMap::new();
+        for route in self.gtfs.routes.values() {
+            for variant in &route.variants {
+                for trip in &variant.trips {
+                    trips.insert(trip.id, trip);
+                }
+            }
+        }
+        // Boardings are sorted by arrival time, so these are too. Unscheduled runs don't have a
+        // trip of their own, so vehicles doing them are reported without a trip.
+        let mut events_per_trip: BTreeMap<TripID, Vec<&BoardingEvent>> = BTreeMap::new();
+        for ev in self.boardings.iter().filter(|ev| !ev.unscheduled) {
+            events_per_trip
+                .entry(ev.trip)
+                .or_insert_with(Vec::new)
+                .push(ev);
+        }
+
+        let start = self
+            .vehicles
+            .iter()
+            .map(|v| v.trajectory.start_time())
+            .min()
+            .unwrap();
+        let end = self
+            .vehicles
+            .iter()
+            .map(|v| v.trajectory.end_time())
+            .max()
+            .unwrap();
+        let mut times = Vec::new();
+        let mut time = start;
+        while time <= end {
+            times.push(time);
+            time = time + interval;
+        }
+
+        let trip_paths = TripPaths::new(self, timer);
+        timer.start_iter("export GTFS-RT snapshots", times.len());
+        for time in times {
+            timer.next();
+            let snapshot = Snapshot {
+                model: self,
+                trips: &trips,
+                events_per_trip: &events_per_trip,
+                time,
+                timestamp: posix_timestamp(self.date, time, utc_offset),
+            };
+            std::fs::write(
+                format!("{output_dir}/vehicle_positions/{}.pb", snapshot.timestamp),
+                snapshot.vehicle_positions(&trip_paths).encode_to_vec(),
+            )?;
+            std::fs::write(
+                format!("{output_dir}/trip_updates/{}.pb", snapshot.timestamp),
+                snapshot.trip_updates().encode_to_vec(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Snapshot<'a> {
+    model: &'a DailyModel,
+    trips: &'a BTreeMap<TripID, &'a Trip>,
+    events_per_trip: &'a BTreeMap<TripID, Vec<&'a BoardingEvent>>,
+    time: Time,
+    timestamp: u64,
+}
+
+impl<'a> Snapshot<'a> {
+    fn vehicle_positions(&self, trip_paths: &TripPaths) -> FeedMessage {
+        let mut entity = Vec::new();
+        for vehicle in &self.model.vehicles {
+            let (pos, speed) =
+                if let Some(x) = trip_paths.interpolate(self.model, vehicle.id, self.time) {
+                    x
+                } else {
+                    continue;
+                };
+            let gps = pos.to_gps(&self.model.gps_bounds);
+
+            let mut vehicle_position = VehiclePosition {
+                trip: None,
+                vehicle: Some(vehicle_descriptor(vehicle)),
+                position: Some(Position {
+                    latitude: gps.y() as f32,
+                    longitude: gps.x() as f32,
+                    bearing: None,
+                    speed: Some(speed.inner_meters_per_second() as f32),
+                }),
+                current_stop_sequence: None,
+                stop_id: None,
+                current_status: None,
+                timestamp: Some(self.timestamp),
+            };
+
+            if let Some((run, trip)) = self.scheduled_trip(vehicle) {
+                vehicle_position.trip = Some(self.trip_descriptor(trip));
+
+                // Stopped at the most recent stop, or on the way to the next one?
+                let events = self.events(vehicle, run.trip);
+                if let Some(ev) = events.iter().rev().find(|ev| ev.arrival_time <= self.time) {
+                    if self.time <= ev.departure_time {
+                        vehicle_position.stop_id =
+                            Some(self.model.gtfs.stops[&ev.stop].orig_id.as_str().to_string());
+                        vehicle_position.current_status = Some(VehicleStopStatus::StoppedAt as i32);
+                    } else if let Some(next) = trip
+                        .stop_times
+                        .iter()
+                        .skip_while(|st| st.stop_id != ev.stop)
+                        .nth(1)
+                    {
+                        vehicle_position.stop_id = Some(
+                            self.model.gtfs.stops[&next.stop_id]
+                                .orig_id
+                                .as_str()
+                                .to_string(),
+                        );
+                        vehicle_position.current_status =
+                            Some(VehicleStopStatus::InTransitTo as i32);
+                    }
+                }
+            }
+
+            entity.push(FeedEntity {
+                id: vehicle.original_id.0.clone(),
+                is_deleted: None,
+                trip_update: None,
+                vehicle: Some(vehicle_position),
+            });
+        }
+        self.feed_message(entity)
+    }
+
+    fn trip_updates(&self) -> FeedMessage {
+        let mut entity = Vec::new();
+        for vehicle in &self.model.vehicles {
+            let (run, trip) = if let Some(x) = self.scheduled_trip(vehicle) {
+                x
+            } else {
+                continue;
+            };
+            let events = self.events(vehicle, run.trip);
+
+            // Before the first observed stop, assume the trip is on time
+            let mut delay = Duration::ZERO;
+            let mut stop_time_update = Vec::new();
+            // Events follow the trip's stops in order, skipping some. Match them up by position,
+            // since a trip can visit the same stop more than once.
+            let mut next_event = 0;
+            for st in &trip.stop_times {
+                let mut observed = None;
+                if let Some(ev) = events.get(next_event) {
+                    if ev.stop == st.stop_id {
+                        next_event += 1;
+                        if ev.arrival_time <= self.time {
+                            observed = Some(ev);
+                        }
+                    }
+                }
+                let (arrival, departure) = if let Some(ev) = observed {
+                    delay = ev.arrival_time - st.arrival_time;
+                    (
+                        self.stop_time_event(ev.arrival_time, st.arrival_time, ev.uncertainty),
+                        self.stop_time_event(ev.departure_time, st.departure_time, ev.uncertainty),
+                    )
+                } else {
+                    (
+                        self.stop_time_event(st.arrival_time + delay, st.arrival_time, None),
+                        self.stop_time_event(st.departure_time + delay, st.departure_time, None),
+                    )
+                };
+                stop_time_update.push(StopTimeUpdate {
+                    stop_sequence: None,
+                    stop_id: Some(
+                        self.model.gtfs.stops[&st.stop_id]
+                            .orig_id
+                            .as_str()
+                            .to_string(),
+                    ),
+                    arrival: Some(arrival),
+                    departure: Some(departure),
+                });
+            }
+
+            // Entity IDs must be unique within the feed, and a vehicle only does one trip at a time
+            entity.push(FeedEntity {
+                id: format!("{}-{}", vehicle.original_id.0, trip.orig_id.as_str()),
+                is_deleted: None,
+                trip_update: Some(TripUpdate {
+                    trip: self.trip_descriptor(trip),
+                    vehicle: Some(vehicle_descriptor(vehicle)),
+                    stop_time_update,
+                    timestamp: Some(self.timestamp),
+                    delay: Some(delay.inner_seconds() as i32),
+                }),
+                vehicle: None,
+            });
+        }
+        self.feed_message(entity)
+    }
+
+    /// The scheduled trip a vehicle is doing at this time, if any. Trips missing from the GTFS
+    /// are skipped.
+    fn scheduled_trip(&self, vehicle: &'a Vehicle) -> Option<(&'a ServedTrip, &'a Trip)> {
+        let run = vehicle
+            .timetable
+            .get_at_time(self.time)
+            .filter(|run| !run.unscheduled)?;
+        let trip = self.trips.get(&run.trip)?;
+        Some((run, *trip))
+    }
+
+    /// A vehicle's events along one trip, in order. Another vehicle may have also served part of
+    /// the same trip.
+    fn events(&self, vehicle: &Vehicle, trip: TripID) -> Vec<&'a BoardingEvent> {
+        self.events_per_trip
+            .get(&trip)
+            .map(|list| {
+                list.iter()
+                    .filter(|ev| ev.vehicle == vehicle.id)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn feed_message(&self, entity: Vec<FeedEntity>) -> FeedMessage {
+        FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".to_string(),
+                timestamp: Some(self.timestamp),
+            },
+            entity,
+        }
+    }
+
+    fn trip_descriptor(&self, trip: &Trip) -> TripDescriptor {
+        TripDescriptor {
+            trip_id: Some(trip.orig_id.as_str().to_string()),
+            route_id: Some(trip.route_id.as_str().to_string()),
+            // Per the GTFS encoding, not our interpretation of it
+            direction_id: Some(if trip.outbound_direction { 0 } else { 1 }),
+            start_date: Some(self.model.date.format("%Y%m%d").to_string()),
+        }
+    }
+
+    // Only observed times have an uncertainty; we don't try to estimate error for predictions
+    fn stop_time_event(
+        &self,
+        time: Time,
+        scheduled: Time,
+        uncertainty: Option<Duration>,
+    ) -> StopTimeEvent {
+        let midnight = self.timestamp as i64 - self.time.inner_seconds() as i64;
+        StopTimeEvent {
+            delay: Some((time - scheduled).inner_seconds() as i32),
+            time: Some(midnight + time.inner_seconds() as i64),
+            uncertainty: uncertainty.map(|x| x.inner_seconds().round() as i32),
+        }
+    }
+}
+
+fn vehicle_descriptor(vehicle: &Vehicle) -> VehicleDescriptor {
+    VehicleDescriptor {
+        id: Some(vehicle.original_id.0.clone()),
+        label: None,
+    }
+}
+
+fn posix_timestamp(date: NaiveDate, time: Time, utc_offset: FixedOffset) -> u64 {
+    let local_midnight = date.and_hms_opt(0, 0, 0).unwrap().timestamp();
+    (local_midnight + time.inner_seconds() as i64 - utc_offset.local_minus_utc() as i64) as u64
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedMessage {
+    #[prost(message, required, tag = "1")]
+    pub header: FeedHeader,
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedHeader {
+    #[prost(string, required, tag = "1")]
+    pub gtfs_realtime_version: String,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedEntity {
+    #[prost(string, required, tag = "1")]
+    pub id: String,
+    #[prost(bool, optional, tag = "2")]
+    pub is_deleted: Option<bool>,
+    #[prost(message, optional, tag = "3")]
+    pub trip_update: Option<TripUpdate>,
+    #[prost(message, optional, tag = "4")]
+    pub vehicle: Option<VehiclePosition>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripUpdate {
+    #[prost(message, required, tag = "1")]
+    pub trip: TripDescriptor,
+    #[prost(message, optional, tag = "3")]
+    pub vehicle: Option<VehicleDescriptor>,
+    #[prost(message, repeated, tag = "2")]
+    pub stop_time_update: Vec<StopTimeUpdate>,
+    #[prost(uint64, optional, tag = "4")]
+    pub timestamp: Option<u64>,
+    #[prost(int32, optional, tag = "5")]
+    pub delay: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub stop_sequence: Option<u32>,
+    #[prost(string, optional, tag = "4")]
+    pub stop_id: Option<String>,
+    #[prost(message, optional, tag = "2")]
+    pub arrival: Option<StopTimeEvent>,
+    #[prost(message, optional, tag = "3")]
+    pub departure: Option<StopTimeEvent>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeEvent {
+    #[prost(int32, optional, tag = "1")]
+    pub delay: Option<i32>,
+    #[prost(int64, optional, tag = "2")]
+    pub time: Option<i64>,
+    #[prost(int32, optional, tag = "3")]
+    pub uncertainty: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct VehiclePosition {
+    #[prost(message, optional, tag = "1")]
+    pub trip: Option<TripDescriptor>,
+    #[prost(message, optional, tag = "8")]
+    pub vehicle: Option<VehicleDescriptor>,
+    #[prost(message, optional, tag = "2")]
+    pub position: Option<Position>,
+    #[prost(uint32, optional, tag = "3")]
+    pub current_stop_sequence: Option<u32>,
+    #[prost(string, optional, tag = "7")]
+    pub stop_id: Option<String>,
+    #[prost(enumeration = "VehicleStopStatus", optional, tag = "4")]
+    pub current_status: Option<i32>,
+    #[prost(uint64, optional, tag = "5")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum VehicleStopStatus {
+    IncomingAt = 0,
+    StoppedAt = 1,
+    InTransitTo = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Position {
+    #[prost(float, required, tag = "1")]
+    pub latitude: f32,
+    #[prost(float, required, tag = "2")]
+    pub longitude: f32,
+    #[prost(float, optional, tag = "3")]
+    pub bearing: Option<f32>,
+    #[prost(float, optional, tag = "5")]
+    pub speed: Option<f32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+    #[prost(uint32, optional, tag = "6")]
+    pub direction_id: Option<u32>,
+    #[prost(string, optional, tag = "3")]
+    pub start_date: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct VehicleDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub label: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::FixedOffset;
+    use geom::Pt2D;
+    use gtfs::{CheapID, RouteVariantID, StopID};
+
+    use super::*;
+    use crate::fixtures::{daily_model, gtfs, trajectory, RouteFixture};
+    use crate::{Timetable, VehicleID};
+
+    fn time(hours: usize, minutes: usize) -> Time {
+        Time::START_OF_DAY + Duration::hours(hours) + Duration::minutes(minutes)
+    }
+
+    fn event(stop: usize, arrival_time: Time) -> BoardingEvent {
+        BoardingEvent {
+            vehicle: VehicleID(0),
+            variant: RouteVariantID(0),
+            trip: CheapID::new(0),
+            stop: StopID::new(stop),
+            arrival_time,
+            departure_time: arrival_time,
+            uncertainty: Some(Duration::ZERO),
+            new_riders: Vec::new(),
+            transfers: Vec::new(),
+            confidence: None,
+            unscheduled: false,
+        }
+    }
+
+    fn served(trip: usize) -> ServedTrip {
+        ServedTrip {
+            trip: CheapID::new(trip),
+            variant: RouteVariantID(0),
+            unscheduled: false,
+        }
+    }
+
+    #[test]
+    fn trip_updates() {
+        // A loop starting and ending at stop 0, scheduled every 2 minutes from 8:00
+        let gtfs = gtfs(
+            &[
+                Pt2D::new(0.0, 0.0),
+                Pt2D::new(100.0, 0.0),
+                Pt2D::new(100.0, 100.0),
+            ],
+            vec![RouteFixture {
+                short_name: Some("1"),
+                stops: vec![0, 1, 2, 0],
+                start_time: time(8, 0),
+            }],
+        );
+        let mut model = daily_model(
+            gtfs,
+            vec![
+                trajectory(vec![(0.0, 0.0, 28_800.0), (100.0, 0.0, 29_400.0)]),
+                trajectory(vec![(0.0, 0.0, 28_800.0), (100.0, 0.0, 29_400.0)]),
+            ],
+        );
+        // The first bus runs a minute late and skips stop 1. The second one claims a trip
+        // missing from the GTFS.
+        model.vehicles[0].timetable = Timetable(vec![(time(8, 1), time(8, 7), served(0))]);
+        model.vehicles[1].timetable = Timetable(vec![(time(8, 1), time(8, 7), served(5))]);
+        model.boardings = vec![
+            event(0, time(8, 1)),
+            event(2, time(8, 5)),
+            event(0, time(8, 7)),
+        ];
+        // Stop 2 was passed between two sparse AVL points
+        model.boardings[1].uncertainty = Some(Duration::seconds(12.4));
+
+        let mut trips = BTreeMap::new();
+        for route in model.gtfs.routes.values() {
+            for trip in &route.variants[0].trips {
+                trips.insert(trip.id, trip);
+            }
+        }
+        let mut events_per_trip = BTreeMap::new();
+        events_per_trip.insert(CheapID::new(0), model.boardings.iter().collect());
+        let utc_offset = FixedOffset::east_opt(0).unwrap();
+        let snapshot = Snapshot {
+            model: &model,
+            trips: &trips,
+            events_per_trip: &events_per_trip,
+            time: time(8, 6),
+            timestamp: posix_timestamp(model.date, time(8, 6), utc_offset),
+        };
+
+        let feed = FeedMessage::decode(snapshot.trip_updates().encode_to_vec().as_slice()).unwrap();
+        assert_eq!(feed.entity.len(), 1);
+        assert_eq!(feed.entity[0].id, "bus0-trip0");
+        let update = feed.entity[0].trip_update.as_ref().unwrap();
+        // Stop, delay, and the uncertainty of observed arrivals
+        let stops: Vec<(String, i32, Option<i32>)> = update
+            .stop_time_update
+            .iter()
+            .map(|st| {
+                let arrival = st.arrival.as_ref().unwrap();
+                (
+                    st.stop_id.clone().unwrap(),
+                    arrival.delay.unwrap(),
+                    arrival.uncertainty,
+                )
+            })
+            .collect();
+        // Stop 1 was skipped, and the return to stop 0 hasn't happened yet, so both are predicted
+        // from the latest delay
+        assert_eq!(
+            stops,
+            vec![
+                ("stop0".to_string(), 60, Some(0)),
+                ("stop1".to_string(), 60, None),
+                ("stop2".to_string(), 60, Some(12)),
+                ("stop0".to_string(), 60, None),
+            ]
+        );
+        let midnight = posix_timestamp(model.date, Time::START_OF_DAY, utc_offset) as i64;
+        assert_eq!(
+            update.stop_time_update[3].arrival.as_ref().unwrap().time,
+            Some(midnight + 8 * 3600 + 7 * 60)
+        );
+    }
+}
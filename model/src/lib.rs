@@ -6,12 +6,19 @@ extern crate log;
 mod assemble;
 mod avl;
 mod experiments;
+mod export;
+#[cfg(test)]
+mod fixtures;
+mod format;
+mod gtfs_rt;
 mod multiday;
+mod shared_gtfs;
 mod ticketing;
 mod timetable;
 mod trajectory;
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
 
 use abstutil::Timer;
 use anyhow::Result;
@@ -22,10 +29,15 @@ use serde::{Deserialize, Serialize};
 use gtfs::{get_zip_file, IDMapping, GTFS};
 
 pub use self::assemble::*;
-pub use self::multiday::MultidayModel;
+pub use self::avl::{CleaningConfig, CleaningStats, KalmanConfig, RouteHint};
+pub use self::format::{Section, SCHEMA_VERSION};
+pub use self::multiday::{FeedVersion, MultidayModel};
+pub use self::shared_gtfs::SharedGtfs;
 pub use self::ticketing::{CardID, Journey, JourneyID, JourneyLeg};
 pub use self::timetable::Timetable;
-pub use self::trajectory::Trajectory;
+pub use self::trajectory::{
+    CompressionConfig, CompressionStats, LinearShape, ProjectedTrajectory, ShapeSample, Trajectory,
+};
 
 /// Detailed bus data for one day
 #[derive(Serialize, Deserialize)]
@@ -37,12 +49,19 @@ pub struct DailyModel {
     // TODO TiVec
     pub vehicles: Vec<Vehicle>,
     pub vehicle_ids: IDMapping<VehicleName, VehicleID>,
-    pub gtfs: GTFS,
+    // Shared between all days imported together
+    pub gtfs: Arc<GTFS>,
+    /// Identifies the GTFS feed. See SharedGtfs.
+    pub gtfs_hash: u64,
     pub journeys: Vec<Journey>,
 
     // TODO This is derived from other things, and may outright replace it at some point
     // Sorted by arrival time
     pub boardings: Vec<BoardingEvent>,
+    /// How boardings were matched
+    pub matching: MatchingConfig,
+    /// How well they were matched
+    pub matching_report: MatchingReport,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -50,13 +69,33 @@ pub struct Vehicle {
     pub id: VehicleID,
     pub original_id: VehicleName,
     pub trajectory: Trajectory,
+    // Only from some AVL sources
+    pub route_hints: Vec<RouteHint>,
+    pub cleaning: CleaningStats,
+    pub compression: CompressionStats,
     // Calculated
-    pub timetable: Timetable<gtfs::TripID>,
+    pub timetable: Timetable<ServedTrip>,
+    pub stages: Timetable<Stage>,
+}
+
+/// One run of a vehicle along a route variant, in its timetable
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServedTrip {
+    pub trip: gtfs::TripID,
+    pub variant: gtfs::RouteVariantID,
+    /// The run didn't match a scheduled trip, so `trip` is just the closest one
+    pub unscheduled: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct VehicleName(pub(crate) String);
 
+impl VehicleName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct VehicleID(pub usize);
 
@@ -66,80 +105,239 @@ impl gtfs::CheapID for VehicleID {
     }
 }
 
+/// Everything controlling how raw input is turned into models
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportConfig {
+    pub cleaning: CleaningConfig,
+    /// Lossy, so off by default. If None, keep every cleaned AVL point.
+    pub compression: Option<CompressionConfig>,
+    pub segmentation: SegmentationConfig,
+    pub matching: MatchingConfig,
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        Self {
+            cleaning: CleaningConfig::default(),
+            compression: None,
+            segmentation: SegmentationConfig::default(),
+            matching: MatchingConfig::default(),
+        }
+    }
+}
+
+/// What happened to each day found in an imported .zip
+#[derive(Default)]
+pub struct ImportReport {
+    pub imported: Vec<NaiveDate>,
+    /// Days that couldn't be imported, with the reason. The rest of the import still succeeds.
+    pub skipped: Vec<(NaiveDate, String)>,
+}
+
+impl ImportReport {
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Imported {} days, skipped {}",
+            self.imported.len(),
+            self.skipped.len()
+        )];
+        for (date, reason) in &self.skipped {
+            lines.push(format!("- {date}: {reason}"));
+        }
+        lines
+    }
+}
+
 impl DailyModel {
-    /// Returns a daily model for everything in the input .zip and the multiday summary
-    pub fn import_zip_bytes(bytes: Vec<u8>, timer: &mut Timer) -> Result<Vec<Self>> {
-        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))?;
+    /// Returns a daily model for every day in the input .zip that could be imported. Days are
+    /// imported in parallel, and ones that fail or crash are skipped and listed in the report.
+    /// Only problems with the input as a whole, like broken GTFS, are errors.
+    pub fn import_zip_bytes(
+        bytes: Vec<u8>,
+        config: &ImportConfig,
+        timer: &mut Timer,
+    ) -> Result<(Vec<Self>, ImportReport)> {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(&bytes[..]))?;
 
         timer.start("loading GTFS");
-        let (gtfs, gps_bounds) = GTFS::load_from_dir(&mut archive, timer)?;
+        let (gtfs, gps_bounds) =
+            GTFS::load_from_dir(&mut archive, config.matching.snap_threshold, timer)?;
+        let shared = SharedGtfs::new(gtfs);
         timer.stop("loading GTFS");
 
-        let avl_files = find_all_files(&archive, "avl/avl_");
-        let bil_files = find_all_files(&archive, "bil/bil_");
+        let depots = if let Ok(file) = get_zip_file(&mut archive, "depots.geojson") {
+            assemble::load_depots(file, &gps_bounds)?
+        } else {
+            Vec::new()
+        };
+
+        let mut avl_files = find_all_files(&archive, "avl/avl_", ".csv");
+        for (date, path) in find_all_files(&archive, "avl/siri_vm_", ".xml") {
+            if let Some(csv) = avl_files.insert(date, path) {
+                bail!("Both {csv} and SIRI-VM AVL for {date} exist; only one can be used");
+            }
+        }
+        let bil_files = find_all_files(&archive, "bil/bil_", ".csv");
         let daily_input_files = find_common_files(avl_files, bil_files);
 
+        let num_days = daily_input_files.len();
         let mut output_models = Vec::new();
-
-        for (date, avl_path, bil_path) in daily_input_files {
-            timer.start(format!("import daily data for {date}"));
-            let mut vehicles = Vec::new();
-            let mut vehicle_ids = IDMapping::new();
-
-            timer.start("loading AVL");
-            for (original_id, trajectory) in
-                avl::load_trajectories(get_zip_file(&mut archive, &avl_path)?, &gps_bounds, date)?
-            {
-                let id = vehicle_ids.insert_new(original_id.clone())?;
-                vehicles.push(Vehicle {
-                    id,
-                    original_id,
-                    trajectory,
-                    timetable: Timetable::new(),
+        let mut report = ImportReport::default();
+        timer.start_iter("import daily data", num_days);
+        parallel_for_each(
+            daily_input_files,
+            |(date, avl_path, bil_path)| {
+                // Each day only decompresses its own input, so a long import doesn't hold every
+                // day's raw input at once. Cloning the archive is cheap; it shares the bytes.
+                let result = catch_panic(|| {
+                    let mut archive = archive.clone();
+                    let input = DailyInput {
+                        date,
+                        avl: read_zip_file(&mut archive, &avl_path)?,
+                        bil: read_zip_file(&mut archive, &bil_path)?,
+                        avl_path,
+                    };
+                    Self::import_day(
+                        input,
+                        &gps_bounds,
+                        &shared,
+                        &depots,
+                        config,
+                        &mut Timer::throwaway(),
+                    )
                 });
-            }
-            timer.stop("loading AVL");
-
-            timer.start("loading BIL");
-            let journeys = ticketing::load_journeys(
-                get_zip_file(&mut archive, &bil_path)?,
-                &gps_bounds,
-                date,
-            )?;
-            timer.stop("loading BIL");
-
-            let mut model = Self {
-                bounds: gps_bounds.to_bounds(),
-                gps_bounds: gps_bounds.clone(),
-                vehicles,
-                vehicle_ids,
-                gtfs: gtfs.clone(),
-                journeys,
-                boardings: Vec::new(),
-                date: date,
-            };
-            assemble::populate_boarding(&mut model, timer)?;
+                (date, result)
+            },
+            |(date, result)| {
+                timer.next();
+                match result {
+                    Ok(model) => {
+                        report.imported.push(date);
+                        output_models.push(model);
+                    }
+                    Err(err) => {
+                        warn!("Skipping {date}: {err}");
+                        report.skipped.push((date, err.to_string()));
+                    }
+                }
+            },
+        );
+        // Days finish in any order
+        report.imported.sort();
+        report.skipped.sort_by_key(|(date, _)| *date);
+        output_models.sort_by_key(|model| model.date);
 
-            output_models.push(model);
-            timer.stop(format!("import daily data for {date}"));
+        if num_days > 0 && output_models.is_empty() {
+            bail!(
+                "None of the {num_days} days could be imported:\n{}",
+                report.describe().join("\n")
+            );
         }
 
         if output_models.is_empty() {
             // An empty GTFS-only model
-            let date = gtfs.calendar.services.values().next().unwrap().start_date;
+            let date = shared
+                .gtfs
+                .calendar
+                .services
+                .values()
+                .next()
+                .ok_or_else(|| anyhow!("No AVL and ticketing data, and the GTFS has no calendar"))?
+                .start_date;
             output_models.push(Self {
                 bounds: gps_bounds.to_bounds(),
                 gps_bounds,
                 vehicles: Vec::new(),
                 vehicle_ids: IDMapping::new(),
-                gtfs,
+                gtfs: shared.gtfs,
+                gtfs_hash: shared.hash,
                 journeys: Vec::new(),
                 boardings: Vec::new(),
+                matching: config.matching.clone(),
+                matching_report: MatchingReport::default(),
                 date,
             });
         }
 
-        Ok(output_models)
+        Ok((output_models, report))
+    }
+
+    fn import_day(
+        input: DailyInput,
+        gps_bounds: &GPSBounds,
+        shared: &SharedGtfs,
+        depots: &[Depot],
+        config: &ImportConfig,
+        timer: &mut Timer,
+    ) -> Result<Self> {
+        let DailyInput {
+            date,
+            avl_path,
+            avl,
+            bil,
+        } = input;
+        let mut vehicles = Vec::new();
+        let mut vehicle_ids = IDMapping::new();
+
+        timer.start("loading AVL");
+        let (trajectories, mut route_hints) = if avl_path.ends_with(".xml") {
+            avl::siri::load(&avl[..], gps_bounds, date, &config.cleaning)?
+        } else {
+            (
+                avl::load_trajectories(&avl[..], gps_bounds, date, &config.cleaning)?,
+                BTreeMap::new(),
+            )
+        };
+        for (original_id, (trajectory, cleaning)) in trajectories {
+            let id = vehicle_ids.insert_new(original_id.clone())?;
+            let (trajectory, compression) = match config.compression {
+                Some(ref compression) => trajectory.compress(compression),
+                None => {
+                    let points = trajectory.num_points();
+                    (
+                        trajectory,
+                        CompressionStats {
+                            original_points: points,
+                            compressed_points: points,
+                            ..Default::default()
+                        },
+                    )
+                }
+            };
+            vehicles.push(Vehicle {
+                id,
+                route_hints: route_hints.remove(&original_id).unwrap_or_else(Vec::new),
+                original_id,
+                trajectory,
+                cleaning,
+                compression,
+                timetable: Timetable::new(),
+                stages: Timetable::new(),
+            });
+        }
+        timer.stop("loading AVL");
+
+        timer.start("loading BIL");
+        let journeys = ticketing::load_journeys(&bil[..], gps_bounds, date, &config.matching)?;
+        timer.stop("loading BIL");
+
+        let mut model = Self {
+            bounds: gps_bounds.to_bounds(),
+            gps_bounds: gps_bounds.clone(),
+            vehicles,
+            vehicle_ids,
+            gtfs: shared.gtfs.clone(),
+            gtfs_hash: shared.hash,
+            journeys,
+            boardings: Vec::new(),
+            matching: config.matching.clone(),
+            matching_report: MatchingReport::default(),
+            date,
+        };
+        assemble::populate_boarding(&mut model, timer)?;
+        assemble::segment_vehicles(&mut model, depots, &config.segmentation, timer);
+        Ok(model)
     }
 
     pub fn empty() -> Self {
@@ -149,9 +347,12 @@ impl DailyModel {
             gps_bounds: GPSBounds::new(),
             vehicles: Vec::new(),
             vehicle_ids: IDMapping::new(),
-            gtfs: GTFS::empty(),
+            gtfs: Arc::new(GTFS::empty()),
+            gtfs_hash: 0,
             journeys: Vec::new(),
             boardings: Vec::new(),
+            matching: MatchingConfig::default(),
+            matching_report: MatchingReport::default(),
             date: NaiveDate::from_ymd(2020, 1, 1),
         }
     }
@@ -162,14 +363,101 @@ impl DailyModel {
     }
 }
 
+// The raw input for one day, read from the .zip
+struct DailyInput {
+    date: NaiveDate,
+    avl_path: String,
+    avl: Vec<u8>,
+    bil: Vec<u8>,
+}
+
+// Runs `f` on every input using all cores, calling `on_result` on this thread as each one
+// finishes. Unlike `Timer::parallelize`, the caller sees results as they arrive, to report
+// progress.
+fn parallel_for_each<I: Send, O: Send>(
+    inputs: Vec<I>,
+    f: impl Fn(I) -> O + Sync,
+    mut on_result: impl FnMut(O),
+) {
+    // No threads in the browser
+    #[cfg(target_arch = "wasm32")]
+    {
+        for input in inputs {
+            on_result(f(input));
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let num_threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(inputs.len())
+            .max(1);
+        let queue = std::sync::Mutex::new(inputs.into_iter());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            for _ in 0..num_threads {
+                let tx = tx.clone();
+                let (queue, f) = (&queue, &f);
+                scope.spawn(move || loop {
+                    let input = queue.lock().unwrap().next();
+                    match input {
+                        Some(input) => {
+                            if tx.send(f(input)).is_err() {
+                                break;
+                            }
+                        }
+                        None => break,
+                    }
+                });
+            }
+            drop(tx);
+            for result in rx {
+                on_result(result);
+            }
+        });
+    }
+}
+
+// A bug while importing one day shouldn't lose the others. The panic still gets logged as usual.
+// (In the browser, panics abort, so this doesn't help there.)
+fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "unknown reason".to_string()
+            };
+            bail!("crashed: {msg}")
+        }
+    }
+}
+
+fn read_zip_file<R: std::io::Read + std::io::Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    path: &str,
+) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut bytes = Vec::new();
+    get_zip_file(archive, path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
 fn find_all_files<R: std::io::Read + std::io::Seek>(
     archive: &zip::ZipArchive<R>,
     prefix: &str,
+    suffix: &str,
 ) -> BTreeMap<NaiveDate, String> {
     let mut results = BTreeMap::new();
     for file_name in archive.file_names() {
         if let Some(x) = file_name.strip_prefix(prefix) {
-            if let Some(x) = x.strip_suffix(".csv") {
+            if let Some(x) = x.strip_suffix(suffix) {
                 if let Ok(date) = NaiveDate::parse_from_str(x, "%Y-%m-%d") {
                     results.insert(date, file_name.to_string());
                 }
@@ -1,3 +1,6 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use abstutil::Counter;
 use anyhow::Result;
 use chrono::NaiveDate;
@@ -11,46 +14,128 @@ use crate::{BoardingEvent, DailyModel, VehicleID, VehicleName};
 /// Summarizes bus data for many days.
 #[derive(Serialize, Deserialize)]
 pub struct MultidayModel {
+    // These match the newest feed version
     pub bounds: Bounds,
     pub gps_bounds: GPSBounds,
-    pub gtfs: GTFS,
+    /// The newest feed version. IDs in boardings only refer to this feed for days within its date
+    /// range; use `feed_for_day` otherwise.
+    pub gtfs: Arc<GTFS>,
+    /// Sorted by date. The date ranges don't overlap.
+    pub feeds: Vec<FeedVersion>,
 
-    // The list of days is sorted. Boardings per day are sorted by arrival time
+    // The list of days is sorted. Boardings per day are sorted by arrival time. Stop, variant, and
+    // trip IDs refer to the feed version for that day.
     pub boardings_per_day: Vec<(NaiveDate, Vec<BoardingEvent>)>,
     pub vehicle_ids: IDMapping<VehicleName, VehicleID>,
     // TODO Include journeys too, probably. But re-express on top of the BoardingEvents / don't
     // store the route name and vehicle again.
 }
 
+/// One version of the GTFS feed, used by every day in a range. Over a long period, the feed changes
+/// and the same stop or route may get a different ID.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FeedVersion {
+    pub gtfs: Arc<GTFS>,
+    pub hash: u64,
+    // Each import has its own bounds, so stop positions are only comparable within one feed
+    pub gps_bounds: GPSBounds,
+    // Inclusive
+    pub first_day: NaiveDate,
+    pub last_day: NaiveDate,
+}
+
 impl MultidayModel {
-    // Assumes at least 1 input and that the inputs all have the same bounds / GTFS data
-    pub fn new_from_daily_models(models: &Vec<DailyModel>) -> Self {
-        let mut output = Self {
-            bounds: models[0].bounds.clone(),
-            gps_bounds: models[0].gps_bounds.clone(),
-            gtfs: models[0].gtfs.clone(),
+    /// Fails if days using different GTFS feeds are interleaved
+    pub fn new_from_daily_models(models: &[DailyModel]) -> Result<Self> {
+        let mut output = Self::empty();
+        for model in models {
+            output.add_day(model)?;
+        }
+        Ok(output)
+    }
 
-            boardings_per_day: Vec::new(),
-            vehicle_ids: IDMapping::new(),
-        };
+    /// Adds the boardings from one day. If that day is already present, it's replaced. A day with a
+    /// new GTFS feed starts a new feed version, but it can't fall between days using another feed.
+    pub fn add_day(&mut self, model: &DailyModel) -> Result<()> {
+        self.add_feed_for_day(model)?;
 
-        for model in models {
-            let mut events = Vec::new();
-            for ev in &model.boardings {
-                // Vehicle ID assignment may change each day, so calculate again from the original
-                // VehicleName
-                let vehicle_name = &model.vehicles[ev.vehicle.0].original_id;
-                let vehicle_id = output.vehicle_ids.insert_idempotent(vehicle_name);
-                let mut ev = ev.clone();
-                ev.vehicle = vehicle_id;
-                events.push(ev);
+        // Vehicles might not be loaded (see Section), so use the ID mapping
+        let vehicle_names: BTreeMap<VehicleID, &VehicleName> = model
+            .vehicle_ids
+            .borrow()
+            .iter()
+            .map(|(name, id)| (*id, name))
+            .collect();
+        let mut events = Vec::new();
+        for ev in &model.boardings {
+            // Vehicle ID assignment may change each day, so calculate again from the original
+            // VehicleName
+            let vehicle_name = vehicle_names[&ev.vehicle];
+            let vehicle_id = self.vehicle_ids.insert_idempotent(vehicle_name);
+            let mut ev = ev.clone();
+            ev.vehicle = vehicle_id;
+            events.push(ev);
+        }
+
+        match self
+            .boardings_per_day
+            .binary_search_by_key(&model.date, |(d, _)| *d)
+        {
+            Ok(idx) => {
+                info!("Replacing existing boardings for {}", model.date);
+                self.boardings_per_day[idx].1 = events;
+            }
+            Err(idx) => {
+                self.boardings_per_day.insert(idx, (model.date, events));
             }
+        }
+        Ok(())
+    }
 
-            output.boardings_per_day.push((model.date, events));
+    fn add_feed_for_day(&mut self, model: &DailyModel) -> Result<()> {
+        let date = model.date;
+        let hash = model.gtfs_hash;
+        let (first_day, last_day) = match self.feeds.iter().find(|f| f.hash == hash) {
+            Some(feed) => (feed.first_day.min(date), feed.last_day.max(date)),
+            None => (date, date),
+        };
+        if let Some(other) = self
+            .feeds
+            .iter()
+            .find(|f| f.hash != hash && f.first_day <= last_day && first_day <= f.last_day)
+        {
+            bail!(
+                "{} was imported with GTFS {:x}, but days from {} to {} use GTFS {:x}. Each feed version must cover separate dates.",
+                date,
+                hash,
+                other.first_day,
+                other.last_day,
+                other.hash
+            );
         }
-        output.boardings_per_day.sort_by_key(|(d, _)| *d);
 
-        output
+        if let Some(feed) = self.feeds.iter_mut().find(|f| f.hash == hash) {
+            feed.first_day = first_day;
+            feed.last_day = last_day;
+        } else {
+            if !self.feeds.is_empty() {
+                info!("{} starts a new GTFS feed version {:x}", date, hash);
+            }
+            self.feeds.push(FeedVersion {
+                gtfs: model.gtfs.clone(),
+                hash,
+                gps_bounds: model.gps_bounds.clone(),
+                first_day,
+                last_day,
+            });
+            self.feeds.sort_by_key(|f| f.first_day);
+        }
+        if self.feeds.last().unwrap().hash == hash {
+            self.bounds = model.bounds.clone();
+            self.gps_bounds = model.gps_bounds.clone();
+            self.gtfs = model.gtfs.clone();
+        }
+        Ok(())
     }
 
     pub fn empty() -> Self {
@@ -58,22 +143,76 @@ impl MultidayModel {
             // Avoid crashing the UI with empty bounds
             bounds: Bounds::from(&[Pt2D::zero(), Pt2D::new(1.0, 1.0)]),
             gps_bounds: GPSBounds::new(),
-            gtfs: GTFS::empty(),
+            gtfs: Arc::new(GTFS::empty()),
+            feeds: Vec::new(),
             boardings_per_day: Vec::new(),
             vehicle_ids: IDMapping::new(),
         }
     }
 
-    pub fn count_boardings_by_stop(&self) -> Counter<StopID> {
+    /// The feed version that IDs in a day's boardings refer to
+    pub fn feed_for_day(&self, date: NaiveDate) -> Option<&FeedVersion> {
+        self.feeds
+            .iter()
+            .find(|f| f.first_day <= date && date <= f.last_day)
+    }
+
+    /// Total boardings over all days, per stop in the newest feed. Stops are matched across feed
+    /// versions by their original ID. Boardings with a confidence score below `min_confidence` are
+    /// skipped.
+    pub fn count_boardings_by_stop(&self, min_confidence: f64) -> Counter<StopID> {
+        let by_orig = self.count_boardings_by_orig_stop(min_confidence);
         let mut cnt = Counter::new();
-        for (_, events) in &self.boardings_per_day {
-            for ev in events {
-                cnt.add(ev.stop, ev.new_riders.len() + ev.transfers.len());
-            }
+        for stop in self.gtfs.stops.values() {
+            cnt.add(stop.id, by_orig.get(stop.orig_id.clone()));
         }
         cnt
     }
 
+    /// Total boardings over all days, per original stop ID from any feed version
+    pub fn count_boardings_by_orig_stop(&self, min_confidence: f64) -> Counter<orig::StopID> {
+        let mut cnt = Counter::new();
+        self.for_each_boarding(min_confidence, |gtfs, ev| {
+            cnt.add(
+                gtfs.stops[&ev.stop].orig_id.clone(),
+                ev.new_riders.len() + ev.transfers.len(),
+            );
+        });
+        cnt
+    }
+
+    /// Total boardings over all days, per route short name from any feed version and the hour of
+    /// arrival. Routes without a short name use their route ID.
+    pub fn count_boardings_by_route_name_per_hour(
+        &self,
+        min_confidence: f64,
+    ) -> Counter<(String, usize)> {
+        let mut cnt = Counter::new();
+        self.for_each_boarding(min_confidence, |gtfs, ev| {
+            cnt.add(
+                (route_name(gtfs, ev.variant), ev.arrival_time.get_hours()),
+                ev.new_riders.len() + ev.transfers.len(),
+            );
+        });
+        cnt
+    }
+
+    // Days outside every feed version's range can't be interpreted, so they're skipped
+    fn for_each_boarding<F: FnMut(&GTFS, &BoardingEvent)>(&self, min_confidence: f64, mut f: F) {
+        for (date, events) in &self.boardings_per_day {
+            let gtfs = match self.feed_for_day(*date) {
+                Some(feed) => &feed.gtfs,
+                None => {
+                    warn!("No GTFS feed version covers {date}; skipping its boardings");
+                    continue;
+                }
+            };
+            for ev in events.iter().filter(|ev| ev.is_confident(min_confidence)) {
+                f(gtfs, ev);
+            }
+        }
+    }
+
     pub fn export_to_csv(&self) -> Result<String> {
         let mut vehicle_ids: Vec<VehicleName> =
             std::iter::repeat_with(|| VehicleName(String::new()))
@@ -87,22 +226,29 @@ impl MultidayModel {
         {
             let mut writer = csv::Writer::from_writer(&mut out);
             for (date, events) in &self.boardings_per_day {
+                let gtfs = &self
+                    .feed_for_day(*date)
+                    .ok_or_else(|| anyhow!("No GTFS feed version covers {date}"))?
+                    .gtfs;
                 for ev in events {
-                    let route = self.gtfs.parent_of_variant(ev.variant);
-                    let variant = self.gtfs.variant(ev.variant);
+                    let route = gtfs.parent_of_variant(ev.variant);
+                    let variant = gtfs.variant(ev.variant);
                     let trip = variant.trips.iter().find(|t| t.id == ev.trip).unwrap();
 
                     writer.serialize(ExportBoardingRow {
                         date: *date,
                         vehicle: vehicle_ids[ev.vehicle.0].clone(),
                         route_id: route.route_id.clone(),
+                        route_name: route_name(gtfs, ev.variant),
                         route_variant: ev.variant,
                         trip: trip.orig_id.clone(),
-                        stop: self.gtfs.stops[&ev.stop].orig_id.clone(),
+                        stop: gtfs.stops[&ev.stop].orig_id.clone(),
                         arrival_time: ev.arrival_time,
                         departure_time: ev.departure_time,
                         new_riders: ev.new_riders.len(),
                         transfers: ev.transfers.len(),
+                        confidence: ev.confidence.map(|c| c.score()),
+                        unscheduled: ev.unscheduled,
                     })?;
                 }
             }
@@ -118,6 +264,7 @@ struct ExportBoardingRow {
     date: NaiveDate,
     vehicle: VehicleName,
     route_id: RouteID,
+    route_name: String,
     route_variant: RouteVariantID,
     trip: orig::TripID,
     stop: orig::StopID,
@@ -125,4 +272,16 @@ struct ExportBoardingRow {
     departure_time: Time,
     new_riders: usize,
     transfers: usize,
+    // Blank if unknown
+    confidence: Option<f64>,
+    // If true, `trip` is only the closest scheduled trip
+    unscheduled: bool,
+}
+
+fn route_name(gtfs: &GTFS, variant: RouteVariantID) -> String {
+    let route = gtfs.parent_of_variant(variant);
+    route
+        .short_name
+        .clone()
+        .unwrap_or_else(|| route.route_id.as_str().to_string())
 }
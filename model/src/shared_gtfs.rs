@@ -0,0 +1,69 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use gtfs::GTFS;
+
+/// One GTFS feed, shared by many daily models instead of copied into each. The hash identifies the
+/// contents of the feed, so saved models can refer to it instead of including it.
+#[derive(Clone)]
+pub struct SharedGtfs {
+    pub gtfs: Arc<GTFS>,
+    pub hash: u64,
+}
+
+impl SharedGtfs {
+    pub fn new(gtfs: GTFS) -> Self {
+        let hash = hash_gtfs(&gtfs);
+        Self {
+            gtfs: Arc::new(gtfs),
+            hash,
+        }
+    }
+
+    /// Models saved without their GTFS feed have it in the same directory, in `gtfs-{hash}.bin`
+    /// (see `file_name`) or, from older imports, `gtfs.bin`. Only the feeds the model refers to
+    /// are loaded; models embedding their feed may have none.
+    pub fn load_next_to(model_path: &str, model_bytes: &[u8]) -> Result<Vec<Self>> {
+        let dir = match Path::new(model_path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut feeds = Vec::new();
+        for hash in crate::format::gtfs_hashes(model_bytes)? {
+            let mut path = dir.join(file_name(hash));
+            if !path.exists() {
+                path = dir.join("gtfs.bin");
+                if !path.exists() {
+                    continue;
+                }
+            }
+            let bytes = std::fs::read(&path).map_err(|err| anyhow!("{}: {err}", path.display()))?;
+            let feed = Self::from_file_bytes(&bytes)?;
+            if feed.hash == hash {
+                feeds.push(feed);
+            }
+        }
+        Ok(feeds)
+    }
+
+    /// Where to save this feed, so that `load_next_to` finds it
+    pub fn file_name(&self) -> String {
+        file_name(self.hash)
+    }
+}
+
+fn file_name(hash: u64) -> String {
+    format!("gtfs-{:016x}.bin", hash)
+}
+
+/// FNV-1a over the serialized feed. Maps in GTFS are all BTreeMaps, so this is deterministic.
+fn hash_gtfs(gtfs: &GTFS) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in abstutil::to_binary(gtfs) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
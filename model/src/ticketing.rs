@@ -5,7 +5,7 @@ use chrono::{NaiveDate, NaiveDateTime, Timelike};
 use geom::{Duration, GPSBounds, LonLat, Pt2D, Time};
 use serde::{Deserialize, Serialize};
 
-use crate::VehicleName;
+use crate::{MatchingConfig, VehicleName};
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct CardID(String);
@@ -33,6 +33,7 @@ pub fn load_journeys<R: std::io::Read>(
     reader: R,
     gps_bounds: &GPSBounds,
     expected_date: NaiveDate,
+    config: &MatchingConfig,
 ) -> Result<Vec<Journey>> {
     let mut per_card: BTreeMap<CardID, Vec<JourneyLeg>> = BTreeMap::new();
 
@@ -67,7 +68,10 @@ pub fn load_journeys<R: std::io::Read>(
             });
     }
 
-    Ok(per_card.into_iter().flat_map(split_into_journeys).collect())
+    Ok(per_card
+        .into_iter()
+        .flat_map(|(card_id, legs)| split_into_journeys(card_id, legs, config))
+        .collect())
 }
 
 #[derive(Deserialize)]
@@ -86,8 +90,12 @@ struct BIL {
     longitude: f64,
 }
 
-// A passenger can board up to four buses in a two-hour window
-fn split_into_journeys((card_id, mut legs): (CardID, Vec<JourneyLeg>)) -> Vec<Journey> {
+// A passenger can board up to four buses in a two-hour window, by default
+fn split_into_journeys(
+    card_id: CardID,
+    mut legs: Vec<JourneyLeg>,
+    config: &MatchingConfig,
+) -> Vec<Journey> {
     legs.sort_by_key(|leg| leg.time);
 
     let mut journeys = Vec::new();
@@ -96,7 +104,9 @@ fn split_into_journeys((card_id, mut legs): (CardID, Vec<JourneyLeg>)) -> Vec<Jo
         // TODO How's the two-hour window defined -- starting from the first event, or the most
         // recent? (Can somebody ride for a total of 10 hours, with 90 minutes between each
         // ticket?)
-        if current_legs.len() < 4 && leg.time - current_legs[0].time < Duration::hours(2) {
+        if current_legs.len() < config.max_journey_legs
+            && leg.time - current_legs[0].time < config.journey_window
+        {
             current_legs.push(leg);
         } else {
             journeys.push(Journey {
@@ -1,8 +1,6 @@
 use geom::Time;
 use serde::{Deserialize, Serialize};
 
-// TODO Unit test
-
 /// A sequence of something, with non-overlapping and sorted time intervals.
 ///
 /// Intervals are "open", aka, it's fine for one interval to end right at 7am and the next to the
@@ -33,6 +31,60 @@ impl<T> Timetable<T> {
         }
     }
 
+    /// Picks the non-overlapping subset of `candidates` with the most total weight, using dynamic
+    /// programming over intervals sorted by end time. Returns the chosen intervals and everything
+    /// else. Intervals with zero or negative weight are never chosen.
+    pub fn max_weight(
+        mut candidates: Vec<(Time, Time, f64, T)>,
+    ) -> (Self, Vec<(Time, Time, f64, T)>) {
+        candidates.sort_by_key(|(t1, t2, _, _)| (*t2, *t1));
+        let ends: Vec<Time> = candidates.iter().map(|(_, t2, _, _)| *t2).collect();
+
+        // best[i] is the most weight possible using only the first i candidates. Candidate j can
+        // follow any of the first compatible[j] candidates, since those all end before it starts.
+        let mut best = vec![0.0; candidates.len() + 1];
+        let mut compatible = Vec::new();
+        for (j, (t1, _, weight, _)) in candidates.iter().enumerate() {
+            let p = ends[..j].partition_point(|end| *end <= *t1);
+            compatible.push(p);
+            best[j + 1] = f64::max(best[j], weight + best[p]);
+        }
+
+        let mut chosen = vec![false; candidates.len()];
+        let mut j = candidates.len();
+        while j > 0 {
+            let weight = candidates[j - 1].2;
+            if weight > 0.0 && weight + best[compatible[j - 1]] >= best[j - 1] {
+                chosen[j - 1] = true;
+                j = compatible[j - 1];
+            } else {
+                j -= 1;
+            }
+        }
+
+        let mut timetable = Self::new();
+        let mut rejected = Vec::new();
+        for (candidate, keep) in candidates.into_iter().zip(chosen) {
+            if keep {
+                // Already sorted and non-overlapping
+                timetable.0.push((candidate.0, candidate.1, candidate.3));
+            } else {
+                rejected.push(candidate);
+            }
+        }
+        (timetable, rejected)
+    }
+
+    /// Everything overlapping the interval. Touching at the ends doesn't count, but an identical
+    /// interval does.
+    pub fn overlapping(&self, check: (Time, Time)) -> Vec<&T> {
+        self.0
+            .iter()
+            .filter(|(t1, t2, _)| check.0 < *t2 && *t1 < check.1)
+            .map(|(_, _, obj)| obj)
+            .collect()
+    }
+
     /// If this is right on the boundary of two intervals, the first is returned
     pub fn get_at_time(&self, time: Time) -> Option<&T> {
         for (t1, t2, obj) in &self.0 {
@@ -54,3 +106,67 @@ fn overlaps(pair1: (Time, Time), pair2: (Time, Time)) -> bool {
         || contains(pair2.0, pair1)
         || contains(pair2.1, pair1)
 }
+
+#[cfg(test)]
+mod tests {
+    use geom::Duration;
+
+    use super::*;
+
+    fn t(minutes: usize) -> Time {
+        Time::START_OF_DAY + Duration::minutes(minutes)
+    }
+
+    fn labels(timetable: &Timetable<&'static str>) -> Vec<&'static str> {
+        timetable.0.iter().map(|(_, _, x)| *x).collect()
+    }
+
+    #[test]
+    fn max_weight_beats_greedy() {
+        // Taking the heaviest interval first would block the other two
+        let (timetable, rejected) = Timetable::max_weight(vec![
+            (t(0), t(10), 5.0, "long"),
+            (t(0), t(4), 3.0, "first"),
+            (t(5), t(10), 3.0, "second"),
+        ]);
+        assert_eq!(labels(&timetable), vec!["first", "second"]);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].3, "long");
+    }
+
+    #[test]
+    fn max_weight_skips_worthless_intervals() {
+        let (timetable, rejected) = Timetable::max_weight(vec![
+            (t(0), t(1), 0.0, "zero"),
+            (t(2), t(3), -1.0, "negative"),
+            (t(4), t(5), 1.0, "positive"),
+        ]);
+        assert_eq!(labels(&timetable), vec!["positive"]);
+        let rejected: Vec<&str> = rejected.into_iter().map(|(_, _, _, x)| x).collect();
+        assert_eq!(rejected, vec!["zero", "negative"]);
+    }
+
+    #[test]
+    fn max_weight_touching_intervals() {
+        let (timetable, rejected) = Timetable::max_weight(vec![
+            (t(5), t(10), 1.0, "second"),
+            (t(0), t(5), 1.0, "first"),
+        ]);
+        assert_eq!(labels(&timetable), vec!["first", "second"]);
+        assert!(rejected.is_empty());
+        assert_eq!(timetable.get_at_time(t(5)), Some(&"first"));
+        assert!(timetable.is_free((t(10), t(15))));
+        assert!(!timetable.is_free((t(9), t(15))));
+
+        assert_eq!(timetable.overlapping((t(0), t(5))), vec![&"first"]);
+        assert_eq!(
+            timetable.overlapping((t(4), t(6))),
+            vec![&"first", &"second"]
+        );
+        assert!(timetable.overlapping((t(10), t(15))).is_empty());
+
+        let (timetable, rejected) = Timetable::<&str>::max_weight(Vec::new());
+        assert!(timetable.0.is_empty());
+        assert!(rejected.is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+use geom::{Distance, Duration, Pt2D, Time};
+use serde::{Deserialize, Serialize};
+
+use super::Trajectory;
+
+// Raw trajectories have a point every few seconds, even when the vehicle sits at a terminal for
+// half an hour. Keep only the points needed to reproduce the original within some error.
+//
+// Stationary periods become dwells: two points at the same position, at the start and end of the
+// period. Everything else is simplified with a space-time Douglas-Peucker, measuring the error of
+// a dropped point against where the simplified trajectory says the vehicle was at that same time
+// (the synchronized Euclidean distance). So `interpolate` on the result is never further than
+// `max_error` from the original at any input time, except during dwells. A dwell's points are all
+// within `dwell_radius` of its first point, but it's kept at their center, so they may be up to
+// twice `dwell_radius` away from it.
+
+/// Controls lossy trajectory compression
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Dropped points are at most this far from the simplified trajectory at the same time
+    pub max_error: Distance,
+    /// A vehicle staying within this distance of one spot... During a dwell, the compressed
+    /// trajectory may be up to twice this far from the original.
+    pub dwell_radius: Distance,
+    /// ...for at least this long is considered to be dwelling
+    pub min_dwell: Duration,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            max_error: Distance::meters(5.0),
+            dwell_radius: Distance::meters(5.0),
+            min_dwell: Duration::seconds(30.0),
+        }
+    }
+}
+
+/// What compression did to one trajectory
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct CompressionStats {
+    pub original_points: usize,
+    pub compressed_points: usize,
+    pub dwells: usize,
+    /// The largest distance between a dropped point and the compressed trajectory
+    pub max_error: Distance,
+}
+
+impl CompressionStats {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} points -> {} ({} dwells), max error {}",
+            self.original_points, self.compressed_points, self.dwells, self.max_error
+        )
+    }
+}
+
+impl Trajectory {
+    pub fn compress(&self, config: &CompressionConfig) -> (Trajectory, CompressionStats) {
+        let mut stats = CompressionStats {
+            original_points: self.inner.len(),
+            ..Default::default()
+        };
+
+        // First collapse dwells. Each one becomes two points that must be kept.
+        let mut pts: Vec<(Pt2D, Time)> = Vec::new();
+        let mut keep: Vec<bool> = Vec::new();
+        let mut idx = 0;
+        while idx < self.inner.len() {
+            let end = self.dwell_end(idx, config.dwell_radius);
+            let (start_pt, start_time) = self.inner[idx];
+            let end_time = self.inner[end].1;
+            if end > idx && end_time - start_time >= config.min_dwell {
+                let center = Pt2D::center(
+                    &self.inner[idx..=end]
+                        .iter()
+                        .map(|(pt, _)| *pt)
+                        .collect::<Vec<_>>(),
+                );
+                for (pt, _) in &self.inner[idx..=end] {
+                    stats.max_error = stats.max_error.max(pt.dist_to(center));
+                }
+                pts.push((center, start_time));
+                pts.push((center, end_time));
+                keep.push(true);
+                keep.push(true);
+                stats.dwells += 1;
+                idx = end + 1;
+            } else {
+                pts.push((start_pt, start_time));
+                keep.push(false);
+                idx += 1;
+            }
+        }
+        keep[0] = true;
+        *keep.last_mut().unwrap() = true;
+
+        // Then simplify between every pair of fixed points
+        let anchors: Vec<usize> = (0..pts.len()).filter(|i| keep[*i]).collect();
+        for pair in anchors.windows(2) {
+            let mut stack = vec![(pair[0], pair[1])];
+            while let Some((i, j)) = stack.pop() {
+                let mut worst: Option<(usize, Distance)> = None;
+                for k in (i + 1)..j {
+                    let err = pts[k].0.dist_to(synchronized_pt(pts[i], pts[j], pts[k].1));
+                    if worst.map(|(_, e)| err > e).unwrap_or(true) {
+                        worst = Some((k, err));
+                    }
+                }
+                match worst {
+                    Some((k, err)) if err > config.max_error => {
+                        keep[k] = true;
+                        stack.push((i, k));
+                        stack.push((k, j));
+                    }
+                    Some((_, err)) => {
+                        stats.max_error = stats.max_error.max(err);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        let inner: Vec<(Pt2D, Time)> = pts
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(pair, keep)| if keep { Some(pair) } else { None })
+            .collect();
+        stats.compressed_points = inner.len();
+        (Trajectory::from_inner(inner), stats)
+    }
+
+    /// Returns every dwell as (position, start, end): consecutive points at the same position
+    pub fn dwells(&self) -> Vec<(Pt2D, Time, Time)> {
+        let mut results = Vec::new();
+        for pair in self.inner.windows(2) {
+            if pair[0].0 == pair[1].0 && pair[0].1 < pair[1].1 {
+                results.push((pair[0].0, pair[0].1, pair[1].1));
+            }
+        }
+        results
+    }
+
+    // The last index such that every point from start onwards stays within the radius of the
+    // starting point
+    pub(super) fn dwell_end(&self, start: usize, radius: Distance) -> usize {
+        let origin = self.inner[start].0;
+        let mut end = start;
+        while end + 1 < self.inner.len() && self.inner[end + 1].0.dist_to(origin) <= radius {
+            end += 1;
+        }
+        end
+    }
+}
+
+// Where the straight line from a to b says the vehicle is at this time
+fn synchronized_pt(a: (Pt2D, Time), b: (Pt2D, Time), time: Time) -> Pt2D {
+    if b.1 <= a.1 {
+        return a.0;
+    }
+    let pct = (time - a.1) / (b.1 - a.1);
+    Pt2D::new(
+        a.0.x() + pct * (b.0.x() - a.0.x()),
+        a.0.y() + pct * (b.0.y() - a.0.y()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(secs: f64) -> Time {
+        Time::START_OF_DAY + Duration::seconds(secs)
+    }
+
+    // Deterministic noise between -1 and 1
+    fn jitter(i: usize) -> f64 {
+        ((i * 37) % 7) as f64 / 3.0 - 1.0
+    }
+
+    // A point every 2 seconds. Drive east at 10m/s, dwell at a stop for a minute, then turn north.
+    fn original() -> Trajectory {
+        let mut pts = Vec::new();
+        for i in 0..=60 {
+            let secs = 2.0 * i as f64;
+            let pt = if secs < 30.0 {
+                Pt2D::new(10.0 * secs, jitter(i))
+            } else if secs <= 90.0 {
+                Pt2D::new(300.0 + 1.5 * jitter(i), 1.5 * jitter(i + 1))
+            } else {
+                Pt2D::new(300.0 + jitter(i), 10.0 * (secs - 90.0))
+            };
+            pts.push((pt, time(secs)));
+        }
+        Trajectory::new(pts).unwrap()
+    }
+
+    // The documented bound, with a little slack for rounding. The segments on either side of the
+    // dwell end at a dwell point, so they get the looser bound too.
+    fn bound(config: &CompressionConfig, t: Time) -> Distance {
+        let slack = Distance::meters(0.01);
+        if t >= time(28.0) && t <= time(92.0) {
+            config.dwell_radius * 2.0 + slack
+        } else {
+            config.max_error + slack
+        }
+    }
+
+    fn check_interpolation(
+        config: &CompressionConfig,
+        original: &Trajectory,
+        compressed: &Trajectory,
+    ) {
+        assert_eq!(compressed.start_time(), original.start_time());
+        assert_eq!(compressed.end_time(), original.end_time());
+        let mut t = original.start_time();
+        while t <= original.end_time() {
+            let (pt1, _) = original.interpolate(t).unwrap();
+            let (pt2, _) = compressed.interpolate(t).unwrap();
+            assert!(
+                pt1.dist_to(pt2) <= bound(config, t),
+                "at {t}, {:?} is {} away from {:?}",
+                pt2,
+                pt1.dist_to(pt2),
+                pt1
+            );
+            t = t + Duration::seconds(0.5);
+        }
+    }
+
+    #[test]
+    fn error_bound() {
+        let config = CompressionConfig::default();
+        let original = original();
+        let (compressed, stats) = original.compress(&config);
+        assert_eq!(stats.dwells, 1);
+        assert!(compressed.num_points() < original.num_points() / 3);
+        check_interpolation(&config, &original, &compressed);
+
+        // Clipping partway along segments
+        let (t1, t2) = (time(13.0), time(101.0));
+        check_interpolation(
+            &config,
+            &original.clip_to_time(t1, t2).unwrap(),
+            &compressed.clip_to_time(t1, t2).unwrap(),
+        );
+
+        // Passing stops before and after the dwell
+        for stop in [Pt2D::new(150.0, 3.0), Pt2D::new(303.0, 150.0)] {
+            let threshold = Distance::meters(10.0);
+            let dedupe = Duration::seconds(30.0);
+            let hits1 = original.times_near_pos(stop, threshold, dedupe);
+            let hits2 = compressed.times_near_pos(stop, threshold, dedupe);
+            assert_eq!(hits1.len(), 1);
+            assert_eq!(hits2.len(), 1);
+            let (t, pt) = hits2[0];
+            assert!(pt.dist_to(original.interpolate(t).unwrap().0) <= bound(&config, t));
+            // Driving at 10m/s, the position error is at most half a second
+            assert!((t - hits1[0].0).inner_seconds().abs() <= 1.0);
+        }
+    }
+}
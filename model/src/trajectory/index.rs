@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use geom::{Distance, Pt2D};
+
+// Matching asks every trajectory when it passes near every stop, and projects every trajectory
+// onto route shapes. Bucket each segment of a line into the grid cells it passes through, so only
+// nearby segments need to be checked.
+const CELL_SIZE: f64 = 100.0;
+
+#[derive(Clone)]
+pub(super) struct SegmentGrid {
+    // Values are the index of the segment's first point
+    cells: HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl SegmentGrid {
+    pub fn new(pts: &[Pt2D]) -> Self {
+        let mut cells: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+        for (idx, pair) in pts.windows(2).enumerate() {
+            for key in cells_crossed(pair[0], pair[1]) {
+                cells.entry(key).or_insert_with(Vec::new).push(idx);
+            }
+        }
+        Self { cells }
+    }
+
+    /// Returns the segments that might be within the threshold of the point, in order
+    pub fn segments_near(&self, pos: Pt2D, threshold: Distance) -> Vec<usize> {
+        let r = threshold.inner_meters();
+        let mut results = Vec::new();
+        for x in cell(pos.x() - r)..=cell(pos.x() + r) {
+            for y in cell(pos.y() - r)..=cell(pos.y() + r) {
+                if let Some(segments) = self.cells.get(&(x, y)) {
+                    results.extend(segments.iter().cloned());
+                }
+            }
+        }
+        results.sort_unstable();
+        results.dedup();
+        results
+    }
+
+    /// Like `segments_near`, but just checks if any of those segments pass the test, stopping at
+    /// the first one that does. Segments may be tested more than once.
+    pub fn any_near<F: FnMut(usize) -> bool>(
+        &self,
+        pos: Pt2D,
+        threshold: Distance,
+        mut test: F,
+    ) -> bool {
+        let r = threshold.inner_meters();
+        for x in cell(pos.x() - r)..=cell(pos.x() + r) {
+            for y in cell(pos.y() - r)..=cell(pos.y() + r) {
+                if let Some(segments) = self.cells.get(&(x, y)) {
+                    if segments.iter().any(|idx| test(*idx)) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+fn cell(x: f64) -> i64 {
+    (x / CELL_SIZE).floor() as i64
+}
+
+// Walks along the segment, one cell boundary at a time. A long diagonal segment, like a gap in
+// GPS, only touches the cells along it, not every cell in its bounding box.
+fn cells_crossed(pt1: Pt2D, pt2: Pt2D) -> Vec<(i64, i64)> {
+    let (mut x, mut y) = (cell(pt1.x()), cell(pt1.y()));
+    let (end_x, end_y) = (cell(pt2.x()), cell(pt2.y()));
+    let (dx, dy) = (pt2.x() - pt1.x(), pt2.y() - pt1.y());
+    let step_x = if dx > 0.0 { 1 } else { -1 };
+    let step_y = if dy > 0.0 { 1 } else { -1 };
+    // For each axis, the fraction along the segment where it crosses the next cell boundary, and
+    // how much further the boundary after that is
+    let (mut next_x, delta_x) = boundary_crossings(pt1.x(), dx, x);
+    let (mut next_y, delta_y) = boundary_crossings(pt1.y(), dy, y);
+
+    let mut cells = vec![(x, y)];
+    // Every step moves one cell on one axis. Don't trust floating point to stop at the end cell.
+    for _ in 0..(end_x - x).abs() + (end_y - y).abs() {
+        if y == end_y || (x != end_x && next_x < next_y) {
+            x += step_x;
+            next_x += delta_x;
+        } else {
+            y += step_y;
+            next_y += delta_y;
+        }
+        cells.push((x, y));
+    }
+    cells
+}
+
+fn boundary_crossings(start: f64, delta: f64, start_cell: i64) -> (f64, f64) {
+    if delta > 0.0 {
+        let boundary = (start_cell + 1) as f64 * CELL_SIZE;
+        ((boundary - start) / delta, CELL_SIZE / delta)
+    } else if delta < 0.0 {
+        let boundary = start_cell as f64 * CELL_SIZE;
+        ((boundary - start) / delta, -CELL_SIZE / delta)
+    } else {
+        (f64::INFINITY, f64::INFINITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagonal_segment() {
+        // About 10km long
+        let cells = cells_crossed(Pt2D::new(50.0, 50.0), Pt2D::new(7050.0, 7030.0));
+        assert_eq!(cells[0], (0, 0));
+        assert_eq!(*cells.last().unwrap(), (70, 70));
+        assert!(cells.len() <= 141);
+
+        let grid = SegmentGrid::new(&[Pt2D::new(50.0, 50.0), Pt2D::new(7050.0, 7030.0)]);
+        assert_eq!(
+            grid.segments_near(Pt2D::new(3500.0, 3500.0), Distance::meters(10.0)),
+            vec![0]
+        );
+        assert!(grid
+            .segments_near(Pt2D::new(6000.0, 500.0), Distance::meters(10.0))
+            .is_empty());
+        assert!(grid.any_near(Pt2D::new(3500.0, 3500.0), Distance::meters(10.0), |_| true));
+        assert!(!grid.any_near(Pt2D::new(3500.0, 3500.0), Distance::meters(10.0), |_| false));
+        assert!(!grid.any_near(Pt2D::new(6000.0, 500.0), Distance::meters(10.0), |_| true));
+    }
+}
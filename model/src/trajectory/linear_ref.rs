@@ -0,0 +1,478 @@
+use std::sync::Arc;
+
+use geom::{Distance, Duration, PolyLine, Pt2D, Speed, Time};
+
+use super::index::SegmentGrid;
+use super::{project_onto_segment, Trajectory};
+
+// Linear referencing describes positions by their distance along a route's shape, instead of x
+// and y. A vehicle following the route then becomes distance over time, which is what stop
+// arrivals, time-space diagrams, and speed profiles need.
+
+// Moving along the shape must account for at least this fraction of the vehicle's movement.
+// Crossing the route at an intersection barely moves along it.
+const MIN_ALIGNMENT: f64 = 0.5;
+// Progressing faster than this along the shape means the projection jumped, usually between two
+// parts of a shape doubling back on itself
+const MAX_SPEED_METERS_PER_SECOND: f64 = 30.0;
+
+/// A shape, indexed to quickly find distances along it
+#[derive(Clone)]
+pub struct LinearShape {
+    pl: PolyLine,
+    // The distance along the shape of each point
+    dists: Vec<Distance>,
+    grid: SegmentGrid,
+}
+
+impl LinearShape {
+    pub fn new(pl: PolyLine) -> Self {
+        let mut dists = vec![Distance::ZERO];
+        for pair in pl.points().windows(2) {
+            dists.push(*dists.last().unwrap() + pair[0].dist_to(pair[1]));
+        }
+        let grid = SegmentGrid::new(pl.points());
+        Self { pl, dists, grid }
+    }
+
+    pub fn polyline(&self) -> &PolyLine {
+        &self.pl
+    }
+
+    pub fn length(&self) -> Distance {
+        *self.dists.last().unwrap()
+    }
+
+    /// The position at some distance along the shape, or None past either end
+    pub fn pos_at(&self, dist: Distance) -> Option<Pt2D> {
+        if dist < Distance::ZERO || dist > self.length() {
+            return None;
+        }
+        self.pl.dist_along(dist).ok().map(|(pt, _)| pt)
+    }
+
+    /// The closest place anywhere along the shape to the point. Returns the distance along, and
+    /// how far away the point is.
+    pub fn closest(&self, pt: Pt2D) -> Option<(Distance, Distance)> {
+        (0..self.dists.len() - 1)
+            .filter_map(|idx| self.project_onto(idx, pt))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    }
+
+    /// The distance along the shape of a point within the threshold of it. When several parts of
+    /// the shape are close, picks the one nearest `near` along the shape, or else the closest.
+    pub fn project(
+        &self,
+        pt: Pt2D,
+        threshold: Distance,
+        near: Option<Distance>,
+    ) -> Option<Distance> {
+        let cost = |(dist, lateral): &(Distance, Distance)| match near {
+            Some(near) => (*dist - near).inner_meters().abs(),
+            None => lateral.inner_meters(),
+        };
+        self.grid
+            .segments_near(pt, threshold)
+            .into_iter()
+            .filter_map(|idx| self.project_onto(idx, pt))
+            .filter(|(_, lateral)| *lateral <= threshold)
+            .min_by(|a, b| cost(a).partial_cmp(&cost(b)).unwrap())
+            .map(|(dist, _)| dist)
+    }
+
+    /// The distance along the shape, at or after `min_dist`, where it first passes within the
+    /// threshold of the point. Of the consecutive segments that close, picks the closest one, so a
+    /// point near a corner goes on the right side of it. None if the shape doesn't pass that
+    /// close after `min_dist`. Placing a sequence of stops this way keeps them in order, even when
+    /// the shape passes the same spot twice.
+    pub fn dist_along_after(
+        &self,
+        pt: Pt2D,
+        min_dist: Distance,
+        threshold: Distance,
+    ) -> Option<Distance> {
+        let mut best: Option<(Distance, Distance)> = None;
+        for idx in 0..self.dists.len() - 1 {
+            match self.project_onto(idx, pt) {
+                Some((dist, lateral)) if dist >= min_dist && lateral <= threshold => {
+                    if best.map(|(_, x)| lateral < x).unwrap_or(true) {
+                        best = Some((dist, lateral));
+                    }
+                }
+                // Segments with no length don't interrupt the run
+                None => {}
+                // Later segments coming close again are a different pass by the point
+                Some(_) => {
+                    if best.is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+        best.map(|(dist, _)| dist)
+    }
+
+    // Projects onto one segment, returning the distance along the shape and how far away the
+    // point is. None if the segment has no length.
+    fn project_onto(&self, idx: usize, pt: Pt2D) -> Option<(Distance, Distance)> {
+        let points = self.pl.points();
+        let (pct, on_segment) = project_onto_segment(pt, points[idx], points[idx + 1])?;
+        let dist = self.dists[idx] + (self.dists[idx + 1] - self.dists[idx]) * pct;
+        Some((dist, pt.dist_to(on_segment)))
+    }
+}
+
+/// A trajectory described by distance along a shape over time
+#[derive(Clone)]
+pub struct ProjectedTrajectory {
+    shape: Arc<LinearShape>,
+    samples: Vec<ShapeSample>,
+}
+
+/// One point of a trajectory, projected onto a shape
+#[derive(Clone, Copy, Debug)]
+pub struct ShapeSample {
+    pub time: Time,
+    pub pos: Pt2D,
+    /// None when the vehicle is off-route, too far from the shape
+    pub dist: Option<Distance>,
+}
+
+impl Trajectory {
+    /// Projects every point onto the shape. Points further than `threshold` from it are
+    /// off-route. Where the shape passes the same spot more than once, each point stays close to
+    /// the previous one along the shape.
+    pub fn project_onto_shape(
+        &self,
+        shape: Arc<LinearShape>,
+        threshold: Distance,
+    ) -> ProjectedTrajectory {
+        let mut samples: Vec<ShapeSample> = Vec::new();
+        for (pos, time) in &self.inner {
+            let near = samples.last().and_then(|s| s.dist);
+            samples.push(ShapeSample {
+                time: *time,
+                pos: *pos,
+                dist: shape.project(*pos, threshold, near),
+            });
+        }
+        ProjectedTrajectory { shape, samples }
+    }
+}
+
+impl ProjectedTrajectory {
+    pub fn shape(&self) -> &LinearShape {
+        &self.shape
+    }
+
+    /// In order of time
+    pub fn samples(&self) -> &[ShapeSample] {
+        &self.samples
+    }
+
+    /// Every time the vehicle moves forwards along the shape past this distance. Passes closer
+    /// together than `dedupe` count as one.
+    pub fn arrivals_at(&self, dist: Distance, dedupe: Duration) -> Vec<Time> {
+        self.arrivals_at_each(&[dist], dedupe).pop().unwrap()
+    }
+
+    /// Like `arrivals_at`, for many distances at once. The distances must be in increasing order.
+    pub fn arrivals_at_each(&self, dists: &[Distance], dedupe: Duration) -> Vec<Vec<Time>> {
+        let mut arrivals = vec![Vec::new(); dists.len()];
+        for pair in self.samples.windows(2) {
+            let (d1, d2) = match forwards(&pair[0], &pair[1]) {
+                Some(x) => x,
+                None => continue,
+            };
+            let (t1, t2) = (pair[0].time, pair[1].time);
+            // Only check the distances crossed
+            let first = dists.partition_point(|d| *d < d1);
+            for (idx, dist) in dists.iter().enumerate().skip(first) {
+                if *dist > d2 {
+                    break;
+                }
+                let time = t1 + ((*dist - d1) / (d2 - d1)) * (t2 - t1);
+                // A pair of points ending exactly at the distance and the next pair starting
+                // there both find it
+                if arrivals[idx]
+                    .last()
+                    .map(|last| time - *last > dedupe)
+                    .unwrap_or(true)
+                {
+                    arrivals[idx].push(time);
+                }
+            }
+        }
+        arrivals
+    }
+
+    /// The distance along the shape at some time. None if the vehicle is off-route then, or
+    /// jumps implausibly along the shape.
+    pub fn dist_at(&self, time: Time) -> Option<Distance> {
+        self.along_at(time).map(|(dist, _)| dist)
+    }
+
+    /// Where the vehicle is at some time, following the shape between consecutive points instead
+    /// of cutting straight across. The speed is along the shape. None when `dist_at` is.
+    pub fn interpolate(&self, time: Time) -> Option<(Pt2D, Speed)> {
+        let (dist, speed) = self.along_at(time)?;
+        Some((self.shape.pos_at(dist)?, speed))
+    }
+
+    /// Roughly how far off a time interpolated along the shape might be, if the vehicle's speed
+    /// varied between the two samples around it. The faster it had to move to cover the distance
+    /// along the shape between them, the less room there is for error. Zero at a sample, and
+    /// largest halfway between two. Compression drops points it can reproduce closely, so this
+    /// overestimates for compressed trajectories. None when `dist_at` is.
+    pub fn time_uncertainty(&self, time: Time) -> Option<Duration> {
+        self.along_at(time)?;
+        let idx = self.samples.partition_point(|s| s.time < time);
+        let s2 = &self.samples[idx];
+        if s2.time == time {
+            return Some(Duration::ZERO);
+        }
+        let s1 = &self.samples[idx - 1];
+        let gap = s2.time - s1.time;
+        let progress = (s2.dist? - s1.dist?).inner_meters().abs();
+        let slack = gap - Duration::seconds(progress / MAX_SPEED_METERS_PER_SECOND);
+        // Distance along the shape is interpolated linearly in time
+        let pct = (time - s1.time) / gap;
+        Some(slack * (pct * (1.0 - pct)))
+    }
+
+    fn along_at(&self, time: Time) -> Option<(Distance, Speed)> {
+        let idx = self.samples.partition_point(|s| s.time < time);
+        let s2 = self.samples.get(idx)?;
+        if idx == 0 {
+            return if s2.time == time {
+                s2.dist.map(|dist| (dist, Speed::ZERO))
+            } else {
+                None
+            };
+        }
+        let s1 = &self.samples[idx - 1];
+        let (d1, d2) = (s1.dist?, s2.dist?);
+        let duration = s2.time - s1.time;
+        let progress = Distance::meters((d2 - d1).inner_meters().abs());
+        if progress.inner_meters() / duration.inner_seconds() > MAX_SPEED_METERS_PER_SECOND {
+            return None;
+        }
+        Some((
+            d1 + (d2 - d1) * ((time - s1.time) / duration),
+            Speed::from_dist_time(progress, duration),
+        ))
+    }
+
+    /// The position along the shape at some distance
+    pub fn pos_at_dist(&self, dist: Distance) -> Option<Pt2D> {
+        self.shape.pos_at(dist)
+    }
+}
+
+// If the vehicle moves forwards along the shape between two samples, returns the distances at
+// each. Going backwards, across the shape, or impossibly fast doesn't count.
+fn forwards(s1: &ShapeSample, s2: &ShapeSample) -> Option<(Distance, Distance)> {
+    let (d1, d2) = (s1.dist?, s2.dist?);
+    if d1 >= d2 || s1.time >= s2.time {
+        return None;
+    }
+    let progress = (d2 - d1).inner_meters();
+    if progress / s1.pos.dist_to(s2.pos).inner_meters().max(1.0) < MIN_ALIGNMENT {
+        return None;
+    }
+    if progress / (s2.time - s1.time).inner_seconds() > MAX_SPEED_METERS_PER_SECOND {
+        return None;
+    }
+    Some((d1, d2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(pts: Vec<(f64, f64)>) -> LinearShape {
+        LinearShape::new(PolyLine::must_new(
+            pts.into_iter().map(|(x, y)| Pt2D::new(x, y)).collect(),
+        ))
+    }
+
+    // 100m east, then 100m north
+    fn l_shape() -> LinearShape {
+        shape(vec![(0.0, 0.0), (100.0, 0.0), (100.0, 100.0)])
+    }
+
+    // 100m east, 20m north, then 100m back west. Points midway between the two long legs are within
+    // 15m of both.
+    fn u_shape() -> LinearShape {
+        shape(vec![(0.0, 0.0), (100.0, 0.0), (100.0, 20.0), (0.0, 20.0)])
+    }
+
+    fn t(secs: f64) -> Time {
+        Time::START_OF_DAY + Duration::seconds(secs)
+    }
+
+    fn meters(dist: Option<Distance>) -> Option<f64> {
+        dist.map(|d| (d.inner_meters() * 1000.0).round() / 1000.0)
+    }
+
+    fn sample(x: f64, y: f64, secs: f64, dist: f64) -> ShapeSample {
+        ShapeSample {
+            time: t(secs),
+            pos: Pt2D::new(x, y),
+            dist: Some(Distance::meters(dist)),
+        }
+    }
+
+    #[test]
+    fn project() {
+        let l = l_shape();
+        let threshold = Distance::meters(10.0);
+        assert_eq!(
+            meters(l.project(Pt2D::new(50.0, 5.0), threshold, None)),
+            Some(50.0)
+        );
+        assert_eq!(
+            meters(l.project(Pt2D::new(105.0, 50.0), threshold, None)),
+            Some(150.0)
+        );
+        assert_eq!(
+            meters(l.project(Pt2D::new(105.0, -5.0), threshold, None)),
+            Some(100.0)
+        );
+        assert_eq!(l.project(Pt2D::new(50.0, 50.0), threshold, None), None);
+
+        // Between the two legs, pick the closest one, unless the vehicle was just on the other
+        let u = u_shape();
+        let threshold = Distance::meters(15.0);
+        let pt = Pt2D::new(50.0, 8.0);
+        assert_eq!(meters(u.project(pt, threshold, None)), Some(50.0));
+        let near = Some(Distance::meters(200.0));
+        assert_eq!(meters(u.project(pt, threshold, near)), Some(170.0));
+    }
+
+    #[test]
+    fn dist_along_after() {
+        let threshold = Distance::meters(10.0);
+        // Both legs of the L are close, but the second is closer
+        let l = l_shape();
+        assert_eq!(
+            meters(l.dist_along_after(Pt2D::new(97.0, 8.0), Distance::ZERO, threshold)),
+            Some(108.0)
+        );
+
+        // The first pass counts, even when the second comes closer
+        let u = u_shape();
+        let threshold = Distance::meters(15.0);
+        let pt = Pt2D::new(50.0, 12.0);
+        assert_eq!(
+            meters(u.dist_along_after(pt, Distance::ZERO, threshold)),
+            Some(50.0)
+        );
+        assert_eq!(
+            meters(u.dist_along_after(pt, Distance::meters(60.0), threshold)),
+            Some(170.0)
+        );
+        assert_eq!(
+            u.dist_along_after(pt, Distance::meters(180.0), threshold),
+            None
+        );
+    }
+
+    #[test]
+    fn arrivals_at_each() {
+        // Around the U at 10m/s
+        let trajectory = Trajectory::new(vec![
+            (Pt2D::new(0.0, 0.0), t(0.0)),
+            (Pt2D::new(100.0, 0.0), t(10.0)),
+            (Pt2D::new(100.0, 20.0), t(12.0)),
+            (Pt2D::new(0.0, 20.0), t(22.0)),
+        ])
+        .unwrap();
+        let projected = trajectory.project_onto_shape(Arc::new(u_shape()), Distance::meters(15.0));
+        let dists: Vec<Option<f64>> = projected.samples().iter().map(|s| meters(s.dist)).collect();
+        assert_eq!(
+            dists,
+            vec![Some(0.0), Some(100.0), Some(120.0), Some(220.0)]
+        );
+
+        let arrivals = projected.arrivals_at_each(
+            &[50.0, 110.0, 170.0].map(Distance::meters),
+            Duration::seconds(30.0),
+        );
+        assert_eq!(arrivals, vec![vec![t(5.0)], vec![t(11.0)], vec![t(17.0)]]);
+        // Only moving forwards along the shape counts
+        assert!(projected
+            .arrivals_at(Distance::meters(250.0), Duration::seconds(30.0))
+            .is_empty());
+
+        assert_eq!(meters(projected.dist_at(t(5.0))), Some(50.0));
+        let (pos, speed) = projected.interpolate(t(11.0)).unwrap();
+        assert!(pos.dist_to(Pt2D::new(100.0, 10.0)) < Distance::meters(0.001));
+        assert!((speed.inner_meters_per_second() - 10.0).abs() < 0.001);
+        assert_eq!(projected.dist_at(t(23.0)), None);
+    }
+
+    #[test]
+    fn along_at() {
+        let projected = ProjectedTrajectory {
+            shape: Arc::new(l_shape()),
+            samples: vec![
+                sample(0.0, 0.0, 0.0, 0.0),
+                sample(50.0, 0.0, 5.0, 50.0),
+                ShapeSample {
+                    time: t(10.0),
+                    pos: Pt2D::new(50.0, 50.0),
+                    dist: None,
+                },
+                sample(100.0, 100.0, 20.0, 200.0),
+                sample(0.0, 0.0, 21.0, 0.0),
+            ],
+        };
+        assert_eq!(meters(projected.dist_at(t(0.0))), Some(0.0));
+        assert_eq!(meters(projected.dist_at(t(2.5))), Some(25.0));
+        assert_eq!(meters(projected.dist_at(t(5.0))), Some(50.0));
+        // Off-route
+        assert_eq!(projected.dist_at(t(7.0)), None);
+        assert_eq!(projected.dist_at(t(15.0)), None);
+        // Jumping 200m in a second
+        assert_eq!(projected.dist_at(t(20.5)), None);
+        // After the trajectory ends
+        assert_eq!(projected.dist_at(t(22.0)), None);
+    }
+
+    #[test]
+    fn forwards() {
+        let moves_forwards = |s1: ShapeSample, s2: ShapeSample| super::forwards(&s1, &s2).is_some();
+        assert!(moves_forwards(
+            sample(0.0, 0.0, 0.0, 0.0),
+            sample(50.0, 0.0, 5.0, 50.0)
+        ));
+        // Backwards
+        assert!(!moves_forwards(
+            sample(50.0, 0.0, 0.0, 50.0),
+            sample(0.0, 0.0, 5.0, 0.0)
+        ));
+        // Mostly across the shape, like crossing it at an intersection
+        assert!(!moves_forwards(
+            sample(95.0, -20.0, 0.0, 95.0),
+            sample(105.0, 20.0, 5.0, 105.0)
+        ));
+        // Impossibly fast
+        assert!(!moves_forwards(
+            sample(0.0, 0.0, 0.0, 0.0),
+            sample(100.0, 100.0, 1.0, 200.0)
+        ));
+        // Not moving forwards in time
+        assert!(!moves_forwards(
+            sample(0.0, 0.0, 5.0, 0.0),
+            sample(50.0, 0.0, 5.0, 50.0)
+        ));
+        // Off-route
+        let off_route = ShapeSample {
+            time: t(5.0),
+            pos: Pt2D::new(50.0, 50.0),
+            dist: None,
+        };
+        assert!(!moves_forwards(sample(0.0, 0.0, 0.0, 0.0), off_route));
+    }
+}
@@ -0,0 +1,498 @@
+use std::fs::File;
+use std::io::Write;
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use geom::{Distance, Duration, GPSBounds, Line, PolyLine, Pt2D, Speed, Time};
+use serde::{Deserialize, Serialize};
+
+pub use self::compress::{CompressionConfig, CompressionStats};
+pub use self::linear_ref::{LinearShape, ProjectedTrajectory, ShapeSample};
+
+use self::index::SegmentGrid;
+
+mod compress;
+mod index;
+mod linear_ref;
+
+// Moving slower than this, a vehicle is stopped. GPS noise makes a stopped vehicle wander a bit.
+const STOPPED_METERS_PER_SECOND: f64 = 1.5;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Trajectory {
+    // Staying in the same position for a while is represented by two equal points. See
+    // compress.rs.
+    inner: Vec<(Pt2D, Time)>,
+    // Built the first time it's needed, and not serialized
+    #[serde(skip)]
+    index: OnceLock<SegmentGrid>,
+}
+
+// Creation
+impl Trajectory {
+    pub fn new(raw: Vec<(Pt2D, Time)>) -> Result<Self> {
+        // Just one validation for now
+        for pair in raw.windows(2) {
+            // TODO Handle equal time, same or different points
+            if pair[0].1 > pair[1].1 {
+                bail!(
+                    "Trajectory input out-of-order: {} then {}",
+                    pair[0].1,
+                    pair[1].1
+                );
+            }
+        }
+        if raw.len() < 2 {
+            bail!("Trajectory doesn't have at least 2 points");
+        }
+        Ok(Self::from_inner(raw))
+    }
+
+    fn from_inner(inner: Vec<(Pt2D, Time)>) -> Self {
+        Self {
+            inner,
+            index: OnceLock::new(),
+        }
+    }
+
+    /// Makes up nonsense times per point
+    // TODO Remove this
+    pub fn from_polyline(pl: &PolyLine) -> Self {
+        let mut time = Time::START_OF_DAY;
+        let mut inner = Vec::new();
+        for pt in pl.points() {
+            inner.push((*pt, time));
+            time += Duration::minutes(1);
+        }
+        Self::from_inner(inner)
+    }
+
+    pub fn from_pieces_with_times(pieces: &Vec<PolyLine>, times: Vec<Time>) -> Result<Self> {
+        if pieces.len() != times.len() - 1 {
+            bail!("{} PolyLines, but {} times", pieces.len(), times.len());
+        }
+
+        let mut inner = Vec::new();
+        for (pair, pl) in times.windows(2).zip(pieces) {
+            let trajectory = Self::lerp_along_pl(pl, pair[0], pair[1]);
+            inner.extend(trajectory.inner);
+        }
+        Self::new(inner)
+    }
+
+    fn lerp_along_pl(pl: &PolyLine, t1: Time, t2: Time) -> Self {
+        let mut inner = Vec::new();
+        let total_dist = pl.length();
+        let mut dist_so_far = Distance::ZERO;
+        let mut last_pt = pl.first_pt();
+        for pt in pl.points() {
+            let pt = *pt;
+            dist_so_far += last_pt.dist_to(pt);
+            last_pt = pt;
+            let pct = dist_so_far / total_dist;
+            let time = t1 + pct * (t2 - t1);
+            inner.push((pt, time));
+        }
+        Self::from_inner(inner)
+    }
+
+    /// Split a trajectory into pieces every time it crosses itself. This will split when a vehicle
+    /// drives on a bridge over a previous part of its path.
+    pub fn split_non_overlapping(&self) -> Vec<Trajectory> {
+        let mut results = Vec::new();
+
+        let mut current_trajectory = Vec::new();
+        let mut current_pl: Option<PolyLine> = None;
+
+        for (pt, t) in &self.inner {
+            let pt = *pt;
+            let t = *t;
+
+            match current_pl {
+                Some(ref current) => {
+                    // We might be looking at two equal adjacent points. That's not a split.
+                    if let Ok(pl) = PolyLine::new(vec![current.last_pt(), pt]) {
+                        let has_intersection = if current.points().len() == 2 {
+                            false
+                        } else {
+                            // Either there's noise in the trajectory data or the intersection
+                            // check is too sensitive. Ignore the last point.
+                            let mut pts = current.clone().into_points();
+                            pts.pop().unwrap();
+                            let compare = PolyLine::must_new(pts);
+                            compare.intersection(&pl).is_some()
+                        };
+
+                        // If adding this point causes the polyline to intersect itself, we've found a split
+                        if has_intersection {
+                            let last = *current_trajectory.last().unwrap();
+                            results.push(Trajectory::from_inner(std::mem::replace(
+                                &mut current_trajectory,
+                                vec![last, (pt, t)],
+                            )));
+                            current_pl = Some(pl);
+                            continue;
+                        }
+                    }
+                    current_trajectory.push((pt, t));
+                    current_pl = Some(current_pl.take().unwrap().optionally_push(pt));
+                }
+                None => {
+                    // Still at the beginning
+                    if let Some(last) = current_trajectory.last() {
+                        current_pl = PolyLine::new(vec![last.0, pt]).ok();
+                    }
+                    current_trajectory.push((pt, t));
+                }
+            }
+        }
+        results.push(Trajectory::from_inner(current_trajectory));
+        results
+    }
+
+    pub fn clip_to_time(&self, t1: Time, t2: Time) -> Result<Self> {
+        let mut inner = Vec::new();
+        for (pt, t) in &self.inner {
+            let pt = *pt;
+            let t = *t;
+            if t < t1 {
+                continue;
+            }
+            if t > t2 {
+                break;
+            }
+            if inner.is_empty() {
+                if let Some((pt, _)) = self.interpolate(t1) {
+                    inner.push((pt, t1));
+                }
+            }
+            inner.push((pt, t));
+        }
+        if let Some((pt, _)) = self.interpolate(t2) {
+            inner.push((pt, t2));
+        }
+
+        Self::new(inner)
+    }
+}
+
+// Queries
+impl Trajectory {
+    /// None if the trajectory isn't active at this time
+    pub fn interpolate(&self, time: Time) -> Option<(Pt2D, Speed)> {
+        if time < self.start_time() || time > self.end_time() {
+            return None;
+        }
+
+        // The first point at or after this time. If that's the very first point, use the first
+        // segment.
+        let idx = self.inner.partition_point(|(_, t)| *t < time).max(1);
+        let (pos1, t1) = self.inner[idx - 1];
+        let (pos2, t2) = self.inner[idx];
+        match Line::new(pos1, pos2) {
+            Ok(line) => {
+                let percent = (time - t1) / (t2 - t1);
+                let pos = line.percent_along(percent).unwrap();
+                let speed = Speed::from_dist_time(line.length(), t2 - t1);
+                Some((pos, speed))
+            }
+            Err(_) => Some((pos1, Speed::ZERO)),
+        }
+    }
+
+    pub fn num_points(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn start_time(&self) -> Time {
+        self.inner[0].1
+    }
+
+    pub fn end_time(&self) -> Time {
+        self.inner.last().unwrap().1
+    }
+
+    pub fn as_polyline(&self) -> PolyLine {
+        let mut pts = Vec::new();
+        for (pos, _) in &self.inner {
+            pts.push(*pos);
+        }
+        let pts = Pt2D::approx_dedupe(pts, Distance::meters(1.0));
+
+        // TODO The trajectory usually doubles back on itself. Should we split into multiple
+        // segments instead of doing this?
+        PolyLine::unchecked_new(pts)
+    }
+
+    /// Returns (center, start, end) for every period where the vehicle stays within the radius of
+    /// one spot for at least some duration
+    pub fn stationary_periods(
+        &self,
+        radius: Distance,
+        min_duration: Duration,
+    ) -> Vec<(Pt2D, Time, Time)> {
+        let mut results = Vec::new();
+        let mut idx = 0;
+        while idx < self.inner.len() {
+            let end = self.dwell_end(idx, radius);
+            let (t1, t2) = (self.inner[idx].1, self.inner[end].1);
+            if end > idx && t2 - t1 >= min_duration {
+                let pts: Vec<Pt2D> = self.inner[idx..=end].iter().map(|(pt, _)| *pt).collect();
+                results.push((Pt2D::center(&pts), t1, t2));
+                idx = end + 1;
+            } else {
+                idx += 1;
+            }
+        }
+        results
+    }
+
+    /// The vehicle passes within the radius of `pos` at `time`. Returns when it arrived and
+    /// departed: when it entered and left the radius during that pass, or if it stopped inside the
+    /// radius, when it stopped and started moving again. `time` is always between the two.
+    pub fn stop_visit(&self, pos: Pt2D, radius: Distance, time: Time) -> (Time, Time) {
+        if self.inner.len() < 2 {
+            return (time, time);
+        }
+        let within = |idx: usize| self.inner[idx].0.dist_to(pos) <= radius;
+        let at = |segment: usize, pct: f64| {
+            let (t1, t2) = (self.inner[segment].1, self.inner[segment + 1].1);
+            t1 + pct * (t2 - t1)
+        };
+
+        // Find the segments of this pass touching the radius, starting from the one at `time`
+        let last_segment = self.inner.len() - 2;
+        let segment = self
+            .inner
+            .partition_point(|(_, t)| *t < time)
+            .saturating_sub(1)
+            .min(last_segment);
+        let mut first = segment;
+        while first > 0 && within(first) {
+            first -= 1;
+        }
+        let mut last = segment;
+        while last < last_segment && within(last + 1) {
+            last += 1;
+        }
+
+        // Interpolate where the pass crosses the radius
+        let crossing = |segment: usize| {
+            let (pt1, pt2) = (self.inner[segment].0, self.inner[segment + 1].0);
+            segment_within_radius(pos, radius, pt1, pt2)
+        };
+        let (mut arrival, mut departure) = match (crossing(first), crossing(last)) {
+            (Some((enter, _)), Some((_, leave))) => (at(first, enter), at(last, leave)),
+            _ => return (time, time),
+        };
+
+        // If the vehicle stopped inside the radius, use that instead
+        let mut stopped = None;
+        for idx in first..=last {
+            let ((pt1, t1), (pt2, t2)) = (self.inner[idx], self.inner[idx + 1]);
+            if !within(idx) || !within(idx + 1) || t1 >= t2 {
+                continue;
+            }
+            let speed = pt1.dist_to(pt2).inner_meters() / (t2 - t1).inner_seconds();
+            if speed < STOPPED_METERS_PER_SECOND {
+                stopped = match stopped {
+                    Some((start, _)) => Some((start, t2)),
+                    None => Some((t1, t2)),
+                };
+            }
+        }
+        if let Some((start, end)) = stopped {
+            arrival = start;
+            departure = end;
+        }
+
+        if arrival > time {
+            arrival = time;
+        }
+        if departure < time {
+            departure = time;
+        }
+        (arrival, departure)
+    }
+
+    /// Does the trajectory ever pass within some threshold of the point? The same as checking
+    /// `times_near_pos` isn't empty, but faster.
+    pub fn passes_near(&self, pos: Pt2D, threshold: Distance) -> bool {
+        self.index().any_near(pos, threshold, |idx| {
+            let (pt1, pt2) = (self.inner[idx].0, self.inner[idx + 1].0);
+            project_onto_segment(pos, pt1, pt2)
+                .map(|(_, pt_on_segment)| pos.dist_to(pt_on_segment) < threshold)
+                .unwrap_or(false)
+        })
+    }
+
+    fn index(&self) -> &SegmentGrid {
+        self.index.get_or_init(|| {
+            let pts: Vec<Pt2D> = self.inner.iter().map(|(pt, _)| *pt).collect();
+            SegmentGrid::new(&pts)
+        })
+    }
+
+    // Returns all the (times, snapped points) when the trajectory passes within some threshold of
+    // the point. If the trajectory stays near the same point for a while, returns the earliest
+    // time. Passes closer together than `dedupe` count as one.
+    pub fn times_near_pos(
+        &self,
+        pos: Pt2D,
+        threshold: Distance,
+        dedupe: Duration,
+    ) -> Vec<(Time, Pt2D)> {
+        let mut hits = Vec::new();
+        for idx in self.index().segments_near(pos, threshold) {
+            let (pt1, t1) = self.inner[idx];
+            let (pt2, t2) = self.inner[idx + 1];
+            // Skip dwells and other zero-length segments. The neighboring segments will find the
+            // position.
+            if let Some((pct, pt_on_segment)) = project_onto_segment(pos, pt1, pt2) {
+                if pos.dist_to(pt_on_segment) < threshold {
+                    hits.push((t1 + pct * (t2 - t1), pt_on_segment));
+                }
+            }
+        }
+
+        // Dedupe by time if the trajectory stays near the same point for a while
+        let mut results = Vec::new();
+        for (t, pt) in hits {
+            if results
+                .last()
+                .map(|(last_t, _)| t - *last_t > dedupe)
+                .unwrap_or(true)
+            {
+                results.push((t, pt));
+            }
+        }
+        results
+    }
+}
+
+// Comparing trajectories. Lower results are more similar.
+impl Trajectory {
+    /// Sum distance from points at these times
+    pub fn score_at_points(&self, expected: Vec<(Time, Pt2D)>) -> Option<Distance> {
+        let mut sum = Distance::ZERO;
+        for (t, pt1) in expected {
+            if let Some((pt2, _)) = self.interpolate(t) {
+                sum += pt1.dist_to(pt2);
+            } else {
+                // If the vehicle wasn't even around at this time, probably not a match
+                return None;
+            }
+        }
+        Some(sum)
+    }
+
+    /// Ignore time. Take the shorter polyline, and walk along it every few meters. Compare to the
+    /// equivalent position along the other.
+    pub fn score_by_position(&self, other: &Trajectory) -> Distance {
+        let step_size = Distance::meters(100.0);
+        let buffer_ends = Distance::ZERO;
+
+        let mut sum = Distance::ZERO;
+        for ((pt1, _), (pt2, _)) in self
+            .as_polyline()
+            .step_along(step_size, buffer_ends)
+            .into_iter()
+            .zip(other.as_polyline().step_along(step_size, buffer_ends))
+        {
+            sum += pt1.dist_to(pt2);
+        }
+        sum
+    }
+
+    pub fn write_to_csv(&self, path: String, gps_bounds: &GPSBounds) -> Result<()> {
+        let mut f = File::create(path)?;
+        writeln!(f, "time,longitude,latitude")?;
+        for (pt, time) in &self.inner {
+            let gps = pt.to_gps(gps_bounds);
+            writeln!(f, "{},{},{}", time.inner_seconds(), gps.x(), gps.y())?;
+        }
+        Ok(())
+    }
+}
+
+// The part of the segment within the radius of the point, as percents along it. None if it never
+// gets that close.
+fn segment_within_radius(pos: Pt2D, radius: Distance, pt1: Pt2D, pt2: Pt2D) -> Option<(f64, f64)> {
+    let (dx, dy) = (pt2.x() - pt1.x(), pt2.y() - pt1.y());
+    let (fx, fy) = (pt1.x() - pos.x(), pt1.y() - pos.y());
+    let r = radius.inner_meters();
+    let a = dx * dx + dy * dy;
+    let c = fx * fx + fy * fy - r * r;
+    if a < 1e-6 {
+        // Not moving, so the whole segment is inside or outside
+        return if c <= 0.0 { Some((0.0, 1.0)) } else { None };
+    }
+    let b = 2.0 * (fx * dx + fy * dy);
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (enter, leave) = ((-b - root) / (2.0 * a), (-b + root) / (2.0 * a));
+    if leave < 0.0 || enter > 1.0 {
+        return None;
+    }
+    Some((enter.max(0.0), leave.min(1.0)))
+}
+
+// Returns the percent along the segment and the closest point on it, or None if the segment has
+// no length
+fn project_onto_segment(pos: Pt2D, pt1: Pt2D, pt2: Pt2D) -> Option<(f64, Pt2D)> {
+    let (dx, dy) = (pt2.x() - pt1.x(), pt2.y() - pt1.y());
+    let len_squared = dx * dx + dy * dy;
+    if len_squared < 1e-6 {
+        return None;
+    }
+    let pct = (((pos.x() - pt1.x()) * dx + (pos.y() - pt1.y()) * dy) / len_squared).clamp(0.0, 1.0);
+    Some((pct, Pt2D::new(pt1.x() + pct * dx, pt1.y() + pct * dy)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trajectory(pts: Vec<(f64, f64)>) -> Trajectory {
+        Trajectory::new(
+            pts.into_iter()
+                .map(|(x, secs)| {
+                    (
+                        Pt2D::new(x, 0.0),
+                        Time::START_OF_DAY + Duration::seconds(secs),
+                    )
+                })
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn stop_visit() {
+        let radius = Distance::meters(25.0);
+        let time = Time::START_OF_DAY + Duration::seconds(50.0);
+
+        // Driving straight past, between two sparse points
+        let (arrival, departure) = trajectory(vec![(0.0, 0.0), (1000.0, 100.0)]).stop_visit(
+            Pt2D::new(500.0, 10.0),
+            radius,
+            time,
+        );
+        assert!(arrival < time && time < departure);
+        assert!(departure - arrival < Duration::seconds(5.0));
+
+        // Stopping inside the radius
+        let (arrival, departure) = trajectory(vec![
+            (0.0, 0.0),
+            (480.0, 48.0),
+            (490.0, 78.0),
+            (900.0, 119.0),
+        ])
+        .stop_visit(Pt2D::new(500.0, 0.0), radius, time);
+        assert_eq!(arrival, Time::START_OF_DAY + Duration::seconds(48.0));
+        assert_eq!(departure, Time::START_OF_DAY + Duration::seconds(78.0));
+    }
+}
@@ -30,4 +30,38 @@ impl FileLoader {
             on_load,
         )
     }
+
+    /// Like `new_state`, but the user can pick several files at once
+    pub fn new_state_multiple<A: 'static>(
+        ctx: &mut EventCtx,
+        on_load: Box<
+            dyn FnOnce(&mut EventCtx, &mut A, Result<Option<Vec<Vec<u8>>>>) -> Transition<A>,
+        >,
+    ) -> Box<dyn State<A>> {
+        let (_, outer_progress_rx) = futures_channel::mpsc::channel(1);
+        let (_, inner_progress_rx) = futures_channel::mpsc::channel(1);
+        FutureLoader::<A, Option<Vec<Vec<u8>>>>::new_state(
+            ctx,
+            Box::pin(async move {
+                let builder = rfd::AsyncFileDialog::new();
+                let result = match builder.pick_files().await {
+                    Some(files) => {
+                        let mut contents = Vec::new();
+                        for file in files {
+                            contents.push(file.read().await);
+                        }
+                        Some(contents)
+                    }
+                    None => None,
+                };
+                let wrap: Box<dyn Send + FnOnce(&A) -> Option<Vec<Vec<u8>>>> =
+                    Box::new(move |_: &A| result);
+                Ok(wrap)
+            }),
+            outer_progress_rx,
+            inner_progress_rx,
+            "Waiting for files to be chosen",
+            on_load,
+        )
+    }
 }
@@ -1,9 +1,8 @@
 use anyhow::Result;
-use serde::de::DeserializeOwned;
 use widgetry::tools::PopupMsg;
 use widgetry::{EventCtx, HorizontalAlignment, Line, Panel, Transition, VerticalAlignment, Widget};
 
-use model::{DailyModel, MultidayModel};
+use model::{DailyModel, ImportConfig, MultidayModel, SharedGtfs};
 
 use crate::components::FileLoader;
 
@@ -31,6 +30,7 @@ impl MainMenu {
             "Load model" => {
                 return Some(load_model::<crate::multiday::App, MultidayModel>(
                     ctx,
+                    MultidayModel::from_file_bytes,
                     Box::new(|ctx, app, model| {
                         *app = crate::multiday::App::new(ctx, model);
                     }),
@@ -53,6 +53,7 @@ impl MainMenu {
             "Load model" => {
                 return Some(load_model::<crate::daily::App, DailyModel>(
                     ctx,
+                    DailyModel::from_file_bytes,
                     Box::new(|ctx, app, model| {
                         *app = crate::daily::App::new(ctx, model);
                     }),
@@ -72,31 +73,28 @@ impl MainMenu {
     }
 }
 
-fn load_model<A: 'static, M: 'static + DeserializeOwned>(
+// The model is picked along with the gtfs-*.bin files saved next to it
+fn load_model<A: 'static, M: 'static>(
     ctx: &mut EventCtx,
+    parse: fn(&[u8], &[SharedGtfs]) -> Result<M>,
     replace: Box<dyn Fn(&mut EventCtx, &mut A, M)>,
 ) -> Transition<A> {
     // TODO Restrict to .bin?
-    Transition::Push(FileLoader::new_state(
+    Transition::Push(FileLoader::new_state_multiple(
         ctx,
-        Box::new(move |ctx, app, maybe_bytes: Result<Option<Vec<u8>>>| {
-            match maybe_bytes {
-                Ok(Some(bytes)) => {
-                    match base64::decode(bytes)
-                        .map_err(|err| err.into())
-                        .and_then(|bytes| abstutil::from_binary::<M>(&bytes))
-                    {
-                        Ok(model) => {
-                            replace(ctx, app, model);
-                            Transition::Multi(vec![Transition::Pop, Transition::Recreate])
-                        }
-                        Err(err) => Transition::Replace(PopupMsg::new_state(
-                            ctx,
-                            "Error",
-                            vec![err.to_string()],
-                        )),
+        Box::new(move |ctx, app, maybe_files: Result<Option<Vec<Vec<u8>>>>| {
+            match maybe_files {
+                Ok(Some(files)) => match parse_with_gtfs(&files, parse) {
+                    Ok(model) => {
+                        replace(ctx, app, model);
+                        Transition::Multi(vec![Transition::Pop, Transition::Recreate])
                     }
-                }
+                    Err(err) => Transition::Replace(PopupMsg::new_state(
+                        ctx,
+                        "Error",
+                        vec![err.to_string()],
+                    )),
+                },
                 // User didn't pick a file
                 Ok(None) => Transition::Pop,
                 Err(err) => {
@@ -117,30 +115,28 @@ fn import_data<A: 'static>(
         Box::new(move |ctx, app, maybe_bytes: Result<Option<Vec<u8>>>| {
             match maybe_bytes {
                 Ok(Some(bytes)) => ctx.loading_screen("import model", |ctx, timer| {
-                    match DailyModel::import_zip_bytes(bytes, timer) {
-                        Ok(models) => {
-                            for model in &models {
-                                // TODO This silently fails in the browser unless we skip
-                                // serializing vehicles. Apparently there are file size limits.
-                                let save_model = base64::encode(abstutil::to_binary(model));
-                                if let Err(err) = abstio::write_file(
-                                    format!("data/output/{}.bin", model.date),
-                                    save_model,
-                                ) {
-                                    error!("Couldn't save imported model: {err}");
-                                }
-                            }
-
-                            let multiday = model::MultidayModel::new_from_daily_models(&models);
-                            if let Err(err) = abstio::write_file(
-                                "data/output/multiday.bin".to_string(),
-                                base64::encode(abstutil::to_binary(&multiday)),
-                            ) {
+                    match DailyModel::import_zip_bytes(bytes, &ImportConfig::default(), timer)
+                        .and_then(|(models, report)| {
+                            let multiday = MultidayModel::new_from_daily_models(&models)?;
+                            Ok((multiday, models, report))
+                        }) {
+                        Ok((multiday, models, report)) => {
+                            // TODO This silently fails in the browser unless we skip serializing
+                            // vehicles. Apparently there are file size limits.
+                            if let Err(err) = save_imported_models(&models, &multiday) {
                                 error!("Couldn't save imported model: {err}");
                             }
 
                             replace(ctx, app, multiday, models);
-                            Transition::Multi(vec![Transition::Pop, Transition::Recreate])
+                            let mut transitions = vec![Transition::Pop, Transition::Recreate];
+                            if !report.skipped.is_empty() {
+                                transitions.push(Transition::Push(PopupMsg::new_state(
+                                    ctx,
+                                    "Some days couldn't be imported",
+                                    report.describe(),
+                                )));
+                            }
+                            Transition::Multi(transitions)
                         }
                         Err(err) => Transition::Replace(PopupMsg::new_state(
                             ctx,
@@ -158,3 +154,60 @@ fn import_data<A: 'static>(
         }),
     ))
 }
+
+fn parse_with_gtfs<M>(
+    files: &[Vec<u8>],
+    parse: fn(&[u8], &[SharedGtfs]) -> Result<M>,
+) -> Result<M> {
+    let mut gtfs = Vec::new();
+    let mut models = Vec::new();
+    for bytes in files {
+        match SharedGtfs::from_file_bytes(bytes) {
+            Ok(feed) => gtfs.push(feed),
+            Err(_) => models.push(bytes),
+        }
+    }
+    if models.len() != 1 {
+        bail!("Pick one model file, along with the gtfs-*.bin files saved with it");
+    }
+    parse(models[0], &gtfs)
+}
+
+/// Saves imported models in `data/output`. The GTFS feeds are saved once next to them, instead
+/// of copied into every model.
+pub fn save_imported_models(models: &[DailyModel], multiday: &MultidayModel) -> Result<()> {
+    for feed in &multiday.feeds {
+        let shared = SharedGtfs {
+            gtfs: feed.gtfs.clone(),
+            hash: feed.hash,
+        };
+        save_model_file(
+            format!("data/output/{}", shared.file_name()),
+            shared.to_file_bytes(),
+        )?;
+    }
+    for model in models {
+        save_model_file(
+            format!("data/output/{}.bin", model.date),
+            model.to_file_bytes(false),
+        )?;
+    }
+    save_model_file(
+        "data/output/multiday.bin".to_string(),
+        multiday.to_file_bytes(false),
+    )
+}
+
+/// Writes the output of `to_file_bytes`. The browser can only save text, so there the bytes are
+/// base64-encoded; loading handles both.
+pub fn save_model_file(path: String, bytes: Vec<u8>) -> Result<()> {
+    if cfg!(target_arch = "wasm32") {
+        abstio::write_file(path, base64::encode(bytes))?;
+    } else {
+        if let Some(dir) = std::path::Path::new(&path).parent() {
+            fs_err::create_dir_all(dir)?;
+        }
+        fs_err::write(path, bytes)?;
+    }
+    Ok(())
+}
@@ -5,5 +5,5 @@ mod main_menu;
 mod render_table;
 
 pub use file_loader::FileLoader;
-pub use main_menu::MainMenu;
+pub use main_menu::{save_imported_models, MainMenu};
 pub use render_table::render_table;
@@ -0,0 +1,179 @@
+use abstutil::prettyprint_usize;
+use widgetry::{
+    Color, DrawBaselayer, EventCtx, GeomBatch, GfxCtx, Line, Outcome, Panel, State, Text, TextExt,
+    Widget,
+};
+
+use gtfs::RouteVariantID;
+
+use super::{App, Transition};
+use crate::components::render_table;
+
+pub struct MatchingReportViewer {
+    panel: Panel,
+}
+
+impl MatchingReportViewer {
+    pub fn new_state(ctx: &mut EventCtx, app: &App) -> Box<dyn State<App>> {
+        let mut col = vec![
+            Widget::row(vec![
+                Line(format!("Matching report for {}", app.model.date))
+                    .small_heading()
+                    .into_widget(ctx),
+                ctx.style().btn_close_widget(ctx),
+            ]),
+            ctx.style()
+                .btn_outline
+                .text("Export to JSON")
+                .build_def(ctx),
+        ];
+        for line in app.model.matching_report.describe() {
+            col.push(line.text_widget(ctx));
+        }
+        col.push(Line("Variants").small_heading().into_widget(ctx));
+        col.push(variant_table(ctx, app));
+        col.push(Line("Vehicles").small_heading().into_widget(ctx));
+        col.push(vehicle_table(ctx, app));
+
+        Box::new(Self {
+            panel: Panel::new_builder(Widget::col(col)).build(ctx),
+        })
+    }
+}
+
+impl State<App> for MatchingReportViewer {
+    fn event(&mut self, ctx: &mut EventCtx, app: &mut App) -> Transition {
+        if let Outcome::Clicked(x) = self.panel.event(ctx) {
+            if x == "close" {
+                return Transition::Pop;
+            }
+            if x == "Export to JSON" {
+                abstio::write_file(
+                    format!("matching_report_{}.json", app.model.date),
+                    app.model.export_matching_report().unwrap(),
+                )
+                .unwrap();
+                return Transition::Keep;
+            }
+            if let Some(x) = x.strip_prefix("variant ") {
+                let variant = RouteVariantID(x.parse::<usize>().unwrap());
+                return Transition::Push(super::variant::VariantInfo::new_state(
+                    ctx,
+                    app,
+                    app.model.gtfs.variant(variant),
+                ));
+            }
+            // Can't click vehicles yet
+        }
+
+        Transition::Keep
+    }
+
+    fn draw(&self, g: &mut GfxCtx, _: &App) {
+        self.panel.draw(g);
+    }
+
+    fn draw_baselayer(&self) -> DrawBaselayer {
+        DrawBaselayer::PreviousState
+    }
+}
+
+// Worst matches first
+fn variant_table(ctx: &mut EventCtx, app: &App) -> Widget {
+    let headers = vec![
+        "Variant".text_widget(ctx),
+        "Trips matched".text_widget(ctx),
+        "Trips expected".text_widget(ctx),
+        "Vehicles".text_widget(ctx),
+    ];
+
+    let mut variants = app.model.matching_report.per_variant.clone();
+    variants.sort_by_key(|v| v.trips_matched as isize - v.trips_expected as isize);
+    let mut rows = Vec::new();
+    for v in variants {
+        rows.push((
+            format!("variant {}", v.variant.0),
+            vec![
+                cell(
+                    ctx,
+                    app.model.gtfs.variant(v.variant).describe(&app.model.gtfs),
+                ),
+                cell(ctx, prettyprint_usize(v.trips_matched)),
+                cell(ctx, prettyprint_usize(v.trips_expected)),
+                cell(ctx, prettyprint_usize(v.vehicles)),
+            ],
+        ));
+    }
+
+    let min_extra_margin = 10.0;
+    render_table(
+        ctx,
+        headers,
+        rows,
+        0.6 * ctx.canvas.window_width,
+        min_extra_margin,
+    )
+}
+
+// Most unmatched ticketing first
+fn vehicle_table(ctx: &mut EventCtx, app: &App) -> Widget {
+    let headers = vec![
+        "Vehicle".text_widget(ctx),
+        "Possible variants".text_widget(ctx),
+        "Trips".text_widget(ctx),
+        "Ticketing matched".text_widget(ctx),
+        "Ticketing unmatched".text_widget(ctx),
+        "Route name mismatches".text_widget(ctx),
+        "Confidence".text_widget(ctx),
+    ];
+
+    let mut vehicles = app.model.matching_report.per_vehicle.clone();
+    vehicles.sort_by_key(|v| std::cmp::Reverse(v.ticketing_unmatched));
+    let mut rows = Vec::new();
+    for v in vehicles {
+        rows.push((
+            format!("vehicle {}", v.vehicle.0),
+            vec![
+                cell(
+                    ctx,
+                    app.model.vehicles[v.vehicle.0]
+                        .original_id
+                        .as_str()
+                        .to_string(),
+                ),
+                cell(ctx, prettyprint_usize(v.possible_variants)),
+                cell(ctx, prettyprint_usize(v.trips)),
+                cell(ctx, prettyprint_usize(v.ticketing_matched)),
+                cell(ctx, prettyprint_usize(v.ticketing_unmatched)),
+                cell(ctx, prettyprint_usize(v.route_name_mismatches)),
+                cell(
+                    ctx,
+                    match app.model.vehicle_confidence(v.vehicle) {
+                        Some(x) => format!("{:.0}%", 100.0 * x),
+                        None => "unknown".to_string(),
+                    },
+                ),
+            ],
+        ));
+    }
+
+    let min_extra_margin = 10.0;
+    render_table(
+        ctx,
+        headers,
+        rows,
+        0.6 * ctx.canvas.window_width,
+        min_extra_margin,
+    )
+}
+
+fn cell(ctx: &mut EventCtx, txt: String) -> GeomBatch {
+    let (mut entry, hitbox) = Text::from(txt)
+        .render_autocropped(ctx)
+        .batch()
+        .container()
+        .padding(10.0)
+        .into_geom(ctx, None);
+    entry.push(Color::RED.alpha(0.2), hitbox);
+    entry
+}
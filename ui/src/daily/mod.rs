@@ -1,4 +1,5 @@
 mod events;
+mod matching_report;
 mod page;
 mod replay;
 mod speed;
@@ -11,7 +12,7 @@ use geom::{Duration, Time};
 use serde::{Deserialize, Serialize};
 use widgetry::{Canvas, Color, EventCtx, GfxCtx, SharedAppState};
 
-use model::DailyModel;
+use model::{DailyModel, TripPaths};
 
 use crate::MaplibreSync;
 pub use replay::Replay;
@@ -19,6 +20,8 @@ use speed::TimeControls;
 
 pub struct App {
     model: DailyModel,
+    // Buses follow the shape of the trip they're serving
+    trip_paths: TripPaths,
 
     time: Time,
     time_increment: Duration,
@@ -54,8 +57,14 @@ impl App {
         ctx.canvas.cam_zoom = ctx.canvas.min_zoom();
         ctx.canvas.center_on_map_pt(bounds.center());
 
+        let trip_paths = TripPaths::new(
+            &model,
+            &mut abstutil::Timer::new("project trips onto shapes"),
+        );
+
         Self {
             model,
+            trip_paths,
 
             time: Time::START_OF_DAY,
             time_increment: Duration::minutes(10),
@@ -9,7 +9,7 @@ use widgetry::{
 };
 
 use gtfs::{DateFilter, RouteVariantID, StopID};
-use model::VehicleID;
+use model::{TripPaths, VehicleID};
 
 use super::events::Events;
 use super::{App, TimeControls, Transition};
@@ -53,6 +53,10 @@ impl Replay {
                 .text("Warp to vehicle")
                 .hotkey(lctrl(Key::J))
                 .build_def(ctx),
+            ctx.style()
+                .btn_outline
+                .text("Matching report")
+                .build_def(ctx),
             format!("No vehicle selected")
                 .text_widget(ctx)
                 .named("vehicle controls"),
@@ -249,8 +253,17 @@ impl State<App> for Replay {
                 match x.as_ref() {
                     "Replace vehicles with GTFS" => {
                         app.model.replace_vehicles_with_gtfs();
+                        app.trip_paths = TripPaths::new(
+                            &app.model,
+                            &mut abstutil::Timer::new("project trips onto shapes"),
+                        );
                         return Transition::Replace(Self::new_state(ctx, app));
                     }
+                    "Matching report" => {
+                        return Transition::Push(
+                            super::matching_report::MatchingReportViewer::new_state(ctx, app),
+                        );
+                    }
                     "Warp to vehicle" => {
                         return warp_to_vehicle(ctx);
                     }
@@ -369,9 +382,11 @@ impl State<App> for Replay {
                 if let Some(id) = self.selected_vehicle {
                     if self.world.get_hovering().is_none() {
                         let vehicle = &app.model.vehicles[id.0];
-                        let hits = vehicle
-                            .trajectory
-                            .times_near_pos(pt, Distance::meters(30.0));
+                        let hits = vehicle.trajectory.times_near_pos(
+                            pt,
+                            Distance::meters(30.0),
+                            app.model.matching.dedupe_passes,
+                        );
                         if !hits.is_empty() {
                             batch.push(
                                 Color::CYAN,
@@ -487,7 +502,7 @@ fn update_world(
     let mut not_serving_route = 0;
 
     for vehicle in &app.model.vehicles {
-        if let Some((pos, speed)) = vehicle.trajectory.interpolate(app.time) {
+        if let Some((pos, speed)) = app.trip_paths.interpolate(&app.model, vehicle.id, app.time) {
             let current_trip = vehicle.timetable.get_at_time(app.time);
             if current_trip.is_some() {
                 serving_route += 1;
@@ -507,14 +522,15 @@ fn update_world(
                 .add(Obj::Bus(vehicle.id))
                 // Use this for the vehicle radius, so it's visually clear if we're close enough to
                 // a stop for it to count
-                .hitbox(Circle::new(pos, model::BUS_TO_STOP_THRESHOLD).to_polygon())
+                .hitbox(Circle::new(pos, app.model.matching.stop_threshold).to_polygon())
                 .draw_color(color)
                 .hover_alpha(0.5)
                 .tooltip(Text::from(format!(
-                    "{:?} currently has speed {}, doing {:?}",
+                    "{:?} currently has speed {}, doing {:?} ({:?})",
                     vehicle.original_id,
                     speed.to_string(&UnitFmt::metric()),
-                    current_trip
+                    current_trip,
+                    vehicle.stages.get_at_time(app.time)
                 )))
                 .clickable()
                 .build(ctx);
@@ -532,7 +548,7 @@ fn update_world(
         hover.push(Color::GREEN, Circle::new(ev.pos, radius).to_polygon());
         // Where's the bus at this time?
         if let Some(vehicle) = app.model.lookup_vehicle(&ev.vehicle_name) {
-            if let Some((pos, _)) = vehicle.trajectory.interpolate(app.time) {
+            if let Some((pos, _)) = app.trip_paths.interpolate(&app.model, vehicle.id, app.time) {
                 if let Ok(line) = geom::Line::new(ev.pos, pos) {
                     hover.push(Color::YELLOW, line.make_polygons(Distance::meters(15.0)));
                     txt.add_line(format!(
@@ -632,14 +648,11 @@ fn warp_to_vehicle(ctx: &mut EventCtx) -> Transition {
 
 fn view_schedule(ctx: &mut EventCtx, app: &App, vehicle: VehicleID) -> Transition {
     let mut page = super::page::PageBuilder::new();
-    let mut col = vec![
-        "See STDOUT for skipped trips".text_widget(ctx),
-        // TODO blank line
-    ];
+    let mut col = Vec::new();
 
+    let schedule = app.model.infer_vehicle_schedule(vehicle, None);
     let mut last_time = None;
-    let debug = true;
-    for trip in app.model.infer_vehicle_schedule(vehicle, debug, None) {
+    for trip in schedule.trips {
         if let Some(t) = last_time {
             col.push(format!("{} gap", trip.start_time() - t).text_widget(ctx));
         }
@@ -651,6 +664,20 @@ fn view_schedule(ctx: &mut EventCtx, app: &App, vehicle: VehicleID) -> Transitio
         ));
     }
 
+    if !schedule.rejected.is_empty() {
+        col.push(
+            Line(format!(
+                "{} candidate trips rejected",
+                schedule.rejected.len()
+            ))
+            .small_heading()
+            .into_widget(ctx),
+        );
+        for rejected in &schedule.rejected {
+            col.push(rejected.describe().text_widget(ctx));
+        }
+    }
+
     // TODO Up to this point, it's great! Next things I want:
     // - Specify routing for every "page" in the app, with some kind of enum. (It could map to a
     //   URL string if needed)
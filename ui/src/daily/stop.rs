@@ -116,10 +116,11 @@ fn schedule(ctx: &mut EventCtx, app: &App, stop: &Stop, variant: &RouteVariant)
         let scheduled = trip.arrival_at(stop.id);
         if let Some(actual) = app.model.find_boarding_event(trip.id, stop.id) {
             txt.add_line(Line(format!(
-                "{} (actually {} -- {}) -- {} new riders, {} transfers by {:?}",
+                "{} (actually {} -- {}, dwelled {}) -- {} new riders, {} transfers by {:?}",
                 scheduled,
                 actual.arrival_time,
                 super::compare_time(scheduled, actual.arrival_time),
+                actual.dwell_time(),
                 actual.new_riders.len(),
                 actual.transfers.len(),
                 actual.vehicle,
@@ -141,7 +141,7 @@ impl Compare {
 }
 
 impl State<App> for Compare {
-    fn event(&mut self, ctx: &mut EventCtx, _: &mut App) -> Transition {
+    fn event(&mut self, ctx: &mut EventCtx, app: &mut App) -> Transition {
         ctx.canvas_movement();
 
         if let Outcome::Clicked(x) = self.panel.event(ctx) {
@@ -184,9 +184,11 @@ impl State<App> for Compare {
             .update(ctx.canvas.get_cursor_in_map_space(), |pt| {
                 let mut txt = Text::new();
                 let mut batch = GeomBatch::new();
-                let hits = self.items[self.idx]
-                    .trajectory
-                    .times_near_pos(pt, Distance::meters(30.0));
+                let hits = self.items[self.idx].trajectory.times_near_pos(
+                    pt,
+                    Distance::meters(30.0),
+                    app.model.matching.dedupe_passes,
+                );
                 if !hits.is_empty() {
                     batch.push(
                         Color::YELLOW,
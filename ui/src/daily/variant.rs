@@ -69,8 +69,15 @@ fn table(ctx: &mut EventCtx, app: &App, variant: &RouteVariant) -> Widget {
             let mut txt = Text::from(format!("{}", stop_time.arrival_time));
             if let Some(event) = app.model.find_boarding_event(trip.id, stop_time.stop_id) {
                 if row.is_empty() {
-                    // Show what vehicle served this trip
-                    let (mut entry, hitbox) = Text::from(Line(format!("{:?}", event.vehicle)))
+                    // Show what vehicle served this trip, and how sure we are
+                    let mut vehicle_txt = Text::from(Line(format!("{:?}", event.vehicle)));
+                    if let Some(confidence) = event.confidence {
+                        vehicle_txt.add_line(Line(format!(
+                            "{:.0}% confident",
+                            100.0 * confidence.score()
+                        )));
+                    }
+                    let (mut entry, hitbox) = vehicle_txt
                         .render_autocropped(ctx)
                         .batch()
                         .container()
@@ -41,14 +41,14 @@ impl Viewer {
                 .make_polygons(Distance::meters(5.0)),
         );
 
-        // The route
-        let variant = app.model.gtfs.variant(variant);
-        if let Ok(pl) = variant.polyline(&app.model.gtfs) {
+        // The route, with the same shape vehicles are matched against
+        if let Ok(shape) = app.model.variant_shape(variant) {
             draw.push(
                 Color::RED.alpha(0.8),
-                pl.make_polygons(Distance::meters(3.0)),
+                shape.polyline().make_polygons(Distance::meters(3.0)),
             );
         }
+        let variant = app.model.gtfs.variant(variant);
 
         // Labeled stops
         for (idx, id) in variant.stops().into_iter().enumerate() {
@@ -56,7 +56,11 @@ impl Viewer {
 
             // If the vehicle never gets close to this stop, point that out
             let color = if trajectory
-                .times_near_pos(pt, model::BUS_TO_STOP_THRESHOLD)
+                .times_near_pos(
+                    pt,
+                    app.model.matching.stop_threshold,
+                    app.model.matching.dedupe_passes,
+                )
                 .is_empty()
             {
                 Color::RED
@@ -129,7 +133,11 @@ impl State<App> for Viewer {
             .update(ctx.canvas.get_cursor_in_map_space(), |pt| {
                 let mut txt = Text::new();
                 let mut batch = GeomBatch::new();
-                let hits = self.trajectory.times_near_pos(pt, Distance::meters(30.0));
+                let hits = self.trajectory.times_near_pos(
+                    pt,
+                    Distance::meters(30.0),
+                    app.model.matching.dedupe_passes,
+                );
                 if !hits.is_empty() {
                     batch.push(
                         Color::YELLOW,
@@ -165,13 +173,19 @@ fn trip_schedule(ctx: &mut EventCtx, app: &App, trip: &ActualTrip) -> Transition
     let mut col = Vec::new();
 
     let stops = app.model.gtfs.variant(trip.variant).stops();
-    let mut last_time = trip.stop_times[0];
-    for (idx, time) in trip.stop_times.iter().enumerate() {
-        let time = *time;
+    let mut last_time = trip.visits.iter().flatten().next().unwrap().0;
+    for (idx, visit) in trip.visits.iter().enumerate() {
+        let (arrival, departure) = match visit {
+            Some(visit) => *visit,
+            None => {
+                col.push(format!("  Stop {}: skipped", idx + 1).text_widget(ctx));
+                continue;
+            }
+        };
 
         let boardings = if let Some(ev) =
             app.model
-                .boarding_event_for_vehicle_stop_time(trip.vehicle, stops[idx], time)
+                .boarding_event_for_vehicle_stop_time(trip.vehicle, stops[idx], arrival)
         {
             format!(
                 "+{} new riders, +{} transfers",
@@ -181,19 +195,26 @@ fn trip_schedule(ctx: &mut EventCtx, app: &App, trip: &ActualTrip) -> Transition
         } else {
             "didn't match to actual trip".to_string()
         };
+        let dwell = if departure > arrival {
+            format!(", dwelled {}", departure - arrival)
+        } else {
+            String::new()
+        };
 
         col.push(page.btn_data(
             ctx,
             ctx.style().btn_plain.text(format!(
-                "  Stop {}: {} ({}) - {}",
+                "  Stop {}: {}{} ({}{}) - {}",
                 idx + 1,
-                time,
-                time - last_time,
+                arrival,
+                trip.describe_uncertainty(idx),
+                arrival - last_time,
+                dwell,
                 boardings
             )),
-            (trip.vehicle, trip.variant, time),
+            (trip.vehicle, trip.variant, arrival),
         ));
-        last_time = time;
+        last_time = departure;
     }
 
     Transition::Push(page.build(
@@ -11,7 +11,7 @@ use geom::{Bounds, GPSBounds};
 use structopt::StructOpt;
 use widgetry::{EventCtx, Settings};
 
-use model::{DailyModel, MultidayModel};
+use model::{DailyModel, ImportConfig, MultidayModel, SharedGtfs};
 
 // TODO These args only make sense on native, because they read files
 // TODO Could probably make this an optional enum now
@@ -20,7 +20,8 @@ struct Args {
     /// The path to a previously built and serialized daily model
     #[structopt(long)]
     daily: Option<String>,
-    /// The path to a previously built and serialized multiday model
+    /// The path to a previously built and serialized multiday model, or a daily model to view
+    /// alone
     #[structopt(long)]
     multiday: Option<String>,
     /// The path to a .zip file with raw data to import. This'll enter the multiday mode after
@@ -54,19 +55,13 @@ fn run(settings: Settings) {
         widgetry::run(settings, move |ctx| {
             let app = ctx.loading_screen("initialize model", |ctx, timer| {
                 let bytes = fs_err::read(path).unwrap();
-                let models = DailyModel::import_zip_bytes(bytes, timer).unwrap();
-                for model in &models {
-                    let save_model = base64::encode(abstutil::to_binary(model));
-                    abstio::write_file(format!("data/output/{}.bin", model.date), save_model)
-                        .unwrap();
+                let (models, report) =
+                    DailyModel::import_zip_bytes(bytes, &ImportConfig::default(), timer).unwrap();
+                for line in report.describe() {
+                    info!("{line}");
                 }
-
-                let multiday = MultidayModel::new_from_daily_models(&models);
-                abstio::write_file(
-                    "data/output/multiday.bin".to_string(),
-                    base64::encode(abstutil::to_binary(&multiday)),
-                )
-                .unwrap();
+                let multiday = MultidayModel::new_from_daily_models(&models).unwrap();
+                components::save_imported_models(&models, &multiday).unwrap();
 
                 multiday::App::new(ctx, multiday)
             });
@@ -76,9 +71,9 @@ fn run(settings: Settings) {
     } else if let Some(path) = args.daily {
         widgetry::run(settings, move |ctx| {
             let mut app = ctx.loading_screen("initialize model", |ctx, _timer| {
-                let bytes = fs_err::read(path).unwrap();
-                let decoded = base64::decode(bytes).unwrap();
-                let model = abstutil::from_binary::<DailyModel>(&decoded).unwrap();
+                let bytes = fs_err::read(&path).unwrap();
+                let gtfs = SharedGtfs::load_next_to(&path, &bytes).unwrap();
+                let model = DailyModel::from_file_bytes(&bytes, &gtfs).unwrap();
                 // TODO Experiments turned on
                 //model.look_for_best_matches_by_pos_and_time();
                 //model.supply_demand_matching().unwrap();
@@ -92,12 +87,9 @@ fn run(settings: Settings) {
     } else if let Some(path) = args.multiday {
         widgetry::run(settings, move |ctx| {
             let app = ctx.loading_screen("initialize model", |ctx, _timer| {
-                let bytes = fs_err::read(path).unwrap();
-                let decoded = base64::decode(bytes).unwrap();
-                multiday::App::new(
-                    ctx,
-                    abstutil::from_binary::<MultidayModel>(&decoded).unwrap(),
-                )
+                let bytes = fs_err::read(&path).unwrap();
+                let gtfs = SharedGtfs::load_next_to(&path, &bytes).unwrap();
+                multiday::App::new(ctx, MultidayModel::from_file_bytes(&bytes, &gtfs).unwrap())
             });
             let states = vec![multiday::Viewer::new_state(ctx, &app)];
             app.restore_savestate(ctx);
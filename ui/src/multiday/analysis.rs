@@ -1,7 +1,9 @@
-use abstutil::{prettyprint_usize, Counter};
+use std::collections::BTreeSet;
+
+use abstutil::prettyprint_usize;
 use widgetry::{Color, EventCtx, Line, Panel, SimpleState, State, Text, TextExt, Widget};
 
-use gtfs::RouteVariantID;
+use gtfs::Route;
 
 use super::{App, Transition};
 use crate::components::render_table;
@@ -9,46 +11,45 @@ use crate::components::render_table;
 pub struct Analysis;
 
 impl Analysis {
-    pub fn boardings_by_variant(ctx: &mut EventCtx, app: &App) -> Box<dyn State<App>> {
+    pub fn boardings_by_route(ctx: &mut EventCtx, app: &App) -> Box<dyn State<App>> {
         // SELECT SUM(new_riders + transfers) over days
-        // GROUP BY variant, round_down_hour(arrival_time)
+        // GROUP BY route short name, round_down_hour(arrival_time)
 
-        // Sum the total number of boardings over all days. Group by (variant, binned hour).
-        let mut sum = Counter::new();
-        for (_, events) in &app.model.boardings_per_day {
-            for ev in events {
-                sum.add(
-                    (ev.variant, ev.arrival_time.get_hours()),
-                    ev.new_riders.len() + ev.transfers.len(),
-                );
-            }
+        // IDs change between GTFS feed versions, but route short names usually don't, so this
+        // covers every day.
+        let sum = app
+            .model
+            .count_boardings_by_route_name_per_hour(app.filters.min_confidence);
+        let mut names: BTreeSet<String> =
+            sum.borrow().keys().map(|(name, _)| name.clone()).collect();
+        for route in app.model.gtfs.routes.values() {
+            names.insert(route_name(route).to_string());
         }
 
         let mut headers = Vec::new();
-        headers.push("Variant".to_string());
+        headers.push("Route".to_string());
         for hour in 0..24 {
             headers.push(format!("Hour {}", hour));
         }
 
         let mut rows = Vec::new();
-        for variant in app.model.gtfs.all_variants() {
-            let mut row = vec![Text::from(
-                app.model.gtfs.variant(variant).describe(&app.model.gtfs),
-            )];
+        for name in names {
+            let mut row = vec![Text::from(name.clone())];
             for hour in 0..24 {
                 row.push(Text::from(format!(
                     "{}",
-                    prettyprint_usize(sum.get((variant, hour)))
+                    prettyprint_usize(sum.get((name.clone(), hour)))
                 )));
             }
-            rows.push((variant.0.to_string(), row));
+            rows.push((format!("route {name}"), row));
         }
 
         let panel = Panel::new_builder(Widget::col(vec![
             Widget::row(vec![
                 Line(format!(
-                    "Total boardings over {} days",
-                    app.model.boardings_per_day.len()
+                    "Total boardings over {} days, with at least {:.0}% confidence",
+                    app.model.boardings_per_day.len(),
+                    100.0 * app.filters.min_confidence
                 ))
                 .small_heading()
                 .into_widget(ctx),
@@ -73,17 +74,33 @@ impl SimpleState<App> for Analysis {
         if x == "close" {
             return Transition::Pop;
         }
-        if let Ok(x) = x.parse::<usize>() {
-            return Transition::Push(super::variant::VariantInfo::new_state(
-                ctx,
-                app,
-                app.model.gtfs.variant(RouteVariantID(x)),
-            ));
+        if let Some(name) = x.strip_prefix("route ") {
+            // Routes only in older feed versions have nothing to show
+            if let Some(variant) = app
+                .model
+                .gtfs
+                .routes
+                .values()
+                .filter(|route| route_name(route) == name)
+                .flat_map(|route| route.variants.iter())
+                .next()
+            {
+                return Transition::Push(super::variant::VariantInfo::new_state(ctx, app, variant));
+            }
+            return Transition::Keep;
         }
         unreachable!()
     }
 }
 
+// Matches how the model names routes across feed versions
+fn route_name(route: &Route) -> &str {
+    route
+        .short_name
+        .as_deref()
+        .unwrap_or(route.route_id.as_str())
+}
+
 fn txt_table(ctx: &mut EventCtx, headers: Vec<String>, rows: Vec<(String, Vec<Text>)>) -> Widget {
     let mut rendered_rows = Vec::new();
     for (label, row) in rows {
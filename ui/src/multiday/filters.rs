@@ -12,6 +12,8 @@ use crate::components::{date_filter, describe};
 pub struct Filters {
     pub filter: VariantFilter,
     pub variant: Option<RouteVariantID>,
+    /// Boardings on trips with a lower confidence score are ignored
+    pub min_confidence: f64,
 }
 
 impl Filters {
@@ -24,6 +26,7 @@ impl Filters {
                 route_type: None,
             },
             variant: None,
+            min_confidence: 0.0,
         }
     }
 
@@ -119,6 +122,17 @@ impl Filters {
             );
         }
 
+        col.push(Widget::row(vec![
+            "Minimum boarding confidence (%)".text_widget(ctx),
+            Spinner::widget(
+                ctx,
+                "min_confidence",
+                (0, 100),
+                (100.0 * self.min_confidence).round() as usize,
+                5,
+            ),
+        ]));
+
         Widget::col(col).section(ctx)
     }
 
@@ -128,6 +142,7 @@ impl Filters {
         let route_type = p.dropdown_value("route_type");
         let mut variant: Option<RouteVariantID> = p.dropdown_value("variant");
         let description_substring = p.text_box("description_substring");
+        let min_confidence = p.spinner::<usize>("min_confidence") as f64 / 100.0;
 
         // If the user changed filters, it may exclude this chosen variant
         if let Some(v) = variant {
@@ -146,6 +161,7 @@ impl Filters {
                 description_substring,
             },
             variant,
+            min_confidence,
         })
     }
 
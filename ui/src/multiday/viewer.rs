@@ -81,7 +81,7 @@ impl Viewer {
                 ]),
                 ctx.style()
                     .btn_outline
-                    .text("Boardings by variant")
+                    .text("Boardings by route")
                     .build_def(ctx),
                 ctx.style().btn_outline.text("Export to CSV").build_def(ctx),
             ]);
@@ -143,9 +143,9 @@ impl State<App> for Viewer {
                             app.filters.filter.description_substring = String::new();
                             self.on_filter_change(ctx, app);
                         }
-                        "Boardings by variant" => {
+                        "Boardings by route" => {
                             return Transition::Push(
-                                super::analysis::Analysis::boardings_by_variant(ctx, app),
+                                super::analysis::Analysis::boardings_by_route(ctx, app),
                             );
                         }
                         "Export to CSV" => {
@@ -285,7 +285,9 @@ fn make_world(ctx: &mut EventCtx, app: &App, panel: &mut Panel, timer: &mut Time
             }
         }
         StopStyle::Boardings => {
-            let mut counts = app.model.count_boardings_by_stop();
+            let mut counts = app
+                .model
+                .count_boardings_by_stop(app.filters.min_confidence);
             counts.subset(&stops);
             heatmap_stops(
                 ctx,